@@ -0,0 +1,398 @@
+use crate::redis_conn::RedisDbConn;
+use crate::signing::Verifier;
+use crate::versions;
+use redis_delta::{Key, Namespace, OutboxEntry, RDelta, RField, SensorType};
+use rocket_contrib::databases::redis::{self, Commands};
+use std::collections::HashSet;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// sensor_tracker creates sensors of these types.  Any
+/// others listed in `<namespace>/sensors` are included, too.
+const SENSOR_TYPES: &[&'static str] = &["temp", "ph", "dht", "do", "ec"];
+
+const ASSIGNMENT_FIELDS: &[&'static str] = &["tank", "area", "ext_device_id", "create_time"];
+
+/// Only this many changes are kept waiting for the local site.
+const MAX_OUTBOX: isize = 1000;
+
+/// The local site picks up at most this many changes at once.
+const OUTBOX_BATCH: isize = 100;
+
+/// A sensor which has reported readings, but hasn't
+/// been linked to a tank or an area yet.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnassignedSensor {
+    pub sensor_type: String,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ext_device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub create_time: Option<u64>,
+}
+
+/// Links a sensor to a tank, or to an area, or (when
+/// both are missing) to nothing at all.
+#[derive(Debug, Deserialize)]
+pub struct Assignment {
+    pub tank: Option<u16>,
+    pub area: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TankName {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TankCount {
+    pub count: u16,
+}
+
+/// Everything the local site hasn't seen yet, oldest first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxBatch {
+    pub entries: Vec<OutboxEntry>,
+}
+
+pub fn fetch_unassigned_sensors(
+    conn: &RedisDbConn,
+    namespace: &str,
+) -> Result<Vec<UnassignedSensor>, redis::RedisError> {
+    let ns = Namespace(namespace.to_owned());
+    let mut sensor_types: Vec<String> = SENSOR_TYPES.iter().map(|s| s.to_string()).collect();
+    let known: HashSet<String> = conn
+        .0
+        .smembers(Key::AllSensorTypes { ns: ns.clone() }.to_string())?;
+    for st in known {
+        if !sensor_types.contains(&st) {
+            sensor_types.push(st)
+        }
+    }
+
+    let mut result: Vec<UnassignedSensor> = vec![];
+    for sensor_type in sensor_types {
+        let st = SensorType(sensor_type.clone());
+        let ids: Vec<String> = conn.0.smembers(
+            Key::AllSensors {
+                ns: ns.clone(),
+                st: st.clone(),
+            }
+            .to_string(),
+        )?;
+        for id in ids.iter().filter_map(|id| Uuid::parse_str(id).ok()) {
+            let data: Vec<Option<String>> = conn.0.hget(
+                Key::Sensor {
+                    ns: ns.clone(),
+                    st: st.clone(),
+                    id,
+                }
+                .to_string(),
+                ASSIGNMENT_FIELDS,
+            )?;
+            if data[0].is_none() && data[1].is_none() {
+                result.push(UnassignedSensor {
+                    sensor_type: sensor_type.clone(),
+                    id: id.to_string(),
+                    ext_device_id: data[2].clone(),
+                    create_time: data[3].as_ref().and_then(|t| t.parse::<u64>().ok()),
+                })
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns `None` if the sensor doesn't exist, or if
+/// `id` isn't a UUID, in which case it couldn't.
+pub fn assign_sensor(
+    conn: &RedisDbConn,
+    namespace: &str,
+    sensor_type: &str,
+    id: &str,
+    assignment: &Assignment,
+) -> Result<Option<()>, redis::RedisError> {
+    let id = match Uuid::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    let key = Key::Sensor {
+        ns: Namespace(namespace.to_owned()),
+        st: SensorType(sensor_type.to_owned()),
+        id,
+    }
+    .to_string();
+    let exists: bool = conn.0.exists(&key)?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let (set, remove) = match (assignment.tank, assignment.area) {
+        (Some(tank), _) => (vec![("tank", tank)], vec!["area"]),
+        (None, Some(area)) => (vec![("area", area)], vec!["tank"]),
+        (None, None) => (vec![], vec!["tank", "area"]),
+    };
+
+    let mut deltas = vec![RDelta::RemoveHashFields {
+        key: key.clone(),
+        fields: remove.iter().map(|f| f.to_string()).collect(),
+        time: epoch_secs(),
+    }];
+    if !set.is_empty() {
+        deltas.push(RDelta::UpdateHash {
+            key: key.clone(),
+            fields: set
+                .iter()
+                .map(|(name, val)| RField {
+                    name: name.to_string(),
+                    val: val.to_string(),
+                })
+                .collect(),
+            time: epoch_secs(),
+        })
+    }
+
+    for delta in deltas {
+        apply_and_send(conn, namespace, delta)?;
+    }
+
+    Ok(Some(()))
+}
+
+pub fn rename_tank(
+    conn: &RedisDbConn,
+    namespace: &str,
+    id: u16,
+    name: &str,
+) -> Result<(), redis::RedisError> {
+    apply_and_send(
+        conn,
+        namespace,
+        RDelta::UpdateHash {
+            key: Key::Tank {
+                ns: Namespace(namespace.to_owned()),
+                id,
+            }
+            .to_string(),
+            fields: vec![RField {
+                name: "name".to_string(),
+                val: name.to_string(),
+            }],
+            time: epoch_secs(),
+        },
+    )
+}
+
+/// Tanks numbered above the new count are retired,
+/// and their hashes deleted.
+pub fn set_tank_count(
+    conn: &RedisDbConn,
+    namespace: &str,
+    count: u16,
+) -> Result<(), redis::RedisError> {
+    let ns = Namespace(namespace.to_owned());
+    let all_tanks = Key::AllTanks { ns: ns.clone() }.to_string();
+    let old_count: Option<u16> = conn.0.get(&all_tanks)?;
+
+    apply_and_send(
+        conn,
+        namespace,
+        RDelta::UpdateString {
+            key: all_tanks,
+            val: count.to_string(),
+            time: epoch_secs(),
+        },
+    )?;
+
+    for id in (1..=old_count.unwrap_or(0)).filter(|id| *id > count) {
+        apply_and_send(
+            conn,
+            namespace,
+            RDelta::DeleteKey {
+                key: Key::Tank { ns: ns.clone(), id }.to_string(),
+                time: epoch_secs(),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The local site's copy of Redis is the source of truth, so
+/// every admin change is queued in an outbox, where the
+/// redis_aggregator can pick it up.  Each entry is scored by
+/// a sequence number, so the aggregator can ask for everything
+/// after the last change it saw.
+pub fn outbox_key(namespace: &str) -> String {
+    Key::Outbox {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
+}
+
+fn outbox_seq_key(namespace: &str) -> String {
+    Key::OutboxSeq {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
+}
+
+/// Applies the change to our copy of Redis right away,
+/// so that the frontend sees it, then queues it
+/// for the local site.  Hash fields are stamped with the
+/// time of the change, just as pushed deltas are.
+fn apply_and_send(
+    conn: &RedisDbConn,
+    namespace: &str,
+    delta: RDelta,
+) -> Result<(), redis::RedisError> {
+    match &delta {
+        RDelta::UpdateHash { key, fields, time } => {
+            versions::update_hash(&conn.0, key, fields, *time)?;
+        }
+        RDelta::RemoveHashFields { key, fields, time } => {
+            versions::remove_hash_fields(&conn.0, key, fields, *time)?;
+        }
+        RDelta::UpdateString { key, val, .. } => conn.0.set(key, val)?,
+        RDelta::UpdateSet { key, vals, .. } => conn.0.sadd(key, vals.clone())?,
+        RDelta::RemoveSetMembers { key, vals, .. } => conn.0.srem(key, vals.clone())?,
+        RDelta::DeleteKey { key, time } => versions::delete_key(&conn.0, key, *time)?,
+        RDelta::ExpireKey { key, ttl_secs, .. } => conn.0.expire(key, *ttl_secs as usize)?,
+        RDelta::UpdateSortedSet { key, members, .. } => {
+            for m in members {
+                let _: () = conn.0.zadd(key, &m.val, m.score)?;
+            }
+        }
+    }
+
+    let seq: u64 = conn.0.incr(outbox_seq_key(namespace), 1)?;
+    let entry = OutboxEntry::new(seq, delta);
+    let key = outbox_key(namespace);
+    redis::pipe()
+        .atomic()
+        .zadd(&key, serde_json::to_string(&entry).unwrap(), seq)
+        .ignore()
+        .zremrangebyrank(&key, 0, -(MAX_OUTBOX + 1))
+        .ignore()
+        .query(&conn.0)
+}
+
+/// Changes queued after sequence number `after`, oldest first.
+pub fn fetch_outbox(
+    conn: &RedisDbConn,
+    namespace: &str,
+    after: u64,
+) -> Result<OutboxBatch, redis::RedisError> {
+    let found: Vec<String> = conn.0.zrangebyscore_limit(
+        outbox_key(namespace),
+        format!("({}", after),
+        "+inf",
+        0,
+        OUTBOX_BATCH,
+    )?;
+
+    Ok(OutboxBatch {
+        entries: found
+            .iter()
+            .filter_map(|s| serde_json::from_str(s).ok())
+            .collect(),
+    })
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// How far the local site's clock may drift from ours
+/// before its requests for the outbox are refused.
+pub const MAX_OUTBOX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// The message signed by the redis_aggregator when it asks
+/// for the outbox.  Including the time keeps an old request
+/// from being replayed.
+pub fn outbox_message(after: u64, time: u64) -> String {
+    format!("outbox:{}:{}", after, time)
+}
+
+/// Verify that a request for the outbox was sent by our redis aggregator,
+/// using the same keys which sign its push messages.
+pub fn verify_outbox_signature(
+    after: u64,
+    time: u64,
+    signature_base64: &str,
+    key_id: Option<&str>,
+    verifier: &Verifier,
+    now: u64,
+) -> bool {
+    verifier.verify_timed(
+        &outbox_message(after, time),
+        time,
+        signature_base64,
+        key_id,
+        now,
+        MAX_OUTBOX_CLOCK_SKEW_SECS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::sign;
+    use crate::signing::VerifyingKey;
+
+    const SECRET: &[u8] = b"tank_secret";
+
+    fn verifier() -> Verifier {
+        Verifier {
+            legacy: Some(VerifyingKey::Hmac(SECRET.to_vec())),
+            ..Default::default()
+        }
+    }
+
+    fn signature(after: u64, time: u64) -> String {
+        base64::encode(sign(&outbox_message(after, time), SECRET).code())
+    }
+
+    #[test]
+    fn outbox_signature_ok() {
+        let sig = signature(10, 1_500_000_000);
+        assert!(verify_outbox_signature(
+            10,
+            1_500_000_000,
+            &sig,
+            None,
+            &verifier(),
+            1_500_000_100
+        ))
+    }
+
+    #[test]
+    fn outbox_signature_wrong_params() {
+        let sig = signature(10, 1_500_000_000);
+        assert!(!verify_outbox_signature(
+            0,
+            1_500_000_000,
+            &sig,
+            None,
+            &verifier(),
+            1_500_000_000
+        ))
+    }
+
+    #[test]
+    fn outbox_signature_stale() {
+        let sig = signature(10, 1_500_000_000);
+        assert!(!verify_outbox_signature(
+            10,
+            1_500_000_000,
+            &sig,
+            None,
+            &verifier(),
+            1_500_000_000 + MAX_OUTBOX_CLOCK_SKEW_SECS + 1
+        ))
+    }
+}
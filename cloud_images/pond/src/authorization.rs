@@ -1,6 +1,7 @@
 use crate::claims::SubjectClaim;
 use crate::redis_conn::RedisConnContext;
 use crate::rocket_contrib::databases::redis::Commands;
+use redis_delta::{Key, Namespace};
 
 /// Authorizes a user based on whether they are allowed to access
 /// the system.  We track a redis SET of firebase UIDs in order
@@ -9,7 +10,23 @@ pub fn authorize(
     firebase_uid: SubjectClaim,
     redis_context: &RedisConnContext,
 ) -> Result<bool, rocket_contrib::databases::redis::RedisError> {
-    let frag = "pond/firebase/authorized_uids";
-    let key = format!("{}/{}", redis_context.namespace, frag);
+    let key = Key::AuthorizedUids {
+        ns: Namespace(redis_context.namespace.to_owned()),
+    }
+    .to_string();
     Ok(redis_context.conn.sismember(key, firebase_uid.0)?)
 }
+
+/// Admins may link sensors to tanks, rename tanks, and so on.
+/// They're tracked in a separate redis SET of firebase UIDs,
+/// and must also be present in the set of authorized users.
+pub fn authorize_admin(
+    firebase_uid: &str,
+    redis_context: &RedisConnContext,
+) -> Result<bool, rocket_contrib::databases::redis::RedisError> {
+    let key = Key::AdminUids {
+        ns: Namespace(redis_context.namespace.to_owned()),
+    }
+    .to_string();
+    Ok(redis_context.conn.sismember(key, firebase_uid)?)
+}
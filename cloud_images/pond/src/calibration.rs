@@ -0,0 +1,52 @@
+use crate::redis_conn::RedisDbConn;
+use redis_delta::{CalibrationStatus, DueSettings, Key, Namespace, SensorType, CALIBRATION_FIELDS};
+use rocket_contrib::databases::redis::Commands;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Fetch the calibration status of every pH sensor from Redis.
+pub fn fetch_all(
+    conn: &RedisDbConn,
+    namespace: &str,
+    settings: &DueSettings,
+    now: u64,
+) -> Result<Vec<CalibrationStatus>, rocket_contrib::databases::redis::RedisError> {
+    let ns = Namespace(namespace.to_owned());
+    let st = SensorType("ph".to_string());
+    let ids: Vec<String> = conn.0.smembers(
+        Key::AllSensors {
+            ns: ns.clone(),
+            st: st.clone(),
+        }
+        .to_string(),
+    )?;
+
+    let mut result: Vec<CalibrationStatus> = vec![];
+    for id in ids.iter().filter_map(|id| Uuid::parse_str(id).ok()) {
+        let data: Vec<Option<String>> = conn.0.hget(
+            Key::Sensor {
+                ns: ns.clone(),
+                st: st.clone(),
+                id,
+            }
+            .to_string(),
+            CALIBRATION_FIELDS,
+        )?;
+
+        let mut with_field_names: HashMap<String, String> = HashMap::new();
+        for (field, maybe_val) in CALIBRATION_FIELDS.iter().zip(data) {
+            if let Some(val) = maybe_val {
+                with_field_names.insert(field.to_string(), val);
+            }
+        }
+
+        result.push(CalibrationStatus::new(
+            &id.to_string(),
+            &with_field_names,
+            settings,
+            now,
+        ));
+    }
+
+    Ok(result)
+}
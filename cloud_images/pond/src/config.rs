@@ -1,4 +1,5 @@
 use crate::redis_conn::RedisPoolContext;
+use redis_delta::DueSettings;
 use regex::Regex;
 
 /// Config settings as read from a .env file
@@ -8,7 +9,13 @@ pub struct Config {
     pub redis_namespace: String,
     rocket_databases: String,
     pub cors_allow_origin: Option<String>,
-    pub signing_secret: String,
+    pub signing_secret: Option<String>,
+    pub signing_secrets: Option<String>,
+    pub signing_public_keys: Option<String>,
+    pub history_retention_secs: Option<u64>,
+    pub calibration_max_age_days: Option<u32>,
+    pub calibration_min_slope_efficiency: Option<f32>,
+    pub push_replay_window_secs: Option<u64>,
 }
 
 impl Config {
@@ -61,6 +68,13 @@ pub struct UrlToml {
     pub url: String,
 }
 
+/// Default to one week of history, matching sensor_tracker.
+const DEFAULT_HISTORY_RETENTION_SECS: u64 = 604_800;
+
+/// Pub/Sub retries for a while, but any delta older than an hour
+/// has been superseded by the time it arrives.
+const DEFAULT_PUSH_REPLAY_WINDOW_SECS: u64 = 3600;
+
 impl Config {
     pub fn new() -> Config {
         match envy::from_env::<Config>() {
@@ -68,6 +82,28 @@ impl Config {
             Err(e) => panic!("Unable to parse config ({})", e),
         }
     }
+
+    pub fn history_retention_secs(&self) -> u64 {
+        self.history_retention_secs
+            .unwrap_or(DEFAULT_HISTORY_RETENTION_SECS)
+    }
+
+    pub fn push_replay_window_secs(&self) -> u64 {
+        self.push_replay_window_secs
+            .unwrap_or(DEFAULT_PUSH_REPLAY_WINDOW_SECS)
+    }
+
+    /// Defaults to the same settings as ph_ref_calibration.
+    pub fn due_settings(&self) -> DueSettings {
+        DueSettings {
+            max_age_days: self
+                .calibration_max_age_days
+                .unwrap_or(redis_delta::DEFAULT_MAX_AGE_DAYS),
+            min_slope_efficiency: self
+                .calibration_min_slope_efficiency
+                .unwrap_or(redis_delta::DEFAULT_MIN_SLOPE_EFFICIENCY),
+        }
+    }
 }
 
 #[cfg(test)]
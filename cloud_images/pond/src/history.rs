@@ -0,0 +1,190 @@
+use crate::redis_conn::RedisDbConn;
+use redis_delta::{Key, Namespace};
+use rocket_contrib::databases::redis::Commands;
+
+/// The tank fields for which sensor_tracker keeps a time series.
+const HISTORY_FIELDS: &[&'static str] = &["temp_f", "temp_c", "ph", "do_mg_l", "ec_us_cm"];
+
+/// Don't let a caller ask for an absurd number of buckets.
+pub const MAX_BUCKETS: u64 = 1000;
+
+/// A struct to hold data returned by the HTTP request
+/// for a single tank's temp, ph, DO & EC history.
+/// Each series is a list of buckets, in ascending time order.
+/// Buckets which don't contain any readings are omitted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TankHistory {
+    pub id: u16,
+    pub from: u64,
+    pub to: u64,
+    pub resolution: u64,
+    pub temp_f: Vec<Bucket>,
+    pub temp_c: Vec<Bucket>,
+    pub ph: Vec<Bucket>,
+    pub do_mg_l: Vec<Bucket>,
+    pub ec_us_cm: Vec<Bucket>,
+}
+
+/// Summarizes the readings which fall in the interval
+/// `[time, time + resolution)`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Bucket {
+    pub time: u64,
+    pub min: f32,
+    pub max: f32,
+    pub avg: f32,
+    pub count: u32,
+}
+
+/// The time series for an individual field of a tank,
+/// e.g. `prawnspace/tanks/1/history/temp_f`
+pub fn history_key(namespace: &str, id: u16, field: &str) -> String {
+    Key::History {
+        container: Box::new(Key::Tank {
+            ns: Namespace(namespace.to_owned()),
+            id,
+        }),
+        field: field.to_owned(),
+    }
+    .to_string()
+}
+
+/// Fetch the history of an individual tank from Redis,
+/// summarized into buckets of `resolution` seconds.
+pub fn fetch(
+    conn: &RedisDbConn,
+    namespace: &str,
+    id: u16,
+    from: u64,
+    to: u64,
+    resolution: u64,
+) -> Result<TankHistory, rocket_contrib::databases::redis::RedisError> {
+    let mut series: Vec<Vec<Bucket>> = vec![];
+    for field in HISTORY_FIELDS {
+        let found: Vec<(String, u64)> =
+            conn.0
+                .zrangebyscore_withscores(history_key(namespace, id, field), from, to)?;
+
+        let points: Vec<(u64, f32)> = found
+            .iter()
+            .filter_map(|(member, score)| parse_member(member).map(|v| (*score, v)))
+            .collect();
+
+        series.push(buckets(&points, from, resolution));
+    }
+
+    let ec_us_cm = series.pop().unwrap_or(vec![]);
+    let do_mg_l = series.pop().unwrap_or(vec![]);
+    let ph = series.pop().unwrap_or(vec![]);
+    let temp_c = series.pop().unwrap_or(vec![]);
+    let temp_f = series.pop().unwrap_or(vec![]);
+
+    Ok(TankHistory {
+        id,
+        from,
+        to,
+        resolution,
+        temp_f,
+        temp_c,
+        ph,
+        do_mg_l,
+        ec_us_cm,
+    })
+}
+
+/// Members are formatted as `<time>:<value>`
+fn parse_member(member: &str) -> Option<f32> {
+    member
+        .splitn(2, ':')
+        .nth(1)
+        .and_then(|v| v.parse::<f32>().ok())
+}
+
+/// Groups `(time, value)` points into buckets of `resolution` seconds,
+/// aligned to `from`.  Expects the points to be sorted by time.
+pub fn buckets(points: &[(u64, f32)], from: u64, resolution: u64) -> Vec<Bucket> {
+    let mut result: Vec<Bucket> = vec![];
+    let mut sum: f32 = 0.0;
+    for (time, val) in points {
+        if *time < from || resolution == 0 {
+            continue;
+        }
+        let bucket_time = from + (time - from) / resolution * resolution;
+
+        let same_bucket = result
+            .last()
+            .map(|b| b.time == bucket_time)
+            .unwrap_or(false);
+        if same_bucket {
+            let b = result.last_mut().unwrap();
+            sum += val;
+            b.count += 1;
+            b.min = b.min.min(*val);
+            b.max = b.max.max(*val);
+            b.avg = sum / b.count as f32;
+        } else {
+            sum = *val;
+            result.push(Bucket {
+                time: bucket_time,
+                min: *val,
+                max: *val,
+                avg: *val,
+                count: 1,
+            });
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_history_member() {
+        assert_eq!(parse_member("1541082840:81.16"), Some(81.16));
+        assert_eq!(parse_member("garbage"), None);
+    }
+
+    #[test]
+    fn summarize_buckets() {
+        let points = vec![(100, 1.0), (110, 3.0), (160, 5.0), (290, 7.0)];
+        let actual = buckets(&points, 100, 60);
+        assert_eq!(
+            actual,
+            vec![
+                Bucket {
+                    time: 100,
+                    min: 1.0,
+                    max: 3.0,
+                    avg: 2.0,
+                    count: 2,
+                },
+                Bucket {
+                    time: 160,
+                    min: 5.0,
+                    max: 5.0,
+                    avg: 5.0,
+                    count: 1,
+                },
+                Bucket {
+                    time: 280,
+                    min: 7.0,
+                    max: 7.0,
+                    avg: 7.0,
+                    count: 1,
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn ignore_points_before_from() {
+        let points = vec![(10, 1.0), (100, 2.0)];
+        let actual = buckets(&points, 50, 100);
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual[0].time, 50);
+        assert_eq!(actual[0].count, 1);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::redis_conn::{RedisDbConn, RedisPoolContext};
 use openssl::x509::X509;
+use redis_delta::{Key, Namespace};
 use reqwest;
 use rocket_contrib::databases::redis::Commands;
 use std::collections::HashMap;
@@ -72,8 +73,10 @@ impl From<std::str::Utf8Error> for PubCertErr {
 pub struct PubKey(pub String);
 
 fn redis_key_for_rsa_pub_key(namespace: &str) -> String {
-    let fragment: &str = "pond/firebase/public_signing_keys";
-    format!("{}/{}", namespace, fragment)
+    Key::SigningKeys {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
 }
 
 pub fn fetch_from_redis(
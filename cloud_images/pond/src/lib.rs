@@ -14,13 +14,20 @@ extern crate serde_derive;
 extern crate crypto;
 extern crate regex;
 extern crate serde_json;
+extern crate uuid;
 
+pub mod admin;
 pub mod authentication;
 mod authorization;
+pub mod calibration;
 pub mod claims;
 pub mod config;
+pub mod history;
 pub mod key_pairs;
 pub mod push;
 mod redis_conn;
+pub mod signing;
+pub mod sync;
 mod tanks;
+mod versions;
 pub mod web;
@@ -1,10 +1,13 @@
 use base64;
+use crate::config::Config;
 use crate::redis_conn::RedisDbConn;
+use crate::signing::Verifier;
+use crate::versions;
 use crypto::hmac::Hmac;
 use crypto::mac::{Mac, MacResult};
 use crypto::sha3::Sha3;
-use redis_delta::RDelta;
-use rocket_contrib::databases::redis::Commands;
+use redis_delta::{Decoded, Encoding, EncodingErr, Key, Namespace, RDelta, ENCODING_ATTRIBUTE};
+use rocket_contrib::databases::redis::{self, Commands, PipelineCommands};
 use std::collections::HashMap;
 
 /// Push data structure which adheres to Google Cloud Pub/Sub
@@ -19,53 +22,294 @@ pub struct PushData {
 }
 
 impl PushData {
-    // Note that we aren't checking the order of messages.
-    pub fn ingest(&self, conn: RedisDbConn) -> Result<(), PushDataError> {
-        let rdelta = self.message.deserialize()?;
-        let result = match rdelta {
-            RDelta::UpdateHash {
-                key,
-                mut fields,
-                time: _,
-            } => {
-                let mut name_vals: Vec<(String, String)> = vec![];
-                for rf in fields.drain(..) {
-                    name_vals.push((rf.name, rf.val));
+    // Sorted sets are time series, so members which fall outside
+    // of the retention period are trimmed as new members arrive.
+    //
+    // Deltas older than the replay window are refused, as are
+    // messages which we've already ingested.  Hash fields are
+    // never overwritten by an older delta than the one which
+    // last wrote them.
+    pub fn ingest(
+        &self,
+        conn: RedisDbConn,
+        config: &Config,
+        now: u64,
+    ) -> Result<(), PushDataError> {
+        let accepted = accept(&self.message, &conn, config, now)?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        pipe_delta(&mut pipe, &accepted.rdelta, config.history_retention_secs());
+        let result: Result<(), _> = pipe.query(&conn.0);
+        if let Err(e) = result {
+            eprintln!("Error on ingest! {:?}", e);
+            unmark(&conn, &[accepted]);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Many messages, each signed just as they would be when
+/// sent individually via Pub/Sub.  Useful for bulk syncs.
+#[derive(Debug, Deserialize)]
+pub struct PushBatch {
+    pub messages: Vec<Message>,
+}
+
+/// What became of each message in a batch, in the same order.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct BatchResults {
+    pub results: Vec<BatchItemStatus>,
+}
+
+impl BatchResults {
+    /// True if there was at least one message, and every
+    /// one of them ended up with this status.
+    pub fn all(&self, status: &BatchItemStatus) -> bool {
+        !self.results.is_empty() && self.results.iter().all(|r| r == status)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchItemStatus {
+    Applied,
+    Stale,
+    Duplicate,
+    Unauthorized,
+    Invalid,
+    /// A kind of delta which we don't know.
+    Skipped,
+}
+
+impl PushBatch {
+    /// Every accepted delta is applied in a single transaction.
+    /// If Redis fails, none of them are applied, and the
+    /// whole batch may be retried.
+    pub fn ingest(
+        &self,
+        conn: RedisDbConn,
+        config: &Config,
+        verifier: &Verifier,
+        now: u64,
+    ) -> Result<BatchResults, PushDataError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        let mut accepted: Vec<Accepted> = vec![];
+        let mut results: Vec<BatchItemStatus> = vec![];
+        for message in &self.messages {
+            if !message.verify_signature(verifier) {
+                results.push(BatchItemStatus::Unauthorized);
+                continue;
+            }
+
+            let status = match accept(message, &conn, config, now) {
+                Ok(a) => {
+                    pipe_delta(&mut pipe, &a.rdelta, config.history_retention_secs());
+                    accepted.push(a);
+                    BatchItemStatus::Applied
                 }
-                Ok(conn.0.hset_multiple(key, &name_vals)?)
+                Err(PushDataError::Stale) => BatchItemStatus::Stale,
+                Err(PushDataError::Duplicate) => BatchItemStatus::Duplicate,
+                Err(PushDataError::Unknown) => BatchItemStatus::Skipped,
+                Err(PushDataError::Redis) => {
+                    unmark(&conn, &accepted);
+                    return Err(PushDataError::Redis);
+                }
+                Err(_) => BatchItemStatus::Invalid,
+            };
+            results.push(status);
+        }
+
+        if !accepted.is_empty() {
+            let applied: Result<(), _> = pipe.query(&conn.0);
+            if let Err(e) = applied {
+                eprintln!("Error on batch ingest! {:?}", e);
+                unmark(&conn, &accepted);
+                return Err(e.into());
             }
-            RDelta::UpdateSet { key, vals, time: _ } => Ok(conn.0.sadd(key, vals)?),
-            RDelta::UpdateString { key, val, time: _ } => Ok(conn.0.set(key, val)?),
-        };
+        }
+
+        Ok(BatchResults { results })
+    }
+}
+
+/// A delta which passed the replay checks, along with the key
+/// which marks its message as ingested.
+struct Accepted {
+    rdelta: RDelta,
+    message_key: Option<String>,
+}
 
-        if let Err(e) = &result {
-            eprintln!("Error on ingest! {:?}", e)
+fn accept(
+    message: &Message,
+    conn: &RedisDbConn,
+    config: &Config,
+    now: u64,
+) -> Result<Accepted, PushDataError> {
+    let rdelta = message.deserialize()?;
+    if !within_window(rdelta.time(), now, config.push_replay_window_secs()) {
+        return Err(PushDataError::Stale);
+    }
+
+    // Without an ID, only the replay window and the
+    // field times (see `versions`) protect against replays.
+    let id = match message.id() {
+        Some(id) => id,
+        None => {
+            return Ok(Accepted {
+                rdelta,
+                message_key: None,
+            })
         }
+    };
 
-        result
+    let message_key = Key::PushMessage {
+        ns: Namespace(config.redis_namespace.to_owned()),
+        id,
+    }
+    .to_string();
+    if !mark_ingested(conn, &message_key, config.push_replay_window_secs())? {
+        return Err(PushDataError::Duplicate);
     }
+
+    Ok(Accepted {
+        rdelta,
+        message_key: Some(message_key),
+    })
+}
+
+/// Lets the messages be redelivered after a failure.
+fn unmark(conn: &RedisDbConn, accepted: &[Accepted]) {
+    for a in accepted {
+        if let Some(key) = &a.message_key {
+            let _: Result<(), _> = conn.0.del(key);
+        }
+    }
+}
+
+fn pipe_delta(pipe: &mut redis::Pipeline, rdelta: &RDelta, history_retention_secs: u64) {
+    match rdelta {
+        RDelta::UpdateHash { key, fields, time } => {
+            versions::pipe_update_hash(pipe, key, fields, *time)
+        }
+        RDelta::RemoveHashFields { key, fields, time } => {
+            versions::pipe_remove_hash_fields(pipe, key, fields, *time)
+        }
+        RDelta::UpdateSet { key, vals, time: _ } => {
+            pipe.sadd(key, vals.clone()).ignore();
+        }
+        RDelta::UpdateString { key, val, time: _ } => {
+            pipe.set(key, val).ignore();
+        }
+        RDelta::UpdateSortedSet { key, members, time } => {
+            for m in members {
+                pipe.zrembyscore(key, m.score, m.score)
+                    .ignore()
+                    .zadd(key, &m.val, m.score)
+                    .ignore();
+            }
+            let expired = time.saturating_sub(history_retention_secs);
+            pipe.zrembyscore(key, "-inf", format!("({}", expired))
+                .ignore();
+        }
+        RDelta::RemoveSetMembers { key, vals, time: _ } => {
+            pipe.srem(key, vals.clone()).ignore();
+        }
+        RDelta::DeleteKey { key, time } => versions::pipe_delete_key(pipe, key, *time),
+        RDelta::ExpireKey { key, ttl_secs, .. } => {
+            pipe.expire(key, *ttl_secs as usize).ignore();
+        }
+    }
+}
+
+/// Deltas are stamped with the time they were read from the
+/// local Redis.  Anything too far from our own clock, in
+/// either direction, is refused.
+fn within_window(time: u64, now: u64, window_secs: u64) -> bool {
+    let skew = if now > time { now - time } else { time - now };
+    skew <= window_secs
+}
+
+/// Remembers the message ID for as long as the replay window lasts.
+/// After that, the message would be refused as stale anyway.
+/// Returns false if the message was already ingested.
+fn mark_ingested(
+    conn: &RedisDbConn,
+    message_key: &str,
+    window_secs: u64,
+) -> Result<bool, PushDataError> {
+    let set: Option<String> = redis::cmd("SET")
+        .arg(message_key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(window_secs)
+        .query(&conn.0)?;
+    Ok(set.is_some())
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Message {
     pub attributes: Option<HashMap<String, String>>,
     pub data: Base64,
+    #[serde(default)]
     pub message_id: String,
 }
 impl Message {
+    /// The `encoding` attribute says how the delta was encoded.
+    /// Without it, the delta is JSON.  Deltas may be wrapped in
+    /// an envelope (see `redis_delta::Envelope`), or sent bare,
+    /// as they were before envelopes existed.
     pub fn deserialize(&self) -> Result<RDelta, PushDataError> {
-        let json_r: Result<RDelta, _> = serde_json::from_slice(&self.data.decode()?[..]);
-        Ok(json_r?)
+        let attribute = self
+            .attributes
+            .as_ref()
+            .and_then(|attrs| attrs.get(ENCODING_ATTRIBUTE))
+            .map(|a| &a[..]);
+        let encoding = Encoding::from_attribute(attribute).ok_or(PushDataError::Encoding)?;
+        match encoding.decode_envelope(&self.data.decode()?[..])? {
+            Decoded::Known(rdelta) => Ok(rdelta),
+            Decoded::Unknown { schema, kind } => {
+                eprintln!("Skipping {} delta with schema {}", kind, schema);
+                Err(PushDataError::Unknown)
+            }
+        }
+    }
+
+    /// Names the message, so that it's only ingested once.
+    /// redis_aggregator puts an `id` inside the signed envelope,
+    /// which we prefer to Pub/Sub's `message_id`: that isn't
+    /// signed, and messages sent directly to us don't have one.
+    pub fn id(&self) -> Option<String> {
+        let attribute = self
+            .attributes
+            .as_ref()
+            .and_then(|attrs| attrs.get(ENCODING_ATTRIBUTE))
+            .map(|a| &a[..]);
+        let signed = Encoding::from_attribute(attribute).and_then(|encoding| {
+            self.data
+                .decode()
+                .ok()
+                .and_then(|bytes| encoding.envelope_id(&bytes))
+        });
+        if signed.is_some() || self.message_id.is_empty() {
+            signed
+        } else {
+            Some(self.message_id.to_owned())
+        }
     }
 
     /// Verify that this message payload is sent by our redis aggregator.
-    pub fn verify_signature(&self, secret: &[u8]) -> bool {
+    /// The `kid` attribute names the key which signed it, if any.
+    pub fn verify_signature(&self, verifier: &Verifier) -> bool {
         if let Some(attrs) = &self.attributes {
-            if let Some(mac) = attrs.get("sig") {
-                if let Ok(sig_bytes) = base64::decode(mac) {
-                    // comparison using MacResult should be quick
-                    return sign(&self.data.0, secret) == MacResult::new(&sig_bytes);
-                }
+            if let Some(sig) = attrs.get("sig") {
+                let key_id = attrs.get("kid").map(|k| &k[..]);
+                return verifier.verify(&self.data.0, sig, key_id);
             }
         }
 
@@ -75,7 +319,7 @@ impl Message {
 
 /// Provides a base64-encoded hmac signature for the base64
 /// message being sent.
-fn sign(message_base64: &str, secret: &[u8]) -> MacResult {
+pub fn sign(message_base64: &str, secret: &[u8]) -> MacResult {
     // create a SHA3-256 object
     let mut hmac = Hmac::new(Sha3::sha3_256(), secret);
 
@@ -97,8 +341,18 @@ impl Base64 {
 pub enum PushDataError {
     Base64,
     Json,
+    Cbor,
+    /// The `encoding` attribute names an encoding we can't read.
+    Encoding,
     Utf8,
     Redis,
+    /// The delta is outside of the replay window.
+    Stale,
+    /// We've already ingested this message.
+    Duplicate,
+    /// Sent by a newer redis_aggregator, as a kind
+    /// of delta which we don't know how to apply.
+    Unknown,
 }
 impl From<rocket_contrib::databases::redis::RedisError> for PushDataError {
     fn from(_e: rocket_contrib::databases::redis::RedisError) -> PushDataError {
@@ -115,9 +369,221 @@ impl From<serde_json::Error> for PushDataError {
         PushDataError::Json
     }
 }
+impl From<EncodingErr> for PushDataError {
+    fn from(e: EncodingErr) -> PushDataError {
+        match e {
+            EncodingErr::Json(_) => PushDataError::Json,
+            EncodingErr::Cbor(_) => PushDataError::Cbor,
+        }
+    }
+}
 
 impl From<base64::DecodeError> for PushDataError {
     fn from(_e: base64::DecodeError) -> PushDataError {
         PushDataError::Base64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        assert!(within_window(1000, 1000, 60));
+        assert!(within_window(940, 1000, 60));
+        assert!(!within_window(939, 1000, 60));
+        assert!(within_window(1060, 1000, 60));
+        assert!(!within_window(1061, 1000, 60));
+        assert!(!within_window(u64::max_value(), 1000, 60));
+        assert!(within_window(1000, 0, u64::max_value()));
+        assert!(within_window(u64::max_value(), u64::max_value(), 60));
+    }
+    #[test]
+    fn batch_results() {
+        let results = BatchResults {
+            results: vec![BatchItemStatus::Applied, BatchItemStatus::Duplicate],
+        };
+        assert_eq!(
+            serde_json::to_string(&results).unwrap(),
+            r#"{"results":["applied","duplicate"]}"#
+        );
+        assert!(!results.all(&BatchItemStatus::Applied));
+
+        let refused = BatchResults {
+            results: vec![BatchItemStatus::Unauthorized, BatchItemStatus::Unauthorized],
+        };
+        assert!(refused.all(&BatchItemStatus::Unauthorized));
+        assert!(!BatchResults { results: vec![] }.all(&BatchItemStatus::Unauthorized))
+    }
+
+    fn message(delta: &RDelta, encoding: Option<Encoding>) -> Message {
+        let mut attributes = HashMap::new();
+        if let Some(e) = encoding {
+            attributes.insert(ENCODING_ATTRIBUTE.to_string(), e.attribute().to_string());
+        }
+        let bytes = encoding.unwrap_or_default().encode(delta).unwrap();
+        Message {
+            attributes: Some(attributes),
+            data: Base64(base64::encode(&bytes)),
+            message_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn deserialize_encodings() {
+        let delta = RDelta::UpdateString {
+            key: "prawnspace/tanks".to_string(),
+            val: "2".to_string(),
+            time: 1_500_000_000,
+        };
+        for encoding in &[None, Some(Encoding::Json), Some(Encoding::Cbor)] {
+            assert_eq!(message(&delta, *encoding).deserialize().unwrap(), delta);
+        }
+
+        let mut unknown = message(&delta, None);
+        unknown.attributes = Some(
+            vec![(ENCODING_ATTRIBUTE.to_string(), "cbor/9".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        match unknown.deserialize() {
+            Err(PushDataError::Encoding) => (),
+            other => panic!("expected an encoding error, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize_envelopes() {
+        let delta = RDelta::UpdateString {
+            key: "prawnspace/tanks".to_string(),
+            val: "2".to_string(),
+            time: 1_500_000_000,
+        };
+        let mut wrapped = message(&delta, None);
+        wrapped.data = Base64(base64::encode(
+            &Encoding::Json.encode_envelope(&delta).unwrap(),
+        ));
+        assert_eq!(wrapped.deserialize().unwrap(), delta);
+
+        let newer = r#"{"schema":1,"kind":"rename_key","body":{"rename_key":{}}}"#;
+        wrapped.data = Base64(base64::encode(newer));
+        match wrapped.deserialize() {
+            Err(PushDataError::Unknown) => (),
+            other => panic!("expected to skip, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn message_ids() {
+        let delta = RDelta::UpdateString {
+            key: "prawnspace/tanks".to_string(),
+            val: "2".to_string(),
+            time: 1_500_000_000,
+        };
+        let mut bare = message(&delta, None);
+        assert_eq!(bare.id(), None);
+        bare.message_id = "136969346945".to_string();
+        assert_eq!(bare.id(), Some("136969346945".to_string()));
+
+        for encoding in &[Encoding::Json, Encoding::Cbor] {
+            let mut signed = message(&delta, Some(*encoding));
+            signed.data = Base64(base64::encode(
+                &encoding.encode_envelope_with_id(&delta, "abc").unwrap(),
+            ));
+            assert_eq!(signed.id(), Some("abc".to_string()));
+            signed.message_id = "136969346945".to_string();
+            assert_eq!(signed.id(), Some("abc".to_string()));
+        }
+    }
+
+    /// Needs redis: `redis-server`
+    #[test]
+    #[ignore]
+    fn removals_applied() {
+        let conn = redis::Client::open("redis://127.0.0.1/")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let sensor = "pond_test/sensors/temp/aaaaaaaa-eeee-aaaa-aaaa-aaaaaaaaaaaa";
+        let sensors = "pond_test/sensors/temp";
+        let tank = "pond_test/tanks/3";
+        let history = "pond_test/tanks/1/history/temp_f";
+        let mut keys: Vec<String> = vec![sensor, sensors, tank, history]
+            .into_iter()
+            .map(|k| k.to_string())
+            .collect();
+        keys.extend(vec![sensor, tank].into_iter().map(|hash| {
+            Key::FieldTimes {
+                hash: hash.to_string(),
+            }
+            .to_string()
+        }));
+        let _: () = conn.del(keys.clone()).unwrap();
+        let _: () = conn
+            .hset_multiple(sensor, &[("tank", "1"), ("temp_f", "81.2")])
+            .unwrap();
+        let _: () = conn.sadd(sensors, vec!["a", "b"]).unwrap();
+        let _: () = conn.hset(tank, "name", "Retired").unwrap();
+        let _: () = conn
+            .zadd(history, "1500000000:81.2", 1_500_000_000)
+            .unwrap();
+
+        // as the redis_aggregator sends them, once the local site
+        // unlinks a sensor, retires a tank, ...
+        let deltas = vec![
+            RDelta::RemoveHashFields {
+                key: sensor.to_string(),
+                fields: vec!["tank".to_string()],
+                time: 1_500_000_000,
+            },
+            RDelta::RemoveSetMembers {
+                key: sensors.to_string(),
+                vals: vec!["b".to_string()],
+                time: 1_500_000_000,
+            },
+            RDelta::DeleteKey {
+                key: tank.to_string(),
+                time: 1_500_000_000,
+            },
+            RDelta::ExpireKey {
+                key: history.to_string(),
+                ttl_secs: 3600,
+                time: 1_500_000_000,
+            },
+        ];
+        for delta in &deltas {
+            for encoding in &[Encoding::Json, Encoding::Cbor] {
+                let mut signed = message(delta, Some(*encoding));
+                signed.data = Base64(base64::encode(
+                    &encoding.encode_envelope_with_id(delta, "abc").unwrap(),
+                ));
+                assert_eq!(&signed.deserialize().unwrap(), delta);
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            pipe_delta(&mut pipe, delta, 3600);
+            pipe.query::<()>(&conn).unwrap();
+        }
+
+        let fields: Vec<String> = conn.hkeys(sensor).unwrap();
+        assert_eq!(fields, vec!["temp_f".to_string()]);
+        let members: Vec<String> = conn.smembers(sensors).unwrap();
+        assert_eq!(members, vec!["a".to_string()]);
+        let exists: bool = conn.exists(tank).unwrap();
+        assert!(!exists);
+        let ttl: i64 = conn.ttl(history).unwrap();
+        assert!(ttl > 0 && ttl <= 3600);
+
+        let _: () = conn.del(keys).unwrap();
+    }
+
+    #[test]
+    fn batch_message_id_optional() {
+        let batch: PushBatch =
+            serde_json::from_str(r#"{"messages":[{"attributes":{"sig":"AA=="},"data":"AA=="}]}"#)
+                .unwrap();
+        assert!(batch.messages[0].message_id.is_empty())
+    }
+}
@@ -0,0 +1,184 @@
+//! Everything sent to us by the redis_aggregator is signed.  A
+//! signature may name the key which made it, so that several keys
+//! can be accepted while one is being rotated out.  Each key is
+//! either an HMAC secret shared with the local site, or the public
+//! half of an Ed25519 key pair, whose private half never leaves
+//! the local site.
+//!
+//! Signatures which don't name a key are checked against
+//! `SIGNING_SECRET`, as they were before keys had IDs.
+use crate::config::Config;
+use crate::push::sign;
+use crypto::ed25519;
+use crypto::mac::MacResult;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyingKey {
+    Hmac(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+impl VerifyingKey {
+    fn verify(&self, message: &str, signature: &[u8]) -> bool {
+        match self {
+            VerifyingKey::Hmac(secret) => sign(message, secret) == MacResult::new(signature),
+            VerifyingKey::Ed25519(public_key) => {
+                // rust-crypto panics on the wrong lengths
+                public_key.len() == 32
+                    && signature.len() == 64
+                    && ed25519::verify(message.as_bytes(), public_key, signature)
+            }
+        }
+    }
+}
+
+/// Kept in Rocket's managed state.
+#[derive(Debug, Clone, Default)]
+pub struct Verifier {
+    /// Used when a signature doesn't name its key.
+    pub legacy: Option<VerifyingKey>,
+    pub keys: HashMap<String, VerifyingKey>,
+}
+
+impl Verifier {
+    /// Panics if the keys listed in the config can't be parsed,
+    /// just as `Config::new` does.
+    pub fn new(config: &Config) -> Verifier {
+        let mut keys = HashMap::new();
+        if let Some(secrets) = &config.signing_secrets {
+            for (id, secret) in parse_key_list(secrets) {
+                keys.insert(id, VerifyingKey::Hmac(secret.into_bytes()));
+            }
+        }
+        if let Some(public_keys) = &config.signing_public_keys {
+            for (id, public_key) in parse_key_list(public_keys) {
+                match base64::decode(&public_key) {
+                    Ok(ref pk) if pk.len() == 32 => {
+                        keys.insert(id, VerifyingKey::Ed25519(pk.to_vec()));
+                    }
+                    _ => panic!("Unable to parse public key {}", id),
+                }
+            }
+        }
+
+        Verifier {
+            legacy: config
+                .signing_secret
+                .as_ref()
+                .map(|s| VerifyingKey::Hmac(s.as_bytes().to_vec())),
+            keys,
+        }
+    }
+
+    /// The algorithm is decided by the key, never by the sender.
+    pub fn verify(&self, message: &str, signature_base64: &str, key_id: Option<&str>) -> bool {
+        let key = match key_id {
+            Some(id) => self.keys.get(id),
+            None => self.legacy.as_ref(),
+        };
+
+        match (key, base64::decode(signature_base64)) {
+            (Some(key), Ok(signature)) => key.verify(message, &signature),
+            _ => false,
+        }
+    }
+
+    /// Verify a request whose signed message includes the time it was
+    /// sent.  Requests too far from our own clock are refused, so that
+    /// an old request can't be replayed.
+    pub fn verify_timed(
+        &self,
+        message: &str,
+        time: u64,
+        signature_base64: &str,
+        key_id: Option<&str>,
+        now: u64,
+        max_skew_secs: u64,
+    ) -> bool {
+        let skew = if now > time { now - time } else { time - now };
+        skew <= max_skew_secs && self.verify(message, signature_base64, key_id)
+    }
+}
+
+/// `id1:value1,id2:value2`.  Values may contain colons, but not commas.
+fn parse_key_list(list: &str) -> Vec<(String, String)> {
+    list.split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(id), Some(value)) if !id.is_empty() => (id.to_string(), value.to_string()),
+                _ => panic!("Unable to parse signing key entry, expected id:value"),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verifier() -> Verifier {
+        let mut keys = HashMap::new();
+        keys.insert(
+            "new".to_string(),
+            VerifyingKey::Hmac(b"new_secret".to_vec()),
+        );
+        keys.insert(
+            "old".to_string(),
+            VerifyingKey::Hmac(b"old_secret".to_vec()),
+        );
+        Verifier {
+            legacy: Some(VerifyingKey::Hmac(b"sekrit".to_vec())),
+            keys,
+        }
+    }
+
+    fn hmac(message: &str, secret: &[u8]) -> String {
+        base64::encode(sign(message, secret).code())
+    }
+
+    #[test]
+    fn legacy_signature() {
+        // the same signature which redis_aggregator's tests expect
+        let sig = "M7HSodfA0G0vHcvaoAsdoFCZk9hj0Dqo9JFX6C1YXjI=";
+        assert!(verifier().verify("AA==", sig, None));
+        assert!(!verifier().verify("AA==", sig, Some("new")));
+    }
+
+    #[test]
+    fn rotation() {
+        let v = verifier();
+        assert!(v.verify("AA==", &hmac("AA==", b"new_secret"), Some("new")));
+        assert!(v.verify("AA==", &hmac("AA==", b"old_secret"), Some("old")));
+        assert!(!v.verify("AA==", &hmac("AA==", b"old_secret"), Some("new")));
+        assert!(!v.verify("AA==", &hmac("AA==", b"new_secret"), Some("gone")));
+    }
+
+    #[test]
+    fn ed25519_signature() {
+        let (secret_key, public_key) = ed25519::keypair(&[7u8; 32]);
+        let mut v = verifier();
+        v.keys
+            .insert("pi".to_string(), VerifyingKey::Ed25519(public_key.to_vec()));
+
+        let sig = base64::encode(&ed25519::signature(b"AA==", &secret_key)[..]);
+        assert!(v.verify("AA==", &sig, Some("pi")));
+        assert!(!v.verify("AB==", &sig, Some("pi")));
+        // an HMAC can't stand in for the key pair
+        assert!(!v.verify("AA==", &hmac("AA==", b"sekrit"), Some("pi")));
+    }
+
+    #[test]
+    fn key_list() {
+        assert_eq!(
+            parse_key_list("2019-06:abc, 2019-01:d:ef"),
+            vec![
+                ("2019-06".to_string(), "abc".to_string()),
+                ("2019-01".to_string(), "d:ef".to_string())
+            ]
+        )
+    }
+}
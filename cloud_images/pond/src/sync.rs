@@ -0,0 +1,190 @@
+//! The redis_aggregator at the local site sends us a digest of
+//! each key which it keeps in sync with our copy.  We answer
+//! with the keys whose digests don't match ours, so that only
+//! those need to be pushed again.
+//!
+//! Pushing a hash or set only ever adds to our copy, so we also
+//! say which fields and members we have, and which keys we have
+//! that weren't sent.  The local site removes whatever it lacks.
+use crate::redis_conn::RedisDbConn;
+use crate::signing::Verifier;
+use redis_delta::{digest_hash, digest_set, digest_string, Key, KeyDigest, RType};
+use rocket_contrib::databases::redis::{self, Commands};
+use std::collections::{HashMap, HashSet};
+
+/// How far the local site's clock may drift from ours
+/// before its requests to sync are refused.
+pub const MAX_SYNC_CLOCK_SKEW_SECS: u64 = 300;
+
+#[derive(Debug, Deserialize)]
+pub struct DigestRequest {
+    pub digests: Vec<KeyDigest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DigestResponse {
+    /// Keys which should be pushed again.
+    pub differ: Vec<String>,
+    /// The field names of each hash in `differ`, or the members
+    /// of each set.
+    pub members: HashMap<String, Vec<String>>,
+    /// Replicated keys which we have, but which weren't sent.
+    pub extra: Vec<String>,
+}
+
+/// The message signed by the redis_aggregator when it asks
+/// which keys differ.  Including the time keeps an old request
+/// from being replayed.
+pub fn sync_message(time: u64) -> String {
+    format!("sync:{}", time)
+}
+
+pub fn verify_sync_signature(
+    time: u64,
+    signature_base64: &str,
+    key_id: Option<&str>,
+    verifier: &Verifier,
+    now: u64,
+) -> bool {
+    verifier.verify_timed(
+        &sync_message(time),
+        time,
+        signature_base64,
+        key_id,
+        now,
+        MAX_SYNC_CLOCK_SKEW_SECS,
+    )
+}
+
+/// Keys outside of our namespace, or which aren't replicated
+/// (authorized users, say), are ignored rather than compared.
+pub fn compare(
+    conn: &RedisDbConn,
+    namespace: &str,
+    request: &DigestRequest,
+) -> Result<DigestResponse, redis::RedisError> {
+    let prefix = format!("{}/", namespace);
+    let mut differ = vec![];
+    let mut members = HashMap::new();
+    for kd in &request.digests {
+        let rtype = match replicated_type(&kd.key, &prefix) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        if digest(conn, &kd.key, rtype)? != kd.digest {
+            differ.push(kd.key.to_string());
+            if let Some(m) = names(conn, &kd.key, rtype)? {
+                members.insert(kd.key.to_string(), m);
+            }
+        }
+    }
+
+    let sent: HashSet<&str> = request.digests.iter().map(|kd| kd.key.as_str()).collect();
+    let found: redis::Iter<String> = conn.0.scan_match(format!("{}*", prefix))?;
+    // SCAN may return a key more than once
+    let mut extra: Vec<String> = found
+        .filter(|key| !sent.contains(key.as_str()))
+        .filter(|key| replicated_type(key, &prefix).is_some())
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+    extra.sort();
+
+    Ok(DigestResponse {
+        differ,
+        members,
+        extra,
+    })
+}
+
+fn replicated_type(key: &str, prefix: &str) -> Option<RType> {
+    if !key.starts_with(prefix) {
+        return None;
+    }
+    key.parse::<Key>().ok().and_then(|k| k.replicated_type())
+}
+
+/// Strings have neither fields nor members.
+fn names(
+    conn: &RedisDbConn,
+    key: &str,
+    rtype: RType,
+) -> Result<Option<Vec<String>>, redis::RedisError> {
+    Ok(match rtype {
+        RType::Hash => Some(conn.0.hkeys(key)?),
+        RType::Set => Some(conn.0.smembers(key)?),
+        RType::String => None,
+    })
+}
+
+/// Missing keys have the same digest as empty ones.
+fn digest(conn: &RedisDbConn, key: &str, rtype: RType) -> Result<String, redis::RedisError> {
+    Ok(match rtype {
+        RType::Hash => {
+            let fields: HashMap<String, String> = conn.0.hgetall(key)?;
+            digest_hash(&fields)
+        }
+        RType::Set => {
+            let members: Vec<String> = conn.0.smembers(key)?;
+            digest_set(&members)
+        }
+        RType::String => {
+            let val: Option<String> = conn.0.get(key)?;
+            digest_string(&val.unwrap_or_default())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::sign;
+    use crate::signing::VerifyingKey;
+
+    const SECRET: &[u8] = b"tank_secret";
+
+    #[test]
+    fn sync_signature() {
+        let verifier = Verifier {
+            legacy: Some(VerifyingKey::Hmac(SECRET.to_vec())),
+            ..Default::default()
+        };
+        let sig = base64::encode(sign(&sync_message(1_500_000_000), SECRET).code());
+        assert!(verify_sync_signature(
+            1_500_000_000,
+            &sig,
+            None,
+            &verifier,
+            1_500_000_100
+        ));
+        assert!(!verify_sync_signature(
+            1_500_000_001,
+            &sig,
+            None,
+            &verifier,
+            1_500_000_100
+        ));
+        assert!(!verify_sync_signature(
+            1_500_000_000,
+            &sig,
+            None,
+            &verifier,
+            1_500_000_000 + MAX_SYNC_CLOCK_SKEW_SECS + 1
+        ))
+    }
+
+    #[test]
+    fn replicated_keys() {
+        let prefix = "prawnbauble/";
+        let t = |key| replicated_type(key, prefix);
+        assert_eq!(t("prawnbauble/tanks"), Some(RType::String));
+        assert_eq!(t("prawnbauble/tanks/1"), Some(RType::Hash));
+        assert_eq!(t("prawnbauble/sensors/temp"), Some(RType::Set));
+        // kept alongside replicated keys, but not replicated themselves
+        assert_eq!(t("prawnbauble/tanks/1/field_times"), None);
+        assert_eq!(t("prawnbauble/tanks/1/history/temp_f"), None);
+        assert_eq!(t("prawnbauble/pond/outbox"), None);
+        assert_eq!(t("otherbauble/tanks/1"), None)
+    }
+}
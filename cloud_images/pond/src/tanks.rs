@@ -1,58 +1,27 @@
 use crate::redis_conn::RedisDbConn;
-use hashbrown::HashMap;
-use redis_delta::{Key, Namespace};
+use redis_delta::{HashRecord, Key, Namespace, TankRecord};
 use rocket_contrib::databases::redis::Commands;
 
 /// A struct to hold data returned by the HTTP request
-/// for tanks' temp & ph info.
+/// for tanks' temp, pH, DO & EC info.  The fields of the tank's
+/// hash are flattened alongside its ID.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Tank {
     pub id: u16,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub temp_f: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub temp_c: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub temp_update_time: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub temp_update_count: Option<u32>,
-
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub ph: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub ph_mv: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub ph_update_time: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub ph_update_count: Option<u32>,
+    #[serde(flatten)]
+    pub record: TankRecord,
 }
 
 impl Tank {
-    pub fn from_fields(id: u16, fields: &HashMap<&str, String>) -> Tank {
-        let (temp_f, temp_c) = match (
-            parse_maybe::<f32>(fields.get("temp_f")),
-            parse_maybe::<f32>(fields.get("temp_c")),
-        ) {
-            (Some(f), Some(c)) => (Some(f), Some(c)),
-            (Some(f), _) => (Some(f), Some(f_to_c(f))),
-            (_, Some(c)) => (Some(c_to_f(c)), Some(c)),
-            (_, _) => (None, None),
-        };
-
-        Tank {
-            id,
-            name: parse_maybe::<String>(fields.get("name")),
-            temp_f,
-            temp_c,
-            temp_update_time: parse_maybe::<u64>(fields.get("temp_update_time")),
-            temp_update_count: parse_maybe::<u32>(fields.get("temp_update_count")),
-            ph: parse_maybe::<f32>(fields.get("ph")),
-            ph_mv: parse_maybe::<f32>(fields.get("ph_mv")),
-            ph_update_time: parse_maybe::<u64>(fields.get("ph_update_time")),
-            ph_update_count: parse_maybe::<u32>(fields.get("ph_update_count")),
+    /// Fills in whichever of `temp_f` and `temp_c` is missing.
+    pub fn new(id: u16, mut record: TankRecord) -> Tank {
+        match (record.temp_f, record.temp_c) {
+            (Some(f), None) => record.temp_c = Some(f_to_c(f)),
+            (None, Some(c)) => record.temp_f = Some(c_to_f(c)),
+            _ => (),
         }
+
+        Tank { id, record }
     }
 }
 
@@ -85,17 +54,6 @@ fn fetch_num_tanks(
     .to_string();
     conn.0.get(key)
 }
-const TANK_FIELDS: &[&'static str] = &[
-    "name",
-    "temp_f",
-    "temp_c",
-    "temp_update_time",
-    "temp_update_count",
-    "ph",
-    "ph_mv",
-    "ph_update_time",
-    "ph_update_count",
-];
 
 /// Fetch the status of an individual tank from Redis
 fn fetch_tank_status(
@@ -109,38 +67,20 @@ fn fetch_tank_status(
     }
     .to_string();
 
-    let data: Vec<Option<String>> = conn.0.hget(&key, TANK_FIELDS)?;
+    let data: Vec<Option<String>> = conn.0.hget(&key, TankRecord::FIELDS)?;
 
     let no_results: bool = data.iter().all(|maybe| maybe.is_none());
 
     Ok(if no_results {
         None
     } else {
-        Some({
-            let mut with_field_names: HashMap<&str, String> = hashbrown::HashMap::new();
-
-            let i: Vec<(&str, Option<String>)> = TANK_FIELDS.iter().map(|s| *s).zip(data).collect();
-            for (field, maybe_val) in i {
-                if let Some(val) = maybe_val {
-                    with_field_names.insert(field, val);
-                }
-            }
-
-            Tank::from_fields(id, &with_field_names)
-        })
+        Some(Tank::new(id, TankRecord::from_values(data)))
     })
 }
 
-fn f_to_c(f: f32) -> f32 {
-    (f - 32f32) * 5f32 / 9f32
-}
-fn c_to_f(c: f32) -> f32 {
-    (c * 9f32 / 5f32) + 32f32
+fn f_to_c(f: f64) -> f64 {
+    (f - 32f64) * 5f64 / 9f64
 }
-
-fn parse_maybe<T>(maybe: Option<&String>) -> Option<T>
-where
-    T: std::str::FromStr,
-{
-    maybe.and_then(|s| s.parse::<T>().ok())
+fn c_to_f(c: f64) -> f64 {
+    (c * 9f64 / 5f64) + 32f64
 }
@@ -0,0 +1,165 @@
+//! Replicated hashes keep the `time` of the delta which last wrote
+//! each of their fields, in a companion hash (see `Key::FieldTimes`).
+//! A field is only written when the incoming delta is at least as
+//! recent as the one which wrote it last, so that redelivered or
+//! replayed messages can't roll readings backwards.  Removing a
+//! field records its time, too, so an older update can't bring
+//! it back.  Deleting a hash removes only the fields which weren't
+//! written after the delete.
+use redis_delta::{Key, RField};
+use rocket_contrib::databases::redis::{self, PipelineCommands, Script};
+
+const UPDATE_HASH_SRC: &str = r"
+        local applied = {}
+        for i = 2, #ARGV, 2 do
+            local t = redis.call('HGET', KEYS[2], ARGV[i])
+            if not t or tonumber(t) <= tonumber(ARGV[1]) then
+                redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+                redis.call('HSET', KEYS[2], ARGV[i], ARGV[1])
+                table.insert(applied, ARGV[i])
+            end
+        end
+        return applied
+        ";
+
+const REMOVE_HASH_FIELDS_SRC: &str = r"
+        local applied = {}
+        for i = 2, #ARGV do
+            local t = redis.call('HGET', KEYS[2], ARGV[i])
+            if not t or tonumber(t) <= tonumber(ARGV[1]) then
+                redis.call('HDEL', KEYS[1], ARGV[i])
+                redis.call('HSET', KEYS[2], ARGV[i], ARGV[1])
+                table.insert(applied, ARGV[i])
+            end
+        end
+        return applied
+        ";
+
+const DELETE_KEY_SRC: &str = r"
+        if redis.call('TYPE', KEYS[1]).ok ~= 'hash' then
+            return redis.call('DEL', KEYS[1])
+        end
+        for _, f in ipairs(redis.call('HKEYS', KEYS[1])) do
+            local t = redis.call('HGET', KEYS[2], f)
+            if not t or tonumber(t) <= tonumber(ARGV[1]) then
+                redis.call('HDEL', KEYS[1], f)
+                redis.call('HSET', KEYS[2], f, ARGV[1])
+            end
+        end
+        return redis.call('EXISTS', KEYS[1])
+        ";
+
+lazy_static! {
+    static ref UPDATE_HASH: Script = Script::new(UPDATE_HASH_SRC);
+    static ref REMOVE_HASH_FIELDS: Script = Script::new(REMOVE_HASH_FIELDS_SRC);
+    static ref DELETE_KEY: Script = Script::new(DELETE_KEY_SRC);
+}
+
+fn field_times_key(key: &str) -> String {
+    Key::FieldTimes {
+        hash: key.to_string(),
+    }
+    .to_string()
+}
+
+/// Returns the names of the fields which were written.
+pub fn update_hash(
+    conn: &redis::Connection,
+    key: &str,
+    fields: &[RField],
+    time: u64,
+) -> Result<Vec<String>, redis::RedisError> {
+    if fields.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut invocation = UPDATE_HASH.key(key);
+    invocation.key(field_times_key(key)).arg(time);
+    for f in fields {
+        invocation.arg(&f.name).arg(&f.val);
+    }
+    invocation.invoke(conn)
+}
+
+/// Returns the names of the fields which were removed.
+pub fn remove_hash_fields(
+    conn: &redis::Connection,
+    key: &str,
+    fields: &[String],
+    time: u64,
+) -> Result<Vec<String>, redis::RedisError> {
+    if fields.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut invocation = REMOVE_HASH_FIELDS.key(key);
+    invocation.key(field_times_key(key)).arg(time);
+    for f in fields {
+        invocation.arg(f);
+    }
+    invocation.invoke(conn)
+}
+
+/// Deletes the key.  Hashes keep any fields written after `time`.
+pub fn delete_key(conn: &redis::Connection, key: &str, time: u64) -> Result<(), redis::RedisError> {
+    let _: u64 = DELETE_KEY
+        .key(key)
+        .key(field_times_key(key))
+        .arg(time)
+        .invoke(conn)?;
+    Ok(())
+}
+
+/// Queues the same update as `update_hash` onto a pipeline, so
+/// that it can be applied alongside other deltas.  Scripts are
+/// sent with EVAL, since a pipeline can't fall back from EVALSHA.
+pub fn pipe_update_hash(pipe: &mut redis::Pipeline, key: &str, fields: &[RField], time: u64) {
+    if fields.is_empty() {
+        return;
+    }
+
+    pipe.cmd("EVAL")
+        .arg(UPDATE_HASH_SRC)
+        .arg(2)
+        .arg(key)
+        .arg(field_times_key(key))
+        .arg(time);
+    for f in fields {
+        pipe.arg(&f.name).arg(&f.val);
+    }
+    pipe.ignore();
+}
+
+/// Queues the same removal as `remove_hash_fields` onto a pipeline.
+pub fn pipe_remove_hash_fields(
+    pipe: &mut redis::Pipeline,
+    key: &str,
+    fields: &[String],
+    time: u64,
+) {
+    if fields.is_empty() {
+        return;
+    }
+
+    pipe.cmd("EVAL")
+        .arg(REMOVE_HASH_FIELDS_SRC)
+        .arg(2)
+        .arg(key)
+        .arg(field_times_key(key))
+        .arg(time);
+    for f in fields {
+        pipe.arg(f);
+    }
+    pipe.ignore();
+}
+
+/// Queues the same deletion as `delete_key` onto a pipeline.
+pub fn pipe_delete_key(pipe: &mut redis::Pipeline, key: &str, time: u64) {
+    pipe.cmd("EVAL")
+        .arg(DELETE_KEY_SRC)
+        .arg(2)
+        .arg(key)
+        .arg(field_times_key(key))
+        .arg(time)
+        .ignore();
+}
@@ -1,15 +1,21 @@
+use crate::admin;
 use crate::authentication::{authenticate, AuthenticationResult};
-use crate::authorization::authorize;
+use crate::authorization::{authorize, authorize_admin};
+use crate::calibration;
 use crate::config::Config;
+use crate::history;
 use crate::key_pairs;
-use crate::push::{PushData, PushDataError};
+use crate::push::{BatchItemStatus, BatchResults, PushBatch, PushData, PushDataError};
 use crate::redis_conn::*;
+use crate::signing::Verifier;
+use crate::sync;
 use crate::tanks;
 use rocket::http::hyper::header::{AccessControlAllowOrigin, AccessControlMaxAge};
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request};
 use rocket::{Outcome, State};
-use rocket_contrib::json::Json;
+use rocket_contrib::json::{Json, JsonError};
+use std::time::SystemTime;
 
 /// This route requires that you authenticate using
 /// a Firebase-signed JWT.
@@ -25,11 +31,7 @@ pub fn tanks(
 ) -> Result<CorsResponder, rocket_contrib::databases::redis::RedisError> {
     Ok(CorsResponder {
         inner: Json(tanks::fetch_all(conn, &config.redis_namespace)?),
-        header: config
-            .cors_allow_origin
-            .clone()
-            .map(|allow_origin| AccessControlAllowOrigin::Value(allow_origin))
-            .unwrap_or(AccessControlAllowOrigin::Any),
+        header: allow_origin(&config),
     })
 }
 
@@ -41,22 +43,301 @@ pub struct CorsResponder {
 }
 
 const ONE_DAY: u32 = 86400;
+const ONE_HOUR: u64 = 3600;
+
+/// Returns a summary of temp & pH readings for a single tank
+/// over time, so that the frontend can chart trends.
+/// The readings are grouped into buckets of `resolution`
+/// seconds, each of which reports min, max, and avg.
+///
+/// - `to` defaults to the present moment
+/// - `from` defaults to one day before `to`
+/// - `resolution` defaults to one hour
+///
+/// Like the `/tanks` route, this requires a Firebase-signed JWT,
+/// and responds with the application origin whitelisted.
+///
+/// ```sh
+/// curl -k -H "Authorization: Bearer $FIREBASE_JWT" "https://$FIREBASE_HOST/tanks/1/history?resolution=600"
+/// ```
+#[get("/tanks/<id>/history?<from>&<to>&<resolution>")]
+pub fn tank_history(
+    _user: AuthorizedUser,
+    conn: RedisDbConn,
+    config: State<Config>,
+    id: u16,
+    from: Option<u64>,
+    to: Option<u64>,
+    resolution: Option<u64>,
+) -> Result<HistoryCorsResponder, Status> {
+    let to = to.unwrap_or(epoch_secs());
+    let from = from.unwrap_or(to.saturating_sub(ONE_DAY as u64));
+    let resolution = resolution.unwrap_or(ONE_HOUR);
+
+    if from >= to || resolution == 0 || (to - from) / resolution > history::MAX_BUCKETS {
+        return Err(Status::BadRequest);
+    }
+
+    match history::fetch(&conn, &config.redis_namespace, id, from, to, resolution) {
+        Ok(h) => Ok(HistoryCorsResponder {
+            inner: Json(h),
+            header: allow_origin(&config),
+        }),
+        Err(e) => {
+            eprintln!("Error fetching history for tank {}: {:?}", id, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(Responder)]
+#[response(content_type = "json")]
+pub struct HistoryCorsResponder {
+    inner: Json<history::TankHistory>,
+    header: AccessControlAllowOrigin,
+}
+
+/// Reports whether each pH sensor is due for calibration,
+/// either because its calibration is older than
+/// `CALIBRATION_MAX_AGE_DAYS`, or because its slope efficiency
+/// has fallen below `CALIBRATION_MIN_SLOPE_EFFICIENCY`.
+///
+/// Like the `/tanks` route, this requires a Firebase-signed JWT,
+/// and responds with the application origin whitelisted.
+///
+/// ```sh
+/// curl -k -H "Authorization: Bearer $FIREBASE_JWT" https://$FIREBASE_HOST/sensors/ph/calibration
+/// ```
+#[get("/sensors/ph/calibration")]
+pub fn ph_calibration(
+    _user: AuthorizedUser,
+    conn: RedisDbConn,
+    config: State<Config>,
+) -> Result<CalibrationCorsResponder, rocket_contrib::databases::redis::RedisError> {
+    Ok(CalibrationCorsResponder {
+        inner: Json(calibration::fetch_all(
+            &conn,
+            &config.redis_namespace,
+            &config.due_settings(),
+            epoch_secs(),
+        )?),
+        header: allow_origin(&config),
+    })
+}
+
+#[derive(Responder)]
+#[response(content_type = "json")]
+pub struct CalibrationCorsResponder {
+    inner: Json<Vec<redis_delta::CalibrationStatus>>,
+    header: AccessControlAllowOrigin,
+}
+
+/// Lists sensors which have reported readings, but which
+/// haven't been linked to a tank or an area.  Requires
+/// a Firebase-signed JWT belonging to an admin.
+///
+/// ```sh
+/// curl -k -H "Authorization: Bearer $FIREBASE_JWT" https://$FIREBASE_HOST/admin/sensors/unassigned
+/// ```
+#[get("/admin/sensors/unassigned")]
+pub fn unassigned_sensors(
+    _admin: AdminUser,
+    conn: RedisDbConn,
+    config: State<Config>,
+) -> Result<UnassignedCorsResponder, rocket_contrib::databases::redis::RedisError> {
+    Ok(UnassignedCorsResponder {
+        inner: Json(admin::fetch_unassigned_sensors(
+            &conn,
+            &config.redis_namespace,
+        )?),
+        header: allow_origin(&config),
+    })
+}
+
+#[derive(Responder)]
+#[response(content_type = "json")]
+pub struct UnassignedCorsResponder {
+    inner: Json<Vec<admin::UnassignedSensor>>,
+    header: AccessControlAllowOrigin,
+}
+
+/// Links a sensor to a tank or to an area.  Sending neither
+/// unlinks the sensor.  The change is made here right away,
+/// and is picked up by the local site via the `/outbox` route.
+///
+/// ```sh
+/// curl -k -X PUT -H "Authorization: Bearer $FIREBASE_JWT" -H "Content-Type: application/json" -d '{"tank": 1}' https://$FIREBASE_HOST/admin/sensors/temp/aaaaaaaa-eeee-aaaa-aaaa-aaaaaaaaaaaa/assignment
+/// ```
+#[put(
+    "/admin/sensors/<sensor_type>/<id>/assignment",
+    format = "application/json",
+    data = "<assignment>"
+)]
+pub fn assign_sensor(
+    _admin: AdminUser,
+    conn: RedisDbConn,
+    config: State<Config>,
+    sensor_type: String,
+    id: String,
+    assignment: Json<admin::Assignment>,
+) -> Result<NoContentCorsResponder, Status> {
+    if assignment.tank.is_some() && assignment.area.is_some() {
+        return Err(Status::BadRequest);
+    }
+
+    match admin::assign_sensor(
+        &conn,
+        &config.redis_namespace,
+        &sensor_type,
+        &id,
+        &assignment,
+    ) {
+        Ok(Some(())) => Ok(no_content(&config)),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("Error assigning sensor {}/{}: {:?}", sensor_type, id, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// ```sh
+/// curl -k -X PUT -H "Authorization: Bearer $FIREBASE_JWT" -H "Content-Type: application/json" -d '{"name": "Nursery"}' https://$FIREBASE_HOST/admin/tanks/1/name
+/// ```
+#[put("/admin/tanks/<id>/name", format = "application/json", data = "<name>")]
+pub fn rename_tank(
+    _admin: AdminUser,
+    conn: RedisDbConn,
+    config: State<Config>,
+    id: u16,
+    name: Json<admin::TankName>,
+) -> Result<NoContentCorsResponder, Status> {
+    if name.name.trim().is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    match admin::rename_tank(&conn, &config.redis_namespace, id, name.name.trim()) {
+        Ok(()) => Ok(no_content(&config)),
+        Err(e) => {
+            eprintln!("Error renaming tank {}: {:?}", id, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Tanks are numbered from 1 up to this count.
+///
+/// ```sh
+/// curl -k -X PUT -H "Authorization: Bearer $FIREBASE_JWT" -H "Content-Type: application/json" -d '{"count": 2}' https://$FIREBASE_HOST/admin/tanks/count
+/// ```
+#[put("/admin/tanks/count", format = "application/json", data = "<count>")]
+pub fn set_tank_count(
+    _admin: AdminUser,
+    conn: RedisDbConn,
+    config: State<Config>,
+    count: Json<admin::TankCount>,
+) -> Result<NoContentCorsResponder, Status> {
+    match admin::set_tank_count(&conn, &config.redis_namespace, count.count) {
+        Ok(()) => Ok(no_content(&config)),
+        Err(e) => {
+            eprintln!("Error setting tank count: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 204)]
+pub struct NoContentCorsResponder {
+    inner: (),
+    header: AccessControlAllowOrigin,
+}
+
+fn no_content(config: &Config) -> NoContentCorsResponder {
+    NoContentCorsResponder {
+        inner: (),
+        header: allow_origin(config),
+    }
+}
 
 #[options("/tanks")]
 pub fn tanks_options(config: State<Config>) -> PreflightOptionsResponder {
+    preflight(&config)
+}
+
+#[options("/tanks/<_id>/history")]
+pub fn tank_history_options(_id: u16, config: State<Config>) -> PreflightOptionsResponder {
+    preflight(&config)
+}
+
+#[options("/sensors/ph/calibration")]
+pub fn ph_calibration_options(config: State<Config>) -> PreflightOptionsResponder {
+    preflight(&config)
+}
+
+#[options("/admin/sensors/unassigned")]
+pub fn unassigned_sensors_options(config: State<Config>) -> PreflightOptionsResponder {
+    preflight(&config)
+}
+
+#[options("/admin/sensors/<_sensor_type>/<_id>/assignment")]
+pub fn assign_sensor_options(
+    _sensor_type: String,
+    _id: String,
+    config: State<Config>,
+) -> PreflightOptionsResponder {
+    admin_preflight(&config)
+}
+
+#[options("/admin/tanks/<_id>/name")]
+pub fn rename_tank_options(_id: u16, config: State<Config>) -> PreflightOptionsResponder {
+    admin_preflight(&config)
+}
+
+#[options("/admin/tanks/count")]
+pub fn set_tank_count_options(config: State<Config>) -> PreflightOptionsResponder {
+    admin_preflight(&config)
+}
+
+fn preflight(config: &Config) -> PreflightOptionsResponder {
     PreflightOptionsResponder {
         inner: (),
-        allow_origin: config
-            .cors_allow_origin
-            .clone()
-            .map(|allow_origin| AccessControlAllowOrigin::Value(allow_origin))
-            .unwrap_or(AccessControlAllowOrigin::Any),
+        allow_origin: allow_origin(config),
         allow_methods: rocket::http::Header::new("Access-Control-Allow-Methods", "GET"),
         allow_headers: rocket::http::Header::new("Access-Control-Allow-Headers", "Authorization"),
         max_age: AccessControlMaxAge(ONE_DAY),
     }
 }
 
+/// Admin routes which change data also receive a JSON body.
+fn admin_preflight(config: &Config) -> PreflightOptionsResponder {
+    PreflightOptionsResponder {
+        inner: (),
+        allow_origin: allow_origin(config),
+        allow_methods: rocket::http::Header::new("Access-Control-Allow-Methods", "PUT"),
+        allow_headers: rocket::http::Header::new(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        ),
+        max_age: AccessControlMaxAge(ONE_DAY),
+    }
+}
+
+fn allow_origin(config: &Config) -> AccessControlAllowOrigin {
+    config
+        .cors_allow_origin
+        .clone()
+        .map(|allow_origin| AccessControlAllowOrigin::Value(allow_origin))
+        .unwrap_or(AccessControlAllowOrigin::Any)
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 #[derive(Responder)]
 pub struct PreflightOptionsResponder {
     inner: (),
@@ -120,6 +401,64 @@ impl<'a, 'r> FromRequest<'a, 'r> for AuthorizedUser {
     }
 }
 
+/// An authorized user who is also listed in
+/// `<namespace>/pond/firebase/admin_uids`.
+#[derive(Debug)]
+pub struct AdminUser {
+    uid: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AdminUser, ()> {
+        let user = request.guard::<AuthorizedUser>()?;
+        let redis_namespace: String = request
+            .guard::<State<Config>>()?
+            .inner()
+            .redis_namespace
+            .to_string();
+        let redis_conn = request.guard::<RedisDbConn>()?;
+
+        match authorize_admin(
+            &user.uid,
+            &RedisConnContext {
+                namespace: redis_namespace,
+                conn: redis_conn,
+            },
+        ) {
+            Ok(true) => Outcome::Success(AdminUser { uid: user.uid }),
+            Ok(false) => Outcome::Failure((Status::Forbidden, ())),
+            Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// The signature sent by redis_aggregator when it asks for
+/// changes made by admins, or for keys which need syncing.
+/// `X-Pond-Key-Id` names the key which made it, if any.
+pub struct AggregatorSignature {
+    sig: String,
+    key_id: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AggregatorSignature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AggregatorSignature, ()> {
+        match request.headers().get_one("X-Pond-Signature") {
+            Some(sig) => Outcome::Success(AggregatorSignature {
+                sig: sig.to_string(),
+                key_id: request
+                    .headers()
+                    .get_one("X-Pond-Key-Id")
+                    .map(|k| k.to_string()),
+            }),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
 fn token_from_bearer_string(bearer_string: &str) -> Result<String, ()> {
     let v: Vec<&str> = bearer_string.split(' ').collect();
     if let Some(token) = v.get(1) {
@@ -147,16 +486,28 @@ fn token_from_bearer_string(bearer_string: &str) -> Result<String, ()> {
 /// ```
 ///
 /// The attributes included in the push data should include a signature
-/// generated using HMAC SHA 256 and a shared secret.  This is sent by
-/// redis_aggregator service.
+/// generated using HMAC SHA 256 and a shared secret, or using an Ed25519
+/// private key held by the local site.  A `kid` attribute names the key,
+/// so that several can be accepted while keys are rotated.  This is sent
+/// by redis_aggregator service.
+///
+/// Deltas older than `PUSH_REPLAY_WINDOW_SECS`, messages which have
+/// already been ingested, and kinds of delta which we don't know yet
+/// are acknowledged with `202 Accepted` but otherwise ignored.  Pub/Sub would redeliver them forever if we
+/// answered with an error.
 #[post("/push_redis", format = "application/json", data = "<data>")]
-pub fn push_redis(data: Json<PushData>, conn: RedisDbConn, config: State<Config>) -> Status {
-    if data
-        .message
-        .verify_signature(config.signing_secret.as_bytes())
-    {
-        match data.ingest(conn) {
+pub fn push_redis(
+    data: Json<PushData>,
+    conn: RedisDbConn,
+    config: State<Config>,
+    verifier: State<Verifier>,
+) -> Status {
+    if data.message.verify_signature(&verifier) {
+        match data.ingest(conn, &config, epoch_secs()) {
             Ok(_) => Status::NoContent,
+            Err(PushDataError::Stale)
+            | Err(PushDataError::Duplicate)
+            | Err(PushDataError::Unknown) => Status::Accepted,
             Err(PushDataError::Redis) => Status::InternalServerError,
             Err(_) => Status::UnprocessableEntity,
         }
@@ -165,16 +516,144 @@ pub fn push_redis(data: Json<PushData>, conn: RedisDbConn, config: State<Config>
     }
 }
 
+/// Receives many push messages at once, in the same shape that
+/// Pub/Sub would deliver them individually.  Each message carries its
+/// own signature.  `message_id` is optional here; messages without
+/// one are not checked for duplicates.
+///
+/// All of the deltas which pass the signature and replay checks are
+/// applied together in one Redis transaction.  The response lists
+/// what became of each message, in order: `applied`, `stale`,
+/// `duplicate`, `unauthorized`, `invalid` or `skipped`, for kinds of
+/// delta which pond doesn't know yet.  If Redis fails, nothing is
+/// applied, `500` is returned, and the whole batch may be retried.
+///
+/// A batch which can never be applied is refused outright, so that
+/// the sender stops retrying it: `401` when every signature is bad,
+/// and `400` when the body can't be read or every message is invalid.
+/// Skipped messages never cause a `400`, even when the whole batch is
+/// skipped, since a newer pond would accept them.
+#[post("/push_redis/batch", format = "application/json", data = "<data>")]
+pub fn push_redis_batch(
+    data: Result<Json<PushBatch>, JsonError>,
+    conn: RedisDbConn,
+    config: State<Config>,
+    verifier: State<Verifier>,
+) -> Result<Json<BatchResults>, Status> {
+    let data = data.map_err(|_| Status::BadRequest)?;
+    match data.ingest(conn, &config, &verifier, epoch_secs()) {
+        Ok(ref results) if results.all(&BatchItemStatus::Unauthorized) => Err(Status::Unauthorized),
+        Ok(ref results) if results.all(&BatchItemStatus::Invalid) => Err(Status::BadRequest),
+        Ok(results) => Ok(Json(results)),
+        Err(PushDataError::Redis) => Err(Status::InternalServerError),
+        Err(_) => Err(Status::BadRequest),
+    }
+}
+
+/// Changes made using the admin routes, which the redis_aggregator
+/// running at the local site applies to its own copy of Redis.
+/// `after` is the sequence number of the last change it has seen.
+///
+/// The `X-Pond-Signature` header must contain a base64 signature of
+/// `outbox:<after>:<time>`, made using the same keys that sign push
+/// messages, and named by `X-Pond-Key-Id` if need be.  `time` must be
+/// within five minutes of our clock.
+#[get("/outbox?<after>&<time>")]
+pub fn outbox(
+    signature: AggregatorSignature,
+    conn: RedisDbConn,
+    config: State<Config>,
+    verifier: State<Verifier>,
+    after: u64,
+    time: u64,
+) -> Result<Json<admin::OutboxBatch>, Status> {
+    if !admin::verify_outbox_signature(
+        after,
+        time,
+        &signature.sig,
+        signature.key_id.as_ref().map(|k| &k[..]),
+        &verifier,
+        epoch_secs(),
+    ) {
+        return Err(Status::Unauthorized);
+    }
+
+    match admin::fetch_outbox(&conn, &config.redis_namespace, after) {
+        Ok(batch) => Ok(Json(batch)),
+        Err(e) => {
+            eprintln!("Error fetching outbox: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Compares digests of the local site's replicated keys with our own
+/// copies, and answers with the keys which differ, along with anything
+/// we have which the local site may not (see `sync::compare`).  Signed
+/// just like requests for the outbox, using the message `sync:<time>`.
+#[post("/sync/digests?<time>", format = "application/json", data = "<data>")]
+pub fn sync_digests(
+    signature: AggregatorSignature,
+    data: Json<sync::DigestRequest>,
+    conn: RedisDbConn,
+    config: State<Config>,
+    verifier: State<Verifier>,
+    time: u64,
+) -> Result<Json<sync::DigestResponse>, Status> {
+    if !sync::verify_sync_signature(
+        time,
+        &signature.sig,
+        signature.key_id.as_ref().map(|k| &k[..]),
+        &verifier,
+        epoch_secs(),
+    ) {
+        return Err(Status::Unauthorized);
+    }
+
+    match sync::compare(&conn, &config.redis_namespace, &data) {
+        Ok(response) => Ok(Json(response)),
+        Err(e) => {
+            eprintln!("Error comparing digests: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
 #[get("/ping")]
 pub fn ping() -> Status {
     Status::NoContent
 }
 
 pub fn startup(config: Config) {
+    let verifier = Verifier::new(&config);
     rocket::ignite()
         .manage(config)
+        .manage(verifier)
         .attach(RedisDbConn::fairing())
-        .mount("/", routes![tanks, tanks_options, push_redis, ping])
+        .mount(
+            "/",
+            routes![
+                tanks,
+                tanks_options,
+                tank_history,
+                tank_history_options,
+                ph_calibration,
+                ph_calibration_options,
+                unassigned_sensors,
+                unassigned_sensors_options,
+                assign_sensor,
+                assign_sensor_options,
+                rename_tank,
+                rename_tank_options,
+                set_tank_count,
+                set_tank_count_options,
+                push_redis,
+                push_redis_batch,
+                outbox,
+                sync_digests,
+                ping
+            ],
+        )
         .launch();
 }
 
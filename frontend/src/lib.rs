@@ -7,11 +7,13 @@ extern crate serde_derive;
 extern crate stdweb;
 #[macro_use]
 extern crate yew;
+extern crate redis_delta;
 
 mod pond;
 
 use crate::pond::PondService;
 use failure::Error;
+use redis_delta::TankRecord;
 use std::time::Duration;
 use stdweb::unstable::TryInto;
 use stdweb::Value;
@@ -19,20 +21,12 @@ use yew::prelude::*;
 use yew::services::{ConsoleService, IntervalService, Task};
 
 /// A struct to hold data returned by the HTTP request
-/// for tanks' temp & ph info.
+/// for tanks' temp, pH, DO & EC info.
 #[derive(Debug, Deserialize)]
 pub struct Tank {
     pub id: u16,
-    pub name: Option<String>,
-    pub temp_f: Option<f32>,
-    pub temp_c: Option<f32>,
-    pub temp_update_time: Option<u64>,
-    pub temp_update_count: Option<u32>,
-
-    pub ph: Option<f32>,
-    pub ph_mv: Option<f32>,
-    pub ph_update_time: Option<u64>,
-    pub ph_update_count: Option<u32>,
+    #[serde(flatten)]
+    pub record: TankRecord,
 }
 
 pub struct Tanks(pub Vec<Tank>);
@@ -47,7 +41,7 @@ impl Tanks {
 pub struct AuthToken(pub String);
 
 /// `auth_token` lets us know whom we're dealing with
-/// `tanks` is the current set of temp & ph data for all tanks in the system, the payload we're interested in showing to the end user
+/// `tanks` is the current set of temp, pH, DO & EC data for all tanks in the system, the payload we're interested in showing to the end user
 /// `link` is used by the javascript.  rust compiler will tell you that you can get rid of it.  DON'T BELIEVE ITS LIES.
 /// `callback_tanks` is invoked when the HTTP request to get recent data is completed
 /// `interval` sends a Tick message every so often, triggering an HTTP fetch of the tank data
@@ -71,17 +65,35 @@ impl Model {
             html! {
                 <tr>
                     <td>{ tank.id }</td>
-                    <td>{ tank.name.clone().unwrap_or("".to_owned()) }</td>
+                    <td>{ tank.record.name.clone().unwrap_or("".to_owned()) }</td>
                     <td>
                     {
                       if self.use_fahrenheit {
-                        tank.temp_f.map(|t| format!("{}℉", t))
+                        tank.record.temp_f.map(|t| format!("{}℉", t))
                       } else {
-                        tank.temp_c.map(|t| format!("{}℃", t))
+                        tank.record.temp_c.map(|t| format!("{}℃", t))
                       }.unwrap_or("".to_owned())
                     }
                     </td>
-                    <td>{ tank.ph.map(|ph| format!("{}",ph)).unwrap_or("".to_owned()) }</td>
+                    <td>{ tank.record.ph.map(|ph| format!("{}",ph)).unwrap_or("".to_owned()) }</td>
+                    <td>
+                    {
+                      match (tank.record.do_mg_l, tank.record.do_saturation) {
+                        (Some(mg_l), Some(sat)) => format!("{} mg/L ({}%)", mg_l, sat),
+                        (Some(mg_l), None) => format!("{} mg/L", mg_l),
+                        _ => "".to_owned(),
+                      }
+                    }
+                    </td>
+                    <td>
+                    {
+                      match (tank.record.ec_us_cm, tank.record.tds_ppm) {
+                        (Some(ec), Some(tds)) => format!("{} µS/cm ({} ppm)", ec, tds),
+                        (Some(ec), None) => format!("{} µS/cm", ec),
+                        _ => "".to_owned(),
+                      }
+                    }
+                    </td>
                 </tr>
             }
         };
@@ -242,6 +254,8 @@ impl Renderable<Model> for Model {
                                     <th>{"Name"}</th>
                                     <th>{"Temp"}</th>
                                     <th>{"pH"}</th>
+                                    <th>{"DO"}</th>
+                                    <th>{"EC"}</th>
                                 </tr>
                             </thead>
                             <tbody>
@@ -0,0 +1,3 @@
+#!/bin/bash
+
+docker run --rm -ti -d prawnalith/alert_tracker
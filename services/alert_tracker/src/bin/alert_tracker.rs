@@ -0,0 +1,24 @@
+extern crate alert_tracker;
+extern crate crossbeam_channel;
+extern crate dotenv;
+
+use alert_tracker::config::AlertConfig;
+use alert_tracker::logic::{consume_delta_events, watch_tanks};
+
+fn main() {
+    dotenv::dotenv().expect("Unable to load .env file");
+
+    let config = AlertConfig::new();
+    let sub_config = config.clone();
+
+    let mut notifiers = config
+        .to_notifiers()
+        .expect("Unable to set up notifications");
+    println!("{} notifier(s) enabled", notifiers.len());
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+
+    std::thread::spawn(move || consume_delta_events(&sub_config, tx));
+
+    watch_tanks(rx, &config, &mut notifiers)
+}
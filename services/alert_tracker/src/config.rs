@@ -0,0 +1,148 @@
+use redis_context::RedisContext;
+
+use crate::mqtt::MqttNotifier;
+use crate::notify::{Notifier, NotifyError};
+use crate::smtp::{SmtpNotifier, SmtpSettings};
+use crate::webhook::WebhookNotifier;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AlertConfig {
+    pub redis_auth: Option<String>,
+    pub redis_host: Option<String>,
+    pub redis_port: Option<u16>,
+    pub redis_namespace: Option<String>,
+    pub redis_delta_event_topic: Option<String>,
+    pub redis_alert_event_topic: Option<String>,
+    pub check_interval_secs: Option<u64>,
+    pub rate_window_secs: Option<u64>,
+    pub notify_mqtt_host: Option<String>,
+    pub notify_mqtt_port: Option<u16>,
+    pub notify_mqtt_topic: Option<String>,
+    pub notify_mqtt_qos: Option<u8>,
+    pub notify_webhook_url: Option<String>,
+    pub notify_smtp_host: Option<String>,
+    pub notify_smtp_port: Option<u16>,
+    pub notify_smtp_username: Option<String>,
+    pub notify_smtp_password: Option<String>,
+    pub notify_smtp_tls: Option<bool>,
+    pub notify_smtp_from: Option<String>,
+    pub notify_smtp_to: Option<String>,
+}
+
+impl AlertConfig {
+    pub fn new() -> AlertConfig {
+        match envy::from_env::<AlertConfig>() {
+            Ok(config) => config,
+            Err(e) => panic!("Unable to parse config ({})", e),
+        }
+    }
+
+    /// Create an object which holds both a connection to redis
+    /// and a string "namespace" used to prefix all keys.
+    pub fn to_redis_context(&self) -> RedisContext {
+        RedisContext::new(
+            (self.redis_host.clone().unwrap_or("127.0.0.1".to_string())).to_string(),
+            self.redis_port.unwrap_or(6379),
+            self.redis_auth.clone(),
+            self.namespace(),
+        )
+    }
+
+    /// We need a separate client for the pub/sub connection,
+    /// since it blocks while listening.
+    pub fn to_redis_client(&self) -> redis::Client {
+        let host = (self.redis_host.clone().unwrap_or("127.0.0.1".to_string())).to_string();
+        let port = self.redis_port.unwrap_or(6379);
+        let rci = redis::ConnectionInfo {
+            addr: Box::new(redis::ConnectionAddr::Tcp(host, port)),
+            db: 0,
+            passwd: self.redis_auth.clone(),
+        };
+        redis::Client::open(rci).unwrap()
+    }
+
+    pub fn namespace(&self) -> String {
+        self.redis_namespace.clone().unwrap_or("".to_string())
+    }
+
+    /// The topic which sensor_tracker uses to announce changes.
+    pub fn delta_event_topic(&self) -> String {
+        self.redis_delta_event_topic
+            .clone()
+            .unwrap_or(format!("{}/system/delta_events", self.namespace()))
+    }
+
+    /// The topic on which we announce alerts opening and resolving.
+    pub fn alert_event_topic(&self) -> String {
+        self.redis_alert_event_topic
+            .clone()
+            .unwrap_or(format!("{}/system/alert_events", self.namespace()))
+    }
+
+    /// How often we check every tank, even if no readings arrive.
+    /// This is how we catch stale sensors.
+    pub fn check_interval_secs(&self) -> u64 {
+        self.check_interval_secs.unwrap_or(30)
+    }
+
+    /// Rate of change is measured against the most recent
+    /// reading which is at least this old.
+    pub fn rate_window_secs(&self) -> u64 {
+        self.rate_window_secs.unwrap_or(3600)
+    }
+
+    /// Build every notifier which has been configured.
+    ///
+    /// - MQTT is enabled by `NOTIFY_MQTT_TOPIC`
+    /// - the webhook is enabled by `NOTIFY_WEBHOOK_URL`
+    /// - email is enabled by `NOTIFY_SMTP_HOST`, and also needs
+    ///   `NOTIFY_SMTP_FROM` and a comma-separated `NOTIFY_SMTP_TO`
+    pub fn to_notifiers(&self) -> Result<Vec<Box<dyn Notifier>>, NotifyError> {
+        let mut notifiers: Vec<Box<dyn Notifier>> = vec![];
+
+        if let Some(topic) = &self.notify_mqtt_topic {
+            notifiers.push(Box::new(MqttNotifier::new(
+                &self
+                    .notify_mqtt_host
+                    .clone()
+                    .unwrap_or("127.0.0.1".to_string()),
+                self.notify_mqtt_port.unwrap_or(1883),
+                topic,
+                self.notify_mqtt_qos.unwrap_or(1),
+            )?));
+        }
+
+        if let Some(url) = &self.notify_webhook_url {
+            notifiers.push(Box::new(WebhookNotifier::new(url)?));
+        }
+
+        if let Some(settings) = self.to_smtp_settings() {
+            notifiers.push(Box::new(SmtpNotifier::new(settings)?));
+        }
+
+        Ok(notifiers)
+    }
+
+    pub fn to_smtp_settings(&self) -> Option<SmtpSettings> {
+        let host = self.notify_smtp_host.clone()?;
+        let from = self.notify_smtp_from.clone()?;
+        let to: Vec<String> = self
+            .notify_smtp_to
+            .clone()?
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        let tls = self.notify_smtp_tls.unwrap_or(false);
+
+        Some(SmtpSettings {
+            host,
+            port: self.notify_smtp_port.unwrap_or(if tls { 587 } else { 25 }),
+            username: self.notify_smtp_username.clone(),
+            password: self.notify_smtp_password.clone(),
+            tls,
+            from,
+            to,
+        })
+    }
+}
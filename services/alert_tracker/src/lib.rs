@@ -0,0 +1,33 @@
+//! # Alert Tracker
+//!
+//! This is a service which watches the readings of each tank,
+//! and raises an alert when a tank goes out of range.
+//! It listens for the delta events emitted by sensor_tracker,
+//! evaluates rules stored in Redis, and records each alert as
+//! it opens and resolves.
+//!
+//! Alerts can also be sent somewhere a human will see them:
+//! an MQTT topic, a webhook, or email.  See `notify`.
+#[macro_use]
+extern crate crossbeam_channel as crossbeam;
+extern crate hyper;
+extern crate hyper_native_tls;
+extern crate lettre;
+extern crate lettre_email;
+extern crate native_tls;
+extern crate redis_context;
+extern crate redis_delta;
+extern crate rumqtt;
+#[macro_use]
+extern crate serde_derive;
+extern crate uuid;
+
+pub mod config;
+pub mod logic;
+pub mod model;
+pub mod mqtt;
+pub mod notify;
+mod predis;
+pub mod rules;
+pub mod smtp;
+pub mod webhook;
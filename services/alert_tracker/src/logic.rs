@@ -0,0 +1,262 @@
+use crossbeam::{Receiver, Sender};
+use redis_context::RedisContext;
+use redis_delta::{Decoded, Encoding, REvent};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::config::AlertConfig;
+use crate::model::*;
+use crate::notify::{self, Notifier};
+use crate::predis;
+use crate::rules::{self, Transition};
+
+/// Listens to the delta event topic which sensor_tracker publishes to,
+/// and passes along the ID of every tank whose hash has changed.
+pub fn consume_delta_events(config: &AlertConfig, tx: Sender<u16>) {
+    let redis_client = config.to_redis_client();
+    let mut sub_conn = redis_client.get_connection().unwrap();
+    let mut sub = sub_conn.as_pubsub();
+    let topic = config.delta_event_topic();
+    let namespace = config.namespace();
+    sub.subscribe(&topic).unwrap();
+
+    println!("Subscribed to redis channel: {}", topic);
+
+    loop {
+        if let Ok(msg) = sub.get_message() {
+            let payload = msg.get_payload().unwrap_or("".to_string());
+            // events which we don't know are never about tank readings
+            let revent = Encoding::Json.decode_envelope::<REvent>(payload.as_bytes());
+            if let Ok(Decoded::Known(REvent::HashUpdated { key, fields: _ })) = revent {
+                if let Some(id) = predis::parse_tank_key(&namespace, &key) {
+                    tx.send(id).unwrap()
+                }
+            }
+        }
+    }
+}
+
+/// Evaluates a tank every time one of its readings changes,
+/// and periodically evaluates all tanks, so that we can
+/// notice when a sensor goes quiet.
+pub fn watch_tanks(rx: Receiver<u16>, config: &AlertConfig, notifiers: &mut [Box<dyn Notifier>]) {
+    let redis_ctx = config.to_redis_context();
+    let ticker = crossbeam::tick(Duration::from_secs(config.check_interval_secs()));
+
+    loop {
+        select! {
+            recv(rx) -> id => {
+                evaluate_and_record(&redis_ctx, id.unwrap(), config, notifiers)
+            },
+            recv(ticker) -> _ => {
+                match predis::fetch_num_tanks(&redis_ctx) {
+                    Ok(num_tanks) => {
+                        for id in 1..=num_tanks {
+                            evaluate_and_record(&redis_ctx, id, config, notifiers)
+                        }
+                    }
+                    Err(e) => eprintln!("Couldn't count tanks: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+fn evaluate_and_record(
+    redis_ctx: &RedisContext,
+    id: u16,
+    config: &AlertConfig,
+    notifiers: &mut [Box<dyn Notifier>],
+) {
+    let result =
+        evaluate_tank(redis_ctx, id, config.rate_window_secs(), epoch_secs()).and_then(|events| {
+            for e in &events {
+                println!(
+                    "Tank {} alert {} {:?}: {}",
+                    e.tank, e.alert, e.status, e.val
+                );
+            }
+            predis::record_events(
+                redis_ctx,
+                id,
+                &events,
+                &config.alert_event_topic(),
+                &config.delta_event_topic(),
+            )?;
+            Ok(events)
+        });
+    notify_recorded(id, result, notifiers)
+}
+
+/// Only events which were recorded are sent to the notifiers.
+/// Otherwise the alert state in redis wouldn't match what we
+/// told the humans, and the same alert would be sent again
+/// on the next evaluation.
+fn notify_recorded(
+    id: u16,
+    recorded: Result<Vec<AlertEvent>, redis::RedisError>,
+    notifiers: &mut [Box<dyn Notifier>],
+) {
+    match recorded {
+        Ok(events) => notify::notify_all(notifiers, &events),
+        Err(e) => eprintln!("Couldn't evaluate alerts for tank {}: {:?}", id, e),
+    }
+}
+
+/// Compares the current readings of a tank against its rules,
+/// and returns an event for each alert which opens or resolves.
+pub fn evaluate_tank(
+    redis_ctx: &RedisContext,
+    id: u16,
+    rate_window_secs: u64,
+    now: u64,
+) -> Result<Vec<AlertEvent>, redis::RedisError> {
+    let rule_fields = predis::fetch_rules(redis_ctx, id)?;
+    let tank = predis::fetch_tank(redis_ctx, id)?;
+    let state = predis::fetch_alert_state(redis_ctx, id)?;
+
+    let mut events: Vec<AlertEvent> = vec![];
+    for measure in Measure::all() {
+        let measure_rules = MeasureRules::from_fields(measure, &rule_fields);
+        let val: Option<f64> = tank
+            .get(measure.field())
+            .and_then(|s| s.parse::<f64>().ok());
+        let update_time: Option<u64> = tank
+            .get(measure.update_time_field())
+            .and_then(|s| s.parse::<u64>().ok());
+
+        let alert = |kind: AlertKind| Alert { measure, kind };
+
+        if let Some(v) = val {
+            if let Some(max) = measure_rules.limit.max {
+                let a = alert(AlertKind::High);
+                let t =
+                    rules::check_high(max, measure_rules.limit.hysteresis, is_open(&state, &a), v);
+                events.extend(to_event(id, &a, t, v, max, now));
+            }
+            if let Some(min) = measure_rules.limit.min {
+                let a = alert(AlertKind::Low);
+                let t =
+                    rules::check_low(min, measure_rules.limit.hysteresis, is_open(&state, &a), v);
+                events.extend(to_event(id, &a, t, v, min, now));
+            }
+            if let (Some(max_rate), Some(ut)) = (measure_rules.rate.max, update_time) {
+                let earlier = predis::fetch_reading_before(
+                    redis_ctx,
+                    id,
+                    measure,
+                    ut.saturating_sub(rate_window_secs),
+                )?;
+                if let Some(rate) = earlier.and_then(|e| rules::rate_per_hour(e, (ut, v))) {
+                    let a = alert(AlertKind::Rate);
+                    let t = rules::check_high(
+                        max_rate,
+                        measure_rules.rate.hysteresis,
+                        is_open(&state, &a),
+                        rate.abs(),
+                    );
+                    events.extend(to_event(id, &a, t, rate, max_rate, now));
+                }
+            }
+        }
+
+        if let (Some(stale_secs), Some(ut)) = (measure_rules.stale_secs, update_time) {
+            let a = alert(AlertKind::Stale);
+            let t = rules::check_stale(stale_secs, is_open(&state, &a), ut, now);
+            let age = now.saturating_sub(ut) as f64;
+            events.extend(to_event(id, &a, t, age, stale_secs as f64, now));
+        }
+    }
+
+    Ok(events)
+}
+
+fn is_open(state: &HashMap<String, String>, alert: &Alert) -> bool {
+    state
+        .get(&alert.name())
+        .map(|s| s == &AlertStatus::Open.to_string())
+        .unwrap_or(false)
+}
+
+fn to_event(
+    tank: u16,
+    alert: &Alert,
+    transition: Transition,
+    val: f64,
+    limit: f64,
+    time: u64,
+) -> Option<AlertEvent> {
+    let status = match transition {
+        Transition::Open => AlertStatus::Open,
+        Transition::Resolve => AlertStatus::Resolved,
+        Transition::Unchanged => return None,
+    };
+
+    Some(AlertEvent {
+        tank,
+        alert: alert.name(),
+        status,
+        val,
+        limit,
+        time,
+    })
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::NotifyError;
+    use std::sync::{Arc, Mutex};
+
+    struct FakeNotifier(Arc<Mutex<Vec<AlertEvent>>>);
+
+    impl Notifier for FakeNotifier {
+        fn notify(&mut self, event: &AlertEvent) -> Result<(), NotifyError> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    fn event(status: AlertStatus) -> AlertEvent {
+        AlertEvent {
+            tank: 1,
+            alert: "ph_low".to_string(),
+            status,
+            val: 6.43,
+            limit: 6.5,
+            time: 1541082869,
+        }
+    }
+
+    #[test]
+    fn recorded_events_are_sent() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(FakeNotifier(received.clone()))];
+
+        let events = vec![event(AlertStatus::Open), event(AlertStatus::Resolved)];
+        notify_recorded(1, Ok(events.clone()), &mut notifiers);
+
+        assert_eq!(*received.lock().unwrap(), events);
+    }
+
+    #[test]
+    fn unrecorded_events_are_not_sent() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(FakeNotifier(received.clone()))];
+
+        let failed = Err(redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "connection refused",
+        )));
+        notify_recorded(1, failed, &mut notifiers);
+
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+/// The tank readings which we know how to watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Measure {
+    TempF,
+    PH,
+}
+
+impl Measure {
+    pub fn all() -> Vec<Measure> {
+        vec![Measure::TempF, Measure::PH]
+    }
+
+    /// The name of the field on the tank hash,
+    /// also used as the prefix for all rule and
+    /// alert fields related to this measure.
+    pub fn field(&self) -> &'static str {
+        match self {
+            Measure::TempF => "temp_f",
+            Measure::PH => "ph",
+        }
+    }
+
+    /// The field that sensor_tracker updates each
+    /// time it writes a reading to the tank hash.
+    pub fn update_time_field(&self) -> &'static str {
+        match self {
+            Measure::TempF => "temp_update_time",
+            Measure::PH => "ph_update_time",
+        }
+    }
+}
+
+/// Bounds for a single value.  Once an alert is open,
+/// the value needs to come back inside the bound by at
+/// least `hysteresis` before the alert is resolved.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limit {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub hysteresis: f64,
+}
+
+/// All of the rules related to a single measure on a tank.
+///
+/// - `limit`: absolute bounds on the reading
+/// - `rate`: bounds on the rate of change, expressed in units per hour
+/// - `stale_secs`: how long we tolerate not hearing from the sensor
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeasureRules {
+    pub limit: Limit,
+    pub rate: Limit,
+    pub stale_secs: Option<u64>,
+}
+
+impl MeasureRules {
+    /// Rules are stored in redis hashes, with field names prefixed
+    /// by the measure, e.g. `ph_min`, `ph_max`, `temp_f_max_rate`.
+    pub fn from_fields(measure: Measure, fields: &HashMap<String, String>) -> MeasureRules {
+        let get = |suffix: &str| -> Option<f64> {
+            fields
+                .get(&format!("{}_{}", measure.field(), suffix))
+                .and_then(|s| s.parse::<f64>().ok())
+        };
+
+        MeasureRules {
+            limit: Limit {
+                min: get("min"),
+                max: get("max"),
+                hysteresis: get("hysteresis").unwrap_or(0.0),
+            },
+            rate: Limit {
+                min: None,
+                max: get("max_rate"),
+                hysteresis: get("rate_hysteresis").unwrap_or(0.0),
+            },
+            stale_secs: get("stale_secs").map(|s| s as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    Low,
+    High,
+    Rate,
+    Stale,
+}
+
+impl AlertKind {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            AlertKind::Low => "low",
+            AlertKind::High => "high",
+            AlertKind::Rate => "rate",
+            AlertKind::Stale => "stale",
+        }
+    }
+}
+
+/// A specific condition which can be raised on a tank,
+/// e.g. `ph_low` or `temp_f_stale`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Alert {
+    pub measure: Measure,
+    pub kind: AlertKind,
+}
+
+impl Alert {
+    pub fn name(&self) -> String {
+        format!("{}_{}", self.measure.field(), self.kind.suffix())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Open,
+    Resolved,
+}
+
+impl AlertStatus {
+    pub fn to_string(&self) -> String {
+        match self {
+            AlertStatus::Open => "open".to_string(),
+            AlertStatus::Resolved => "resolved".to_string(),
+        }
+    }
+}
+
+/// Emitted whenever an alert opens or resolves.
+/// `val` is the reading (or rate, or age in seconds)
+/// which caused the transition, and `limit` is the
+/// bound it was compared against.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub tank: u16,
+    pub alert: String,
+    pub status: AlertStatus,
+    pub val: f64,
+    pub limit: f64,
+    pub time: u64,
+}
@@ -0,0 +1,48 @@
+use rumqtt::{MqttClient, MqttOptions, Notification, QoS, ReconnectOptions};
+use uuid::Uuid;
+
+use crate::model::AlertEvent;
+use crate::notify::{Notifier, NotifyError};
+use crossbeam::Receiver;
+
+/// Publishes each alert event, as JSON, to an MQTT topic.
+pub struct MqttNotifier {
+    client: MqttClient,
+    topic: String,
+    qos: QoS,
+    // rumqtt hands us a channel of incoming notifications,
+    // which we don't care about, but need to keep alive.
+    _notifications: Receiver<Notification>,
+}
+
+impl MqttNotifier {
+    pub fn new(host: &str, port: u16, topic: &str, qos: u8) -> Result<MqttNotifier, NotifyError> {
+        let mqtt_options = MqttOptions::new(generate_mq_client_id(), host, port)
+            .set_keep_alive(10)
+            .set_reconnect_opts(ReconnectOptions::Always(10));
+
+        let (client, notifications) = MqttClient::start(mqtt_options)
+            .map_err(|e| NotifyError::MqttErr(format!("{:?}", e)))?;
+        let qos = QoS::from_u8(qos).map_err(|e| NotifyError::MqttErr(format!("{:?}", e)))?;
+
+        Ok(MqttNotifier {
+            client,
+            topic: topic.to_string(),
+            qos,
+            _notifications: notifications,
+        })
+    }
+}
+
+impl Notifier for MqttNotifier {
+    fn notify(&mut self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let payload = serde_json::to_string(event)?;
+        self.client
+            .publish(self.topic.clone(), self.qos, false, payload)
+            .map_err(|e| NotifyError::MqttErr(format!("{:?}", e)))
+    }
+}
+
+fn generate_mq_client_id() -> String {
+    format!("alert_tracker/{}", Uuid::new_v4())
+}
@@ -0,0 +1,83 @@
+use crate::model::{AlertEvent, AlertStatus};
+
+/// Something which can tell a human that an alert has
+/// opened or resolved.  Each implementation is configured
+/// through `AlertConfig`, and any number of them may be enabled.
+pub trait Notifier: Send {
+    fn notify(&mut self, event: &AlertEvent) -> Result<(), NotifyError>;
+}
+
+/// All possible errors that a notifier can throw
+#[derive(Debug)]
+pub enum NotifyError {
+    JsonErr(serde_json::Error),
+    MqttErr(String),
+    HttpErr(hyper::Error),
+    HttpStatus(u16),
+    TlsErr(String),
+    EmailErr(lettre_email::error::Error),
+    SmtpErr(lettre::smtp::error::Error),
+}
+
+impl From<serde_json::Error> for NotifyError {
+    fn from(error: serde_json::Error) -> Self {
+        NotifyError::JsonErr(error)
+    }
+}
+
+impl From<hyper::Error> for NotifyError {
+    fn from(error: hyper::Error) -> Self {
+        NotifyError::HttpErr(error)
+    }
+}
+
+impl From<lettre_email::error::Error> for NotifyError {
+    fn from(error: lettre_email::error::Error) -> Self {
+        NotifyError::EmailErr(error)
+    }
+}
+
+impl From<lettre::smtp::error::Error> for NotifyError {
+    fn from(error: lettre::smtp::error::Error) -> Self {
+        NotifyError::SmtpErr(error)
+    }
+}
+
+/// Tells every notifier about every event.  A notifier which
+/// fails doesn't stop the others from being tried.
+pub fn notify_all(notifiers: &mut [Box<dyn Notifier>], events: &[AlertEvent]) {
+    for event in events {
+        for n in notifiers.iter_mut() {
+            if let Err(e) = n.notify(event) {
+                eprintln!(
+                    "Couldn't send notification for tank {}: {:?}",
+                    event.tank, e
+                )
+            }
+        }
+    }
+}
+
+/// A short, human-readable line, e.g. `Tank 1: ph_low open (6.43, limit 6.5)`
+pub fn summary(event: &AlertEvent) -> String {
+    format!(
+        "Tank {}: {} {} ({}, limit {})",
+        event.tank,
+        event.alert,
+        event.status.to_string(),
+        event.val,
+        event.limit
+    )
+}
+
+/// A slightly longer explanation, used for email bodies.
+pub fn details(event: &AlertEvent) -> String {
+    let what = match event.status {
+        AlertStatus::Open => "Alert opened",
+        AlertStatus::Resolved => "Alert resolved",
+    };
+    format!(
+        "{} on tank {}: {}\n\nValue: {}\nLimit: {}\nTime: {}\n",
+        what, event.tank, event.alert, event.val, event.limit, event.time
+    )
+}
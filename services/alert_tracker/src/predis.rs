@@ -0,0 +1,166 @@
+use redis::Commands;
+use redis_context::RedisContext;
+use redis_delta::{Envelope, Key, Namespace, REvent};
+use std::collections::HashMap;
+
+use crate::model::{AlertEvent, Measure};
+
+/// The number of tanks in the system, e.g. `prawnspace/tanks`
+pub fn fetch_num_tanks(redis_ctx: &RedisContext) -> Result<u16, redis::RedisError> {
+    let found: Option<u16> = redis_ctx.conn.get(
+        Key::AllTanks {
+            ns: Namespace(redis_ctx.namespace.to_owned()),
+        }
+        .to_string(),
+    )?;
+    Ok(found.unwrap_or(0))
+}
+
+pub fn tank_key(namespace: &str, id: u16) -> Key {
+    Key::Tank {
+        ns: Namespace(namespace.to_owned()),
+        id,
+    }
+}
+
+/// Finds the tank ID in a key like `prawnspace/tanks/1`.
+/// Anything else, including tank history keys, is ignored.
+pub fn parse_tank_key(namespace: &str, key: &str) -> Option<u16> {
+    match key.parse::<Key>() {
+        Ok(Key::Tank { ref ns, id }) if ns.0 == namespace => Some(id),
+        _ => None,
+    }
+}
+
+/// Default rules, applied to every tank, live at `<namespace>/alerts/rules`.
+/// Each tank can override them with its own hash at
+/// `<namespace>/alerts/rules/tanks/<id>`.
+pub fn fetch_rules(
+    redis_ctx: &RedisContext,
+    id: u16,
+) -> Result<HashMap<String, String>, redis::RedisError> {
+    let ns = Namespace(redis_ctx.namespace.to_owned());
+    let mut rules: HashMap<String, String> = redis_ctx
+        .conn
+        .hgetall(Key::AlertRules { ns: ns.clone() }.to_string())?;
+    let tank_rules: HashMap<String, String> = redis_ctx
+        .conn
+        .hgetall(Key::TankAlertRules { ns, id }.to_string())?;
+    rules.extend(tank_rules);
+    Ok(rules)
+}
+
+pub fn fetch_tank(
+    redis_ctx: &RedisContext,
+    id: u16,
+) -> Result<HashMap<String, String>, redis::RedisError> {
+    redis_ctx
+        .conn
+        .hgetall(tank_key(&redis_ctx.namespace, id).to_string())
+}
+
+/// Open/resolve state for every alert on a tank is kept in a hash
+/// at `<namespace>/alerts/tanks/<id>`.
+pub fn alert_state_key(namespace: &str, id: u16) -> String {
+    Key::AlertState {
+        ns: Namespace(namespace.to_owned()),
+        id,
+    }
+    .to_string()
+}
+
+pub fn fetch_alert_state(
+    redis_ctx: &RedisContext,
+    id: u16,
+) -> Result<HashMap<String, String>, redis::RedisError> {
+    redis_ctx
+        .conn
+        .hgetall(alert_state_key(&redis_ctx.namespace, id))
+}
+
+/// Looks up the most recent reading which was recorded at or before
+/// `time`, using the time series which sensor_tracker keeps for each tank.
+pub fn fetch_reading_before(
+    redis_ctx: &RedisContext,
+    id: u16,
+    measure: Measure,
+    time: u64,
+) -> Result<Option<(u64, f64)>, redis::RedisError> {
+    let key = Key::History {
+        container: Box::new(tank_key(&redis_ctx.namespace, id)),
+        field: measure.field().to_string(),
+    }
+    .to_string();
+    let found: Vec<(String, u64)> = redis_ctx
+        .conn
+        .zrevrangebyscore_limit_withscores(key, time, "-inf", 0, 1)?;
+
+    Ok(found.get(0).and_then(|(member, score)| {
+        member
+            .splitn(2, ':')
+            .nth(1)
+            .and_then(|v| v.parse::<f64>().ok())
+            .map(|v| (*score, v))
+    }))
+}
+
+/// Writes the new state of each alert to the tank's alert hash,
+/// announces each event on the alert topic, and lets the
+/// redis_aggregator know that the alert hash has changed.
+pub fn record_events(
+    redis_ctx: &RedisContext,
+    id: u16,
+    events: &[AlertEvent],
+    alert_event_topic: &str,
+    delta_event_topic: &str,
+) -> Result<(), redis::RedisError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let key = alert_state_key(&redis_ctx.namespace, id);
+    let mut data: Vec<(String, String)> = vec![];
+    for e in events {
+        data.push((e.alert.to_string(), e.status.to_string()));
+        data.push((
+            format!("{}_{}_time", e.alert, e.status.to_string()),
+            e.time.to_string(),
+        ));
+        data.push((format!("{}_val", e.alert), e.val.to_string()));
+        data.push((format!("{}_limit", e.alert), e.limit.to_string()));
+    }
+
+    let _: () = redis_ctx.conn.hset_multiple(&key, &data[..])?;
+
+    for e in events {
+        if let Ok(s) = serde_json::to_string(e) {
+            let _: u64 = redis_ctx.conn.publish(alert_event_topic, s)?;
+        }
+    }
+
+    let delta_event = REvent::HashUpdated {
+        key,
+        fields: data.into_iter().map(|(f, _)| f).collect(),
+    };
+    if let Ok(s) = serde_json::to_string(&Envelope::wrap(&delta_event)) {
+        let _: u64 = redis_ctx.conn.publish(delta_event_topic, s)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tank_keys() {
+        assert_eq!(parse_tank_key("prawnspace", "prawnspace/tanks/2"), Some(2));
+        assert_eq!(parse_tank_key("other", "prawnspace/tanks/2"), None);
+        assert_eq!(
+            parse_tank_key("prawnspace", "prawnspace/tanks/2/history/ph"),
+            None
+        );
+        assert_eq!(parse_tank_key("prawnspace", "prawnspace/tanks"), None);
+    }
+}
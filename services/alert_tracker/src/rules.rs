@@ -0,0 +1,99 @@
+/// What should happen to an alert after we've
+/// compared the most recent reading against its rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Open,
+    Resolve,
+    Unchanged,
+}
+
+/// Opens as soon as `val` rises above `max`, but only resolves
+/// once `val` has fallen to `max - hysteresis` or below.  This keeps
+/// a reading which hovers around the limit from flapping.
+pub fn check_high(max: f64, hysteresis: f64, open: bool, val: f64) -> Transition {
+    if !open && val > max {
+        Transition::Open
+    } else if open && val <= max - hysteresis {
+        Transition::Resolve
+    } else {
+        Transition::Unchanged
+    }
+}
+
+/// Opens as soon as `val` drops below `min`, but only resolves
+/// once `val` has risen to `min + hysteresis` or above.
+pub fn check_low(min: f64, hysteresis: f64, open: bool, val: f64) -> Transition {
+    if !open && val < min {
+        Transition::Open
+    } else if open && val >= min + hysteresis {
+        Transition::Resolve
+    } else {
+        Transition::Unchanged
+    }
+}
+
+/// Opens when we haven't heard from the sensor in more than
+/// `stale_secs`, and resolves as soon as a fresh reading arrives.
+pub fn check_stale(stale_secs: u64, open: bool, update_time: u64, now: u64) -> Transition {
+    let stale = now.saturating_sub(update_time) > stale_secs;
+    if !open && stale {
+        Transition::Open
+    } else if open && !stale {
+        Transition::Resolve
+    } else {
+        Transition::Unchanged
+    }
+}
+
+/// Rate of change between two `(epoch_secs, value)` readings,
+/// expressed in units per hour.
+pub fn rate_per_hour(earlier: (u64, f64), later: (u64, f64)) -> Option<f64> {
+    let (t0, v0) = earlier;
+    let (t1, v1) = later;
+    if t1 <= t0 {
+        None
+    } else {
+        Some((v1 - v0) / (t1 - t0) as f64 * 3600.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_opens_above_max() {
+        assert_eq!(check_high(8.0, 0.2, false, 8.1), Transition::Open);
+        assert_eq!(check_high(8.0, 0.2, false, 8.0), Transition::Unchanged);
+    }
+
+    #[test]
+    fn high_hovering_does_not_flap() {
+        // already open, and the reading dips just under the limit
+        assert_eq!(check_high(8.0, 0.2, true, 7.9), Transition::Unchanged);
+        assert_eq!(check_high(8.0, 0.2, true, 8.1), Transition::Unchanged);
+        assert_eq!(check_high(8.0, 0.2, true, 7.8), Transition::Resolve);
+    }
+
+    #[test]
+    fn low_hovering_does_not_flap() {
+        assert_eq!(check_low(6.5, 0.2, false, 6.4), Transition::Open);
+        assert_eq!(check_low(6.5, 0.2, true, 6.6), Transition::Unchanged);
+        assert_eq!(check_low(6.5, 0.2, true, 6.7), Transition::Resolve);
+    }
+
+    #[test]
+    fn stale_sensor() {
+        assert_eq!(check_stale(60, false, 1000, 1061), Transition::Open);
+        assert_eq!(check_stale(60, true, 1000, 1061), Transition::Unchanged);
+        assert_eq!(check_stale(60, true, 1050, 1061), Transition::Resolve);
+        assert_eq!(check_stale(60, false, 1050, 1061), Transition::Unchanged);
+    }
+
+    #[test]
+    fn rate_in_units_per_hour() {
+        assert_eq!(rate_per_hour((0, 80.0), (1800, 81.0)), Some(2.0));
+        assert_eq!(rate_per_hour((0, 7.0), (3600, 6.5)), Some(-0.5));
+        assert_eq!(rate_per_hour((10, 7.0), (10, 6.5)), None);
+    }
+}
@@ -0,0 +1,68 @@
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::client::net::ClientTlsParameters;
+use lettre::{ClientSecurity, SmtpClient, SmtpTransport, Transport};
+use lettre_email::Email;
+use native_tls::TlsConnector;
+
+use crate::model::AlertEvent;
+use crate::notify::{self, Notifier, NotifyError};
+
+/// Everything needed to send mail through an SMTP relay.
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Require STARTTLS before sending credentials or mail
+    pub tls: bool,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Sends an email to each recipient when an alert opens or resolves.
+pub struct SmtpNotifier {
+    transport: SmtpTransport,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpNotifier {
+    pub fn new(settings: SmtpSettings) -> Result<SmtpNotifier, NotifyError> {
+        let security = if settings.tls {
+            let connector = TlsConnector::builder()
+                .build()
+                .map_err(|e| NotifyError::TlsErr(format!("{:?}", e)))?;
+            ClientSecurity::Required(ClientTlsParameters::new(settings.host.clone(), connector))
+        } else {
+            ClientSecurity::None
+        };
+
+        let mut client = SmtpClient::new((settings.host.as_str(), settings.port), security)?;
+        if let (Some(username), Some(password)) = (settings.username, settings.password) {
+            client = client.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpNotifier {
+            transport: client.transport(),
+            from: settings.from,
+            to: settings.to,
+        })
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn notify(&mut self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let mut builder = Email::builder()
+            .from(self.from.as_str())
+            .subject(notify::summary(event))
+            .text(notify::details(event));
+        for to in &self.to {
+            builder = builder.to(to.as_str());
+        }
+        let email = builder.build()?;
+
+        self.transport.send(email.into())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use hyper::header::ContentType;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+
+use crate::model::AlertEvent;
+use crate::notify::{self, Notifier, NotifyError};
+
+/// POSTs each alert event as JSON to an arbitrary URL.
+/// The `text` field makes the payload acceptable to
+/// chat services which expect incoming webhooks to look
+/// like `{ "text": "..." }`.
+pub struct WebhookNotifier {
+    url: String,
+    client: hyper::Client,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    text: String,
+    event: &'a AlertEvent,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Result<WebhookNotifier, NotifyError> {
+        let tls = NativeTlsClient::new().map_err(|e| NotifyError::TlsErr(format!("{:?}", e)))?;
+        Ok(WebhookNotifier {
+            url: url.to_string(),
+            client: hyper::Client::with_connector(HttpsConnector::new(tls)),
+        })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&mut self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let body = serde_json::to_string(&WebhookPayload {
+            text: notify::summary(event),
+            event,
+        })?;
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header(ContentType::json())
+            .body(body.as_str())
+            .send()?;
+
+        if response.status.is_success() {
+            Ok(())
+        } else {
+            Err(NotifyError::HttpStatus(response.status.to_u16()))
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! These tests run each notifier against a local stand-in:
+//! a tiny HTTP server, a tiny SMTP sink, and (for the ignored
+//! MQTT test) a mosquitto broker listening on localhost:1883.
+use alert_tracker::model::{AlertEvent, AlertStatus};
+use alert_tracker::mqtt::MqttNotifier;
+use alert_tracker::notify::{Notifier, NotifyError};
+use alert_tracker::smtp::{SmtpNotifier, SmtpSettings};
+use alert_tracker::webhook::WebhookNotifier;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn ph_low() -> AlertEvent {
+    AlertEvent {
+        tank: 1,
+        alert: "ph_low".to_string(),
+        status: AlertStatus::Open,
+        val: 6.43,
+        limit: 6.5,
+        time: 1541083020,
+    }
+}
+
+/// Accepts a single HTTP request, answers with `status_line`,
+/// and hands back the raw request text.
+fn http_stand_in(status_line: &'static str) -> (u16, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = String::new();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.to_lowercase().starts_with("content-length:") {
+                content_length = line[15..].trim().parse::<usize>().unwrap();
+            }
+            request.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status_line
+        )
+        .unwrap();
+        tx.send(request).unwrap();
+    });
+
+    (port, rx)
+}
+
+/// Speaks just enough SMTP to accept a single message,
+/// and hands back everything sent after `DATA`.
+fn smtp_stand_in() -> (u16, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+        let mut data = String::new();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    stream.write_all(b"250 OK\r\n").unwrap();
+                } else {
+                    data.push_str(&line);
+                }
+                continue;
+            }
+
+            let command = line.to_uppercase();
+            if command.starts_with("EHLO") || command.starts_with("HELO") {
+                stream.write_all(b"250 localhost\r\n").unwrap();
+            } else if command.starts_with("DATA") {
+                in_data = true;
+                stream.write_all(b"354 go ahead\r\n").unwrap();
+            } else if command.starts_with("QUIT") {
+                stream.write_all(b"221 bye\r\n").unwrap();
+                break;
+            } else {
+                stream.write_all(b"250 OK\r\n").unwrap();
+            }
+        }
+        tx.send(data).unwrap();
+    });
+
+    (port, rx)
+}
+
+#[test]
+fn webhook_posts_json() {
+    let (port, rx) = http_stand_in("200 OK");
+    let mut notifier = WebhookNotifier::new(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+
+    notifier.notify(&ph_low()).unwrap();
+
+    let request = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("POST /hook HTTP/1.1"));
+    assert!(request.contains("\"text\":\"Tank 1: ph_low open (6.43, limit 6.5)\""));
+    assert!(request.contains("\"alert\":\"ph_low\""));
+    assert!(request.contains("\"status\":\"open\""));
+}
+
+#[test]
+fn webhook_error_status() {
+    let (port, _rx) = http_stand_in("500 Internal Server Error");
+    let mut notifier = WebhookNotifier::new(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+
+    match notifier.notify(&ph_low()) {
+        Err(NotifyError::HttpStatus(500)) => (),
+        other => panic!("expected a 500, got {:?}", other),
+    }
+}
+
+#[test]
+fn smtp_sends_mail() {
+    let (port, rx) = smtp_stand_in();
+    let mut notifier = SmtpNotifier::new(SmtpSettings {
+        host: "127.0.0.1".to_string(),
+        port,
+        username: None,
+        password: None,
+        tls: false,
+        from: "prawns@example.com".to_string(),
+        to: vec!["keeper@example.com".to_string()],
+    })
+    .unwrap();
+
+    notifier.notify(&ph_low()).unwrap();
+    drop(notifier);
+
+    let data = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(data.contains("Subject: Tank 1: ph_low open (6.43, limit 6.5)"));
+    assert!(data.contains("keeper@example.com"));
+    assert!(data.contains("Alert opened on tank 1: ph_low"));
+}
+
+/// Needs a broker: `mosquitto -p 1883`
+#[test]
+#[ignore]
+fn mqtt_publishes_json() {
+    use rumqtt::{MqttClient, MqttOptions, Notification, QoS};
+
+    let topic = "alert_tracker/test/alerts";
+    let (mut sub, notifications) = MqttClient::start(MqttOptions::new(
+        "alert_tracker_test_sub",
+        "127.0.0.1",
+        1883,
+    ))
+    .unwrap();
+    sub.subscribe(topic, QoS::AtLeastOnce).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let mut notifier = MqttNotifier::new("127.0.0.1", 1883, topic, 1).unwrap();
+    notifier.notify(&ph_low()).unwrap();
+
+    loop {
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::Publish(p)) => {
+                let event: AlertEvent = serde_json::from_slice(&p.payload).unwrap();
+                assert_eq!(event, ph_low());
+                break;
+            }
+            Ok(_) => continue,
+            Err(e) => panic!("no message from broker: {:?}", e),
+        }
+    }
+}
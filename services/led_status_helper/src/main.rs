@@ -4,11 +4,13 @@ extern crate serde_derive;
 extern crate dotenv;
 extern crate envy;
 extern crate redis;
+extern crate redis_delta;
 
 use std::slice::SliceConcatExt;
 use std::time;
 
 use redis::Commands;
+use redis_delta::{AreaRecord, HashRecord, Key, Namespace, TankRecord};
 use rumqtt::{MqttClient, MqttOptions, QoS};
 
 use uuid::Uuid;
@@ -37,7 +39,7 @@ fn get_num_containers(
     namespace: &str,
     container: Container,
 ) -> Result<i64, redis::RedisError> {
-    conn.get(format!("{}/{}", namespace, container.to_string()))
+    conn.get(container.all_key(namespace).to_string())
 }
 
 enum Container {
@@ -46,14 +48,31 @@ enum Container {
 }
 
 impl Container {
-    pub fn to_string(self) -> String {
+    pub fn all_key(self, namespace: &str) -> Key {
+        let ns = Namespace(namespace.to_string());
         match self {
-            Container::Tanks => "tanks".to_string(),
-            Container::Areas => "areas".to_string(),
+            Container::Tanks => Key::AllTanks { ns },
+            Container::Areas => Key::AllAreas { ns },
         }
     }
 }
 
+fn area_key(namespace: &str, area: i64) -> String {
+    Key::Area {
+        ns: Namespace(namespace.to_string()),
+        id: area as u16,
+    }
+    .to_string()
+}
+
+fn tank_key(namespace: &str, tank: i64) -> String {
+    Key::Tank {
+        ns: Namespace(namespace.to_string()),
+        id: tank as u16,
+    }
+    .to_string()
+}
+
 struct Temp {
     f: f64,
     c: f64,
@@ -75,6 +94,34 @@ struct PH {
     update_time: Option<u64>,
 }
 
+/// Dissolved oxygen, in mg/L
+struct DO {
+    mg_l: f64,
+    update_time: Option<u64>,
+}
+
+/// Electrical conductivity, in µS/cm
+struct EC {
+    us_cm: f64,
+    update_time: Option<u64>,
+}
+
+struct TankData {
+    temp: Option<Temp>,
+    ph: Option<PH>,
+    oxygen: Option<DO>,
+    conductivity: Option<EC>,
+}
+
+impl TankData {
+    fn is_empty(&self) -> bool {
+        self.temp.is_none()
+            && self.ph.is_none()
+            && self.oxygen.is_none()
+            && self.conductivity.is_none()
+    }
+}
+
 struct Staleness {
     warning: String,
     deadline_seconds: u32,
@@ -113,44 +160,20 @@ fn get_area_data(
     area: i64,
     namespace: &str,
 ) -> Result<Option<DHT>, redis::RedisError> {
-    let numbers: Vec<Option<f64>> = conn.hget(
-        format!("{}/areas/{}", namespace, area),
-        vec![
-            "humidity",
-            "temp_f",
-            "temp_c",
-            "heat_index_f",
-            "heat_index_c",
-        ],
-    )?;
-
-    // A redis string
-    let update_time_vec: Option<String> = conn.hget(
-        format!("{}/areas/{}", namespace, area),
-        vec!["dht_update_time"],
-    )?;
-
-    let (humidity, init_temp_f, init_temp_c, heat_index_f, heat_index_c) = (
-        numbers.get(0),
-        numbers.get(1),
-        numbers.get(2),
-        numbers.get(3),
-        numbers.get(4),
-    );
-
-    let update_time = update_time_vec.map(|s| s.parse::<u64>().unwrap_or(0));
-
-    let temp = safe_temp(init_temp_f, init_temp_c, update_time);
+    let area: AreaRecord =
+        AreaRecord::from_values(conn.hget(area_key(namespace, area), AreaRecord::FIELDS)?);
+
+    let temp = safe_temp(area.temp_f, area.temp_c, area.dht_update_time);
 
     let (temp_f, temp_c) = temp.map(|t| (t.f, t.c)).unwrap_or((NAN, NAN));
 
     Ok(Some(DHT {
-        humidity: unnest_ref(humidity).unwrap_or(NAN),
+        humidity: area.humidity.unwrap_or(NAN),
         temp_f,
         temp_c,
-        heat_index_f: unnest_ref(heat_index_f).unwrap_or(NAN),
-        heat_index_c: unnest_ref(heat_index_c).unwrap_or(NAN),
-        update_time,
+        heat_index_f: area.heat_index_f.unwrap_or(NAN),
+        heat_index_c: area.heat_index_c.unwrap_or(NAN),
+        update_time: area.dht_update_time,
     }))
 }
 
@@ -158,46 +181,48 @@ fn get_tank_data(
     conn: &redis::Connection,
     tank: i64,
     namespace: &str,
-) -> Result<(Option<Temp>, Option<PH>), redis::RedisError> {
-    let numbers: Vec<Option<f64>> = conn.hget(
-        format!("{}/tanks/{}", namespace, tank),
-        vec!["temp_f", "temp_c", "ph"],
-    )?;
-    let update_times: Vec<Option<u64>> = conn.hget(
-        format!("{}/tanks/{}", namespace, tank),
-        vec!["temp_update_time", "ph_update_time"],
-    )?;
-    let (temp_f, temp_c) = (numbers.get(0), numbers.get(1));
-    let (temp_update_time, ph_update_time) = (
-        unnest_ref(update_times.get(0)),
-        unnest_ref(update_times.get(1)),
-    );
-    let temp = safe_temp(temp_f, temp_c, temp_update_time);
-    let ph = unnest_ref(numbers.get(2)).map(|val| PH {
+) -> Result<TankData, redis::RedisError> {
+    let tank: TankRecord =
+        TankRecord::from_values(conn.hget(tank_key(namespace, tank), TankRecord::FIELDS)?);
+    let temp = safe_temp(tank.temp_f, tank.temp_c, tank.temp_update_time);
+    let ph = tank.ph.map(|val| PH {
         val,
-        update_time: ph_update_time,
+        update_time: tank.ph_update_time,
+    });
+    let oxygen = tank.do_mg_l.map(|mg_l| DO {
+        mg_l,
+        update_time: tank.do_update_time,
+    });
+    let conductivity = tank.ec_us_cm.map(|us_cm| EC {
+        us_cm,
+        update_time: tank.ec_update_time,
     });
 
-    Ok((temp, ph))
+    Ok(TankData {
+        temp,
+        ph,
+        oxygen,
+        conductivity,
+    })
 }
 
 fn safe_temp(
-    temp_f: Option<&Option<f64>>,
-    temp_c: Option<&Option<f64>>,
+    temp_f: Option<f64>,
+    temp_c: Option<f64>,
     temp_update_time: Option<u64>,
 ) -> Option<Temp> {
     match (temp_f, temp_c) {
-        (Some(&Some(f)), Some(&Some(c))) => Some(Temp {
+        (Some(f), Some(c)) => Some(Temp {
             f,
             c,
             update_time: temp_update_time,
         }),
-        (_, Some(&Some(c))) => Some(Temp {
+        (_, Some(c)) => Some(Temp {
             f: c_to_f(c),
             c,
             update_time: temp_update_time,
         }),
-        (Some(&Some(f)), _) => Some(Temp {
+        (Some(f), _) => Some(Temp {
             f,
             c: f_to_c(f),
             update_time: temp_update_time,
@@ -206,17 +231,6 @@ fn safe_temp(
     }
 }
 
-fn unnest_ref<A>(a: Option<&Option<A>>) -> Option<A>
-where
-    A: Copy,
-{
-    match a {
-        Some(&Some(thing)) => Some(thing),
-        Some(&None) => None,
-        None => None,
-    }
-}
-
 fn generate_status(
     conn: &redis::Connection,
     temp_unit: &char,
@@ -227,13 +241,14 @@ fn generate_status(
 
     let tank_statuses: Result<Vec<String>, redis::RedisError> = (1..num_tanks + 1)
         .map(move |tank| {
-            get_tank_data(&conn, tank, namespace).map(move |(maybe_temp, maybe_ph)| {
-                if let (&None, &None) = (&maybe_temp, &maybe_ph) {
+            get_tank_data(&conn, tank, namespace).map(move |data| {
+                if data.is_empty() {
                     return "".to_string(); // nothing to format
                 }
 
                 let tank_string = format!("T{}:", tank);
-                let temp_string = maybe_temp
+                let temp_string = data
+                    .temp
                     .map(move |t| {
                         (
                             match temp_unit {
@@ -252,11 +267,20 @@ fn generate_status(
                         )
                     })
                     .unwrap_or("".to_string());
-                let ph_string: String = maybe_ph
+                let ph_string: String = data
+                    .ph
                     .map(move |ph| format!(" pH {}{}", ph.val, staleness.text(ph.update_time)))
                     .unwrap_or("".to_string());
+                let do_string: String = data
+                    .oxygen
+                    .map(move |o| format!(" DO {}{}", o.mg_l, staleness.text(o.update_time)))
+                    .unwrap_or("".to_string());
+                let ec_string: String = data
+                    .conductivity
+                    .map(move |ec| format!(" EC {}{}", ec.us_cm, staleness.text(ec.update_time)))
+                    .unwrap_or("".to_string());
 
-                tank_string + &ph_string + &temp_string
+                tank_string + &ph_string + &temp_string + &do_string + &ec_string
             })
         })
         .collect();
@@ -0,0 +1,49 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+
+use crate::ApiToken;
+
+/// Guards the routes which change a calibration, or which show
+/// its history and status.  The caller needs to present the
+/// `api_token` from Rocket.toml:
+/// ```
+/// curl -H "Authorization: Bearer $API_TOKEN" ...
+/// ```
+/// If no `api_token` is configured, nobody is allowed in.
+#[derive(Debug)]
+pub struct Authorized;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Authorized {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Authorized, ()> {
+        let auth_headers: Vec<_> = request.headers().get("Authorization").collect();
+        if auth_headers.len() != 1 {
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+
+        let expected = match &request.guard::<State<ApiToken>>()?.inner().0 {
+            Some(token) => token.to_string(),
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let mut parts = auth_headers[0].splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("Bearer"), Some(token)) if constant_time_eq(token, &expected) => {
+                Outcome::Success(Authorized)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compares every byte, so that the time taken doesn't
+/// reveal how much of the token was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
@@ -5,14 +5,17 @@ extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
 
+#[macro_use]
 extern crate serde_derive;
 
+mod auth;
 mod external_id;
 mod model;
 mod predis;
 mod routes;
 mod web_error;
 
+use redis_delta::DueSettings;
 use rocket_contrib::databases::redis;
 
 use routes::*;
@@ -24,6 +27,19 @@ pub struct RedisConn(redis::Connection);
 /// "namespace". [Read more here](https://rocket.rs/v0.4/guide/configuration/)
 pub struct Namespace(String);
 
+/// The electrode response, in millivolts per pH, that counts as
+/// 100% slope efficiency.  Defaults to the Nernst slope at 25°C,
+/// but an amplified probe board will want something larger.
+pub struct IdealSlope(f32);
+
+/// Calibration routes are only available to callers who present
+/// this token.  If it isn't configured, they're disabled.
+pub struct ApiToken(Option<String>);
+
+/// Where we announce changes to sensor hashes, so that
+/// redis_aggregator can replicate them.
+pub struct DeltaEventTopic(String);
+
 fn main() {
     rocket::ignite()
         .attach(rocket::fairing::AdHoc::on_attach(
@@ -37,13 +53,76 @@ fn main() {
                 Ok(rocket.manage(Namespace(namespace)))
             },
         ))
+        .attach(rocket::fairing::AdHoc::on_attach(
+            "Ideal Slope Config",
+            |rocket| {
+                let ideal = rocket
+                    .config()
+                    .get_float("ideal_mv_per_ph")
+                    .map(|f| f as f32)
+                    .unwrap_or(model::NERNST_MV_PER_PH);
+                Ok(rocket.manage(IdealSlope(ideal)))
+            },
+        ))
+        .attach(rocket::fairing::AdHoc::on_attach(
+            "Calibration Write Config",
+            |rocket| {
+                let api_token = rocket
+                    .config()
+                    .get_str("api_token")
+                    .ok()
+                    .map(|s| s.to_string());
+                let namespace = rocket
+                    .config()
+                    .get_str("namespace")
+                    .unwrap_or("shrimpfiesta")
+                    .to_string();
+                let topic = rocket
+                    .config()
+                    .get_str("delta_event_topic")
+                    .map(|s| s.to_string())
+                    .unwrap_or(format!("{}/system/delta_events", namespace));
+                Ok(rocket
+                    .manage(ApiToken(api_token))
+                    .manage(DeltaEventTopic(topic)))
+            },
+        ))
+        .attach(rocket::fairing::AdHoc::on_attach(
+            "Calibration Due Config",
+            |rocket| {
+                let max_age_days = rocket
+                    .config()
+                    .get_int("calibration_max_age_days")
+                    .map(|i| i as u32)
+                    .unwrap_or(redis_delta::DEFAULT_MAX_AGE_DAYS);
+                let min_slope_efficiency = rocket
+                    .config()
+                    .get_float("calibration_min_slope_efficiency")
+                    .map(|f| f as f32)
+                    .unwrap_or(redis_delta::DEFAULT_MIN_SLOPE_EFFICIENCY);
+                Ok(rocket.manage(DueSettings {
+                    max_age_days,
+                    min_slope_efficiency,
+                }))
+            },
+        ))
         .attach(RedisConn::fairing())
         .mount(
             "/",
             routes![
                 resolve_external_id,
                 lookup_ph_calibration_by_ext_id,
-                lookup_ph_calibration
+                lookup_ph_calibration,
+                lookup_ph_calibration_json,
+                add_ph_calibration_point,
+                add_ph_calibration_point_by_ext_id,
+                replace_ph_calibration,
+                replace_ph_calibration_by_ext_id,
+                ph_calibration_history,
+                ph_calibration_history_by_ext_id,
+                ph_calibration_status,
+                ph_calibration_status_by_ext_id,
+                ph_calibration_statuses
             ],
         )
         .launch();
@@ -9,28 +9,239 @@ pub struct ExtId {
     pub device_type: String,
 }
 
-/// Represents a pH calibration with low and high
-/// reference values.  Each reference value maps
-/// to a millivolt reading from an SEN0169 pH meter.
-/// These are used to construct the linear scale
-/// which will provide the pH reading at the microcontroller.
-#[derive(Debug)]
+/// Ideal electrode response at 25°C, in millivolts per pH unit.
+pub const NERNST_MV_PER_PH: f32 = 59.16;
+
+/// Represents a pH calibration made from any number of
+/// reference buffers (e.g. pH 4, 7, and 10).  Each reference
+/// value maps to a millivolt reading from an SEN0169 pH meter,
+/// and a line is fitted through all of them.
+#[derive(Debug, Serialize)]
 pub struct PhCalibration {
-    pub low: PhRefValue,
-    pub hi: PhRefValue,
+    pub points: Vec<PhRefValue>,
+    pub fit: PhFit,
 }
 
 impl PhCalibration {
+    pub fn new(
+        mut points: Vec<PhRefValue>,
+        ideal_mv_per_ph: f32,
+    ) -> Result<PhCalibration, CalibrationError> {
+        points.sort_by(|a, b| {
+            a.ph_ref
+                .partial_cmp(&b.ph_ref)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let fit = PhFit::new(&points, ideal_mv_per_ph)?;
+        Ok(PhCalibration { points, fit })
+    }
+
+    /// The microcontroller only knows how to build a linear scale
+    /// from a low and a high reference value.  We give it the two
+    /// points on the fitted line at the lowest and highest
+    /// reference pH, which describe exactly the same line.
     pub fn as_csv(&self) -> String {
+        let low = self.points[0].ph_ref;
+        let hi = self.points[self.points.len() - 1].ph_ref;
         format!(
             "low_ph_ref,low_mv,hi_ph_ref,hi_mv\n{:.*},{:.*},{:.*},{:.*}\n",
-            2, self.low.ph_ref, 2, self.low.mv, 2, self.hi.ph_ref, 2, self.hi.mv,
+            2,
+            low,
+            2,
+            self.fit.mv_at(low),
+            2,
+            hi,
+            2,
+            self.fit.mv_at(hi),
         )
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct PhRefValue {
     pub ph_ref: f32, // pH reference level
     pub mv: f32,     // millivolt reading
 }
+
+/// A least-squares line through the calibration points,
+/// such that `ph = slope * mv + offset`.
+///
+/// - `r_squared` says how well the points sit on the line:
+///   1.0 is perfect, and anything much below 0.99 suggests
+///   a bad buffer or a dirty probe
+/// - `slope_efficiency` compares the observed millivolts per pH
+///   against the ideal response, as a percentage.  A healthy
+///   probe reads somewhere around 95-105%.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhFit {
+    pub slope: f32,
+    pub offset: f32,
+    pub r_squared: f32,
+    pub slope_efficiency: f32,
+}
+
+impl PhFit {
+    pub fn new(points: &[PhRefValue], ideal_mv_per_ph: f32) -> Result<PhFit, CalibrationError> {
+        if points.len() < 2 {
+            return Err(CalibrationError::TooFewPoints(points.len()));
+        }
+
+        let n = points.len() as f64;
+        let mean_mv = points.iter().map(|p| p.mv as f64).sum::<f64>() / n;
+        let mean_ph = points.iter().map(|p| p.ph_ref as f64).sum::<f64>() / n;
+
+        let mut s_xx = 0.0;
+        let mut s_xy = 0.0;
+        let mut s_yy = 0.0;
+        for p in points {
+            let dx = p.mv as f64 - mean_mv;
+            let dy = p.ph_ref as f64 - mean_ph;
+            s_xx += dx * dx;
+            s_xy += dx * dy;
+            s_yy += dy * dy;
+        }
+
+        if s_xx == 0.0 || s_yy == 0.0 {
+            return Err(CalibrationError::Degenerate);
+        }
+
+        let slope = s_xy / s_xx;
+        let offset = mean_ph - slope * mean_mv;
+        let r_squared = (s_xy * s_xy) / (s_xx * s_yy);
+        let slope_efficiency = (1.0 / slope).abs() / ideal_mv_per_ph as f64 * 100.0;
+
+        Ok(PhFit {
+            slope: slope as f32,
+            offset: offset as f32,
+            r_squared: r_squared as f32,
+            slope_efficiency: slope_efficiency as f32,
+        })
+    }
+
+    pub fn ph_at(&self, mv: f32) -> f32 {
+        self.slope * mv + self.offset
+    }
+
+    pub fn mv_at(&self, ph: f32) -> f32 {
+        (ph - self.offset) / self.slope
+    }
+}
+
+/// A complete replacement for a sensor's calibration.
+#[derive(Debug, Deserialize)]
+pub struct NewCalibration {
+    pub points: Vec<PhRefValue>,
+}
+
+/// Written to a sensor's calibration history every time
+/// its calibration changes.  `fit` is missing until the
+/// calibration has at least two usable points.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CalibrationRecord {
+    pub time: u64,
+    pub points: Vec<PhRefValue>,
+    pub fit: Option<PhFit>,
+    pub drift: Option<CalibrationDrift>,
+}
+
+/// How much the fitted line moved since the previous calibration.
+///
+/// - `slope_change`: percent change in slope
+/// - `offset_change`: change in offset, in pH units
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationDrift {
+    pub slope_change: f32,
+    pub offset_change: f32,
+}
+
+impl CalibrationDrift {
+    pub fn between(previous: &PhFit, current: &PhFit) -> CalibrationDrift {
+        CalibrationDrift {
+            slope_change: (current.slope - previous.slope) / previous.slope * 100.0,
+            offset_change: current.offset - previous.offset,
+        }
+    }
+}
+
+/// Reasons that we can't fit a line through a calibration.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalibrationError {
+    /// A line needs at least two points
+    TooFewPoints(usize),
+    /// All of the points share the same pH, or the same millivolts
+    Degenerate,
+    /// A stored point couldn't be read as numbers
+    Unreadable(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(ph_ref: f32, mv: f32) -> PhRefValue {
+        PhRefValue { ph_ref, mv }
+    }
+
+    #[test]
+    fn three_point_fit() {
+        // an ideal probe, reading 0mV at pH 7
+        let points = vec![point(4.0, 177.48), point(7.0, 0.0), point(10.0, -177.48)];
+        let fit = PhFit::new(&points, NERNST_MV_PER_PH).unwrap();
+        assert!((fit.slope - (-1.0 / 59.16)).abs() < 1e-6);
+        assert!((fit.offset - 7.0).abs() < 1e-4);
+        assert!((fit.r_squared - 1.0).abs() < 1e-6);
+        assert!((fit.slope_efficiency - 100.0).abs() < 1e-3);
+        assert!((fit.ph_at(59.16) - 6.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn imperfect_fit() {
+        let points = vec![point(4.0, 170.0), point(7.0, 3.0), point(10.0, -160.0)];
+        let fit = PhFit::new(&points, NERNST_MV_PER_PH).unwrap();
+        assert!(fit.r_squared < 1.0 && fit.r_squared > 0.99);
+        assert!(fit.slope_efficiency < 100.0);
+    }
+
+    #[test]
+    fn not_enough_points() {
+        assert_eq!(
+            PhFit::new(&[point(7.0, 0.0)], NERNST_MV_PER_PH),
+            Err(CalibrationError::TooFewPoints(1))
+        );
+        assert_eq!(
+            PhFit::new(&[point(7.0, 0.0), point(7.0, 2.0)], NERNST_MV_PER_PH),
+            Err(CalibrationError::Degenerate)
+        );
+    }
+
+    #[test]
+    fn drift_between_calibrations() {
+        let previous = PhFit {
+            slope: -0.0169,
+            offset: 7.0,
+            r_squared: 1.0,
+            slope_efficiency: 100.0,
+        };
+        let current = PhFit {
+            slope: -0.0178,
+            offset: 7.1,
+            ..previous
+        };
+        let drift = CalibrationDrift::between(&previous, &current);
+        assert!((drift.slope_change - 5.325).abs() < 1e-2);
+        assert!((drift.offset_change - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn csv_describes_fitted_line() {
+        let calibration = PhCalibration::new(
+            vec![point(10.0, -177.48), point(4.0, 177.48), point(7.0, 0.0)],
+            NERNST_MV_PER_PH,
+        )
+        .unwrap();
+        assert_eq!(
+            calibration.as_csv(),
+            "low_ph_ref,low_mv,hi_ph_ref,hi_mv\n4.00,177.48,10.00,-177.48\n"
+        );
+    }
+}
@@ -1,37 +1,378 @@
-use redis::Commands;
+use redis::{Commands, PipelineCommands};
+use redis_delta::{CalibrationStatus, DueSettings, Envelope, Key, Namespace, REvent, SensorType};
+use std::collections::HashMap;
+use std::time::SystemTime;
 use uuid::Uuid;
 
 use crate::model::*;
+use crate::web_error::WebError;
 use crate::RedisConn;
 
+/// The fields used by the original two-point calibration,
+/// stored directly on the sensor hash.
+const LEGACY_FIELDS: [&str; 4] = ["low_ph_ref", "low_mv", "hi_ph_ref", "hi_mv"];
+
+/// N-point calibrations are kept in their own hash,
+/// mapping each reference pH to the millivolts observed, e.g.
+/// ```text
+/// HSET <namespace>/sensors/ph/<uuid>/calibration 4.00 171.3 7.00 1.2 10.00 -168.9
+/// ```
+pub fn calibration_key(namespace: &str, id: Uuid) -> String {
+    Key::PhCalibration {
+        ns: Namespace(namespace.to_owned()),
+        id,
+    }
+    .to_string()
+}
+
+/// Every change to a sensor's calibration is pushed onto this list,
+/// newest first, as a JSON `CalibrationRecord`.
+pub fn calibration_history_key(namespace: &str, id: Uuid) -> String {
+    Key::PhCalibrationHistory {
+        ns: Namespace(namespace.to_owned()),
+        id,
+    }
+    .to_string()
+}
+
+/// Only this many calibration records are kept for each sensor.
+const MAX_HISTORY: isize = 100;
+
+/// Written alongside a fit, when there's an earlier fit to compare it to.
+const DRIFT_FIELDS: [&str; 2] = ["calibration_slope_change", "calibration_offset_change"];
+
 pub fn lookup_ph_calibration(
     redis_conn: &RedisConn,
     namespace: &str,
     id: Uuid,
-) -> Result<PhCalibration, redis::RedisError> {
-    let r: Vec<Option<f32>> = redis_conn.0.hget(
-        format!("{}/sensors/ph/{}", namespace, id),
-        vec!["low_ph_ref", "low_mv", "hi_ph_ref", "hi_mv"],
-    )?;
-    Ok(PhCalibration {
-        low: PhRefValue {
-            ph_ref: r[0].unwrap_or(0.0),
-            mv: r[1].unwrap_or(0.0),
-        },
-        hi: PhRefValue {
-            ph_ref: r[2].unwrap_or(0.0),
-            mv: r[3].unwrap_or(0.0),
-        },
+    ideal_mv_per_ph: f32,
+) -> Result<PhCalibration, WebError> {
+    let points = lookup_calibration_points(redis_conn, namespace, id)?;
+    Ok(PhCalibration::new(points, ideal_mv_per_ph)?)
+}
+
+/// Prefers the N-point calibration hash, but falls back to the
+/// `low_*` and `hi_*` fields on the sensor hash if there isn't one.
+pub fn lookup_calibration_points(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+) -> Result<Vec<PhRefValue>, WebError> {
+    let stored: HashMap<String, String> = redis_conn.0.hgetall(calibration_key(namespace, id))?;
+    if !stored.is_empty() {
+        let mut points = vec![];
+        for (ph_ref, mv) in stored {
+            match (ph_ref.parse::<f32>(), mv.parse::<f32>()) {
+                (Ok(ph_ref), Ok(mv)) => points.push(PhRefValue { ph_ref, mv }),
+                _ => return Err(CalibrationError::Unreadable(ph_ref).into()),
+            }
+        }
+        return Ok(points);
+    }
+
+    let r = lookup_legacy_fields(redis_conn, namespace, id)?;
+    legacy_points(&r).ok_or_else(|| WebError::MissingCalibration {
+        id,
+        missing: LEGACY_FIELDS
+            .iter()
+            .zip(r.iter())
+            .filter(|(_, v)| v.is_none())
+            .map(|(f, _)| f.to_string())
+            .collect(),
     })
 }
 
+fn lookup_legacy_fields(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+) -> Result<Vec<Option<f32>>, redis::RedisError> {
+    redis_conn
+        .0
+        .hget(sensor_key(namespace, id), LEGACY_FIELDS.to_vec())
+}
+
+/// The two points of a legacy calibration, if all of its fields are present.
+fn legacy_points(fields: &[Option<f32>]) -> Option<Vec<PhRefValue>> {
+    match fields {
+        [Some(low_ph_ref), Some(low_mv), Some(hi_ph_ref), Some(hi_mv)] => Some(vec![
+            PhRefValue {
+                ph_ref: *low_ph_ref,
+                mv: *low_mv,
+            },
+            PhRefValue {
+                ph_ref: *hi_ph_ref,
+                mv: *hi_mv,
+            },
+        ]),
+        _ => None,
+    }
+}
+
+/// Adds a single reference point to the sensor's calibration.
+/// Recording the same reference pH twice replaces the earlier reading.
+///
+/// A sensor which was calibrated the legacy way has no calibration
+/// hash yet.  Its two points are copied into the hash along with the
+/// first new one, since the hash takes precedence over them from then on.
+pub fn add_calibration_point(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+    point: PhRefValue,
+    ideal_mv_per_ph: f32,
+    delta_event_topic: &str,
+) -> Result<CalibrationRecord, WebError> {
+    let key = calibration_key(namespace, id);
+    let has_hash: bool = redis_conn.0.exists(&key)?;
+    let existing = if has_hash {
+        vec![]
+    } else {
+        legacy_points(&lookup_legacy_fields(redis_conn, namespace, id)?).unwrap_or(vec![])
+    };
+
+    let _: () = redis_conn
+        .0
+        .hset_multiple(&key, &calibration_fields(&existing, point)[..])?;
+
+    save_fit(
+        redis_conn,
+        namespace,
+        id,
+        ideal_mv_per_ph,
+        delta_event_topic,
+    )
+}
+
+/// Throws away every existing point and replaces them.
+/// The new points must be enough to fit a line.
+pub fn replace_calibration(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+    points: Vec<PhRefValue>,
+    ideal_mv_per_ph: f32,
+    delta_event_topic: &str,
+) -> Result<CalibrationRecord, WebError> {
+    PhFit::new(&points, ideal_mv_per_ph)?;
+
+    let key = calibration_key(namespace, id);
+    let fields: Vec<(String, f32)> = points
+        .iter()
+        .map(|p| (format!("{:.2}", p.ph_ref), p.mv))
+        .collect();
+    let _: () = redis::pipe()
+        .atomic()
+        .del(&key)
+        .ignore()
+        .hset_multiple(&key, &fields[..])
+        .ignore()
+        .query(&redis_conn.0)?;
+
+    save_fit(
+        redis_conn,
+        namespace,
+        id,
+        ideal_mv_per_ph,
+        delta_event_topic,
+    )
+}
+
+/// The fields of the calibration hash which hold `existing`
+/// and `point`.  `point` replaces an existing point with
+/// the same reference pH.
+fn calibration_fields(existing: &[PhRefValue], point: PhRefValue) -> Vec<(String, f32)> {
+    let point_ref = format!("{:.2}", point.ph_ref);
+    let mut fields: Vec<(String, f32)> = existing
+        .iter()
+        .map(|p| (format!("{:.2}", p.ph_ref), p.mv))
+        .filter(|(ph_ref, _)| *ph_ref != point_ref)
+        .collect();
+    fields.push((point_ref, point.mv));
+    fields
+}
+
+/// Newest first
+pub fn lookup_calibration_history(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+) -> Result<Vec<CalibrationRecord>, WebError> {
+    let found: Vec<String> = redis_conn
+        .0
+        .lrange(calibration_history_key(namespace, id), 0, -1)?;
+    Ok(found
+        .iter()
+        .filter_map(|s| serde_json::from_str(s).ok())
+        .collect())
+}
+
+/// Fits a line through the stored points, then writes the
+/// result to the sensor hash, where other services can find it:
+/// `calibration_slope`, `calibration_offset`, `calibration_r_squared`,
+/// `calibration_slope_efficiency` and `calibration_time`.
+/// The redis_aggregator is told about the change.
+fn save_fit(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+    ideal_mv_per_ph: f32,
+    delta_event_topic: &str,
+) -> Result<CalibrationRecord, WebError> {
+    let mut points = lookup_calibration_points(redis_conn, namespace, id)?;
+    points.sort_by(|a, b| {
+        a.ph_ref
+            .partial_cmp(&b.ph_ref)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let fit = PhFit::new(&points, ideal_mv_per_ph).ok();
+    let previous_fit = lookup_calibration_history(redis_conn, namespace, id)?
+        .into_iter()
+        .filter_map(|r| r.fit)
+        .next();
+    let drift = match (&previous_fit, &fit) {
+        (Some(previous), Some(current)) => Some(CalibrationDrift::between(previous, current)),
+        _ => None,
+    };
+    let record = CalibrationRecord {
+        time: epoch_secs(),
+        fit,
+        points,
+        drift,
+    };
+
+    let sensor_key = sensor_key(namespace, id);
+    let history_key = calibration_history_key(namespace, id);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .lpush(&history_key, serde_json::to_string(&record)?)
+        .ignore()
+        .ltrim(&history_key, 0, MAX_HISTORY - 1)
+        .ignore();
+
+    let mut updated_fields: Vec<String> = vec![];
+    let mut removed_fields: Vec<String> = vec![];
+    if let Some(fit) = record.fit {
+        let mut fields = vec![
+            ("calibration_slope", fit.slope.to_string()),
+            ("calibration_offset", fit.offset.to_string()),
+            ("calibration_r_squared", fit.r_squared.to_string()),
+            (
+                "calibration_slope_efficiency",
+                fit.slope_efficiency.to_string(),
+            ),
+            ("calibration_time", record.time.to_string()),
+        ];
+        if let Some(drift) = record.drift {
+            fields.push(("calibration_slope_change", drift.slope_change.to_string()));
+            fields.push(("calibration_offset_change", drift.offset_change.to_string()));
+        } else {
+            // don't leave behind the drift from an older fit
+            removed_fields = DRIFT_FIELDS.iter().map(|f| f.to_string()).collect();
+            pipe.hdel(&sensor_key, DRIFT_FIELDS.to_vec()).ignore();
+        }
+        pipe.hset_multiple(&sensor_key, &fields[..]).ignore();
+        updated_fields = fields.iter().map(|(f, _)| f.to_string()).collect();
+    }
+
+    let _: () = pipe.query(&redis_conn.0)?;
+
+    if !updated_fields.is_empty() {
+        let delta_event = REvent::HashUpdated {
+            key: sensor_key.clone(),
+            fields: updated_fields,
+        };
+        let _: u64 = redis_conn.0.publish(
+            delta_event_topic,
+            serde_json::to_string(&Envelope::wrap(&delta_event))?,
+        )?;
+    }
+    if !removed_fields.is_empty() {
+        let delta_event = REvent::HashFieldsRemoved {
+            key: sensor_key,
+            fields: removed_fields,
+        };
+        let _: u64 = redis_conn.0.publish(
+            delta_event_topic,
+            serde_json::to_string(&Envelope::wrap(&delta_event))?,
+        )?;
+    }
+
+    Ok(record)
+}
+
+fn sensor_key(namespace: &str, id: Uuid) -> String {
+    Key::Sensor {
+        ns: Namespace(namespace.to_owned()),
+        st: ph(),
+        id,
+    }
+    .to_string()
+}
+
+fn ph() -> SensorType {
+    SensorType("ph".to_owned())
+}
+
+/// Reports whether a sensor is due for calibration, based on
+/// the calibration fields written to its sensor hash.
+pub fn lookup_calibration_status(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    id: Uuid,
+    settings: &DueSettings,
+) -> Result<CalibrationStatus, WebError> {
+    let fields: HashMap<String, String> = redis_conn.0.hgetall(sensor_key(namespace, id))?;
+    Ok(CalibrationStatus::new(
+        &id.to_string(),
+        &fields,
+        settings,
+        epoch_secs(),
+    ))
+}
+
+/// Calibration status for every pH sensor in `<namespace>/sensors/ph`
+pub fn lookup_all_calibration_statuses(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    settings: &DueSettings,
+) -> Result<Vec<CalibrationStatus>, WebError> {
+    let ids: Vec<String> = redis_conn.0.smembers(
+        Key::AllSensors {
+            ns: Namespace(namespace.to_owned()),
+            st: ph(),
+        }
+        .to_string(),
+    )?;
+    let mut statuses = vec![];
+    for id in ids {
+        let fields: HashMap<String, String> = match Uuid::parse_str(&id) {
+            Ok(uuid) => redis_conn.0.hgetall(sensor_key(namespace, uuid))?,
+            Err(_) => continue,
+        };
+        statuses.push(CalibrationStatus::new(&id, &fields, settings, epoch_secs()));
+    }
+    Ok(statuses)
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 /// This is the "name" field that will be used to form a V5 UUID
 pub fn get_external_device_namespace(
     redis_conn: &RedisConn,
     namespace: &str,
     device_type: &str,
 ) -> Result<Uuid, redis::RedisError> {
-    let key = format!("{}/external_device_namespace", namespace);
+    let key = Key::ExternalDeviceNamespace {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string();
     let r: Option<String> = redis_conn.0.hget(&key, device_type)?;
 
     match r {
@@ -45,3 +386,52 @@ pub fn get_external_device_namespace(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(ph_ref: f32, mv: f32) -> PhRefValue {
+        PhRefValue { ph_ref, mv }
+    }
+
+    #[test]
+    fn read_legacy_points() {
+        assert_eq!(
+            legacy_points(&[Some(4.0), Some(171.3), Some(7.0), Some(1.2)]),
+            Some(vec![point(4.0, 171.3), point(7.0, 1.2)])
+        );
+        assert_eq!(legacy_points(&[Some(4.0), Some(171.3), None, None]), None);
+    }
+
+    #[test]
+    fn first_point_keeps_legacy_points() {
+        let legacy = legacy_points(&[Some(4.0), Some(171.3), Some(7.0), Some(1.2)]).unwrap();
+        let fields = calibration_fields(&legacy, point(10.0, -168.9));
+        assert_eq!(
+            fields,
+            vec![
+                ("4.00".to_string(), 171.3),
+                ("7.00".to_string(), 1.2),
+                ("10.00".to_string(), -168.9),
+            ]
+        );
+
+        // and there are now enough points to fit a line
+        let points: Vec<PhRefValue> = fields
+            .iter()
+            .map(|(ph_ref, mv)| point(ph_ref.parse().unwrap(), *mv))
+            .collect();
+        assert!(PhFit::new(&points, NERNST_MV_PER_PH).is_ok());
+    }
+
+    #[test]
+    fn new_point_replaces_same_reference() {
+        let legacy = vec![point(4.0, 171.3), point(7.0, 1.2)];
+        let fields = calibration_fields(&legacy, point(7.0, 3.5));
+        assert_eq!(
+            fields,
+            vec![("4.00".to_string(), 171.3), ("7.00".to_string(), 3.5)]
+        );
+    }
+}
@@ -2,14 +2,17 @@ use uuid::Uuid;
 
 use crate::RedisConn;
 
+use crate::auth::Authorized;
 use crate::external_id;
 use crate::model::*;
 use crate::predis;
 use crate::web_error::WebError;
-use crate::Namespace;
+use crate::{DeltaEventTopic, IdealSlope, Namespace};
+use redis_delta::{CalibrationStatus, DueSettings};
 
 use rocket::request::Form;
 use rocket::State;
+use rocket_contrib::json::Json;
 
 /// You need to Accept: text/plain in your get request
 /// e.g.
@@ -38,6 +41,7 @@ pub fn resolve_external_id(
 pub fn lookup_ph_calibration_by_ext_id(
     redis_conn: RedisConn,
     namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
     ext_id: String,
 ) -> Result<String, WebError> {
     let ext_device_namespace =
@@ -45,7 +49,7 @@ pub fn lookup_ph_calibration_by_ext_id(
 
     let id = external_id::resolve(&ext_id, ext_device_namespace)?;
 
-    let calibration = predis::lookup_ph_calibration(&redis_conn, &namespace.0, id)?;
+    let calibration = predis::lookup_ph_calibration(&redis_conn, &namespace.0, id, ideal.0)?;
 
     Ok(calibration.as_csv())
 }
@@ -58,10 +62,252 @@ pub fn lookup_ph_calibration_by_ext_id(
 pub fn lookup_ph_calibration(
     redis_conn: RedisConn,
     namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
     uuid: String,
 ) -> Result<String, WebError> {
     let id = Uuid::parse_str(&uuid)?;
 
-    let calibration = predis::lookup_ph_calibration(&redis_conn, &namespace.0, id)?;
+    let calibration = predis::lookup_ph_calibration(&redis_conn, &namespace.0, id, ideal.0)?;
     Ok(calibration.as_csv())
 }
+
+/// Shows every calibration point, along with the fitted line
+/// and how well the probe is performing.
+/// ```
+/// curl http://localhost:8000/sensors/ph/ffffffff-ffff-aaaa-eeee-bbbbddddaaaa/calibration -H "Accept: application/json"
+/// ```
+#[get("/sensors/ph/<uuid>/calibration", format = "application/json")]
+pub fn lookup_ph_calibration_json(
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
+    uuid: String,
+) -> Result<Json<PhCalibration>, WebError> {
+    let id = Uuid::parse_str(&uuid)?;
+
+    Ok(Json(predis::lookup_ph_calibration(
+        &redis_conn,
+        &namespace.0,
+        id,
+        ideal.0,
+    )?))
+}
+
+/// Records a single calibration point: the reference pH
+/// of a buffer, and the millivolts the probe read in it.
+/// ```
+/// curl -X POST http://localhost:8000/sensors/ph/ffffffff-ffff-aaaa-eeee-bbbbddddaaaa/calibration/points -H "Authorization: Bearer $API_TOKEN" -H "Content-Type: application/json" -d '{"ph_ref": 7.0, "mv": 1.2}'
+/// ```
+#[post(
+    "/sensors/ph/<uuid>/calibration/points",
+    format = "json",
+    data = "<point>"
+)]
+pub fn add_ph_calibration_point(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
+    topic: State<DeltaEventTopic>,
+    uuid: String,
+    point: Json<PhRefValue>,
+) -> Result<Json<CalibrationRecord>, WebError> {
+    let id = Uuid::parse_str(&uuid)?;
+
+    Ok(Json(predis::add_calibration_point(
+        &redis_conn,
+        &namespace.0,
+        id,
+        point.into_inner(),
+        ideal.0,
+        &topic.0,
+    )?))
+}
+
+#[post(
+    "/sensors/ph/calibration/points?<ext_id>",
+    format = "json",
+    data = "<point>"
+)]
+pub fn add_ph_calibration_point_by_ext_id(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
+    topic: State<DeltaEventTopic>,
+    ext_id: String,
+    point: Json<PhRefValue>,
+) -> Result<Json<CalibrationRecord>, WebError> {
+    let id = resolve_ph_ext_id(&redis_conn, &namespace.0, &ext_id)?;
+
+    Ok(Json(predis::add_calibration_point(
+        &redis_conn,
+        &namespace.0,
+        id,
+        point.into_inner(),
+        ideal.0,
+        &topic.0,
+    )?))
+}
+
+/// Replaces every calibration point for the sensor at once.
+/// ```
+/// curl -X PUT http://localhost:8000/sensors/ph/ffffffff-ffff-aaaa-eeee-bbbbddddaaaa/calibration -H "Authorization: Bearer $API_TOKEN" -H "Content-Type: application/json" -d '{"points": [{"ph_ref": 4.0, "mv": 171.3}, {"ph_ref": 7.0, "mv": 1.2}]}'
+/// ```
+#[put(
+    "/sensors/ph/<uuid>/calibration",
+    format = "json",
+    data = "<calibration>"
+)]
+pub fn replace_ph_calibration(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
+    topic: State<DeltaEventTopic>,
+    uuid: String,
+    calibration: Json<NewCalibration>,
+) -> Result<Json<CalibrationRecord>, WebError> {
+    let id = Uuid::parse_str(&uuid)?;
+
+    Ok(Json(predis::replace_calibration(
+        &redis_conn,
+        &namespace.0,
+        id,
+        calibration.into_inner().points,
+        ideal.0,
+        &topic.0,
+    )?))
+}
+
+#[put(
+    "/sensors/ph/calibration?<ext_id>",
+    format = "json",
+    data = "<calibration>"
+)]
+pub fn replace_ph_calibration_by_ext_id(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    ideal: State<IdealSlope>,
+    topic: State<DeltaEventTopic>,
+    ext_id: String,
+    calibration: Json<NewCalibration>,
+) -> Result<Json<CalibrationRecord>, WebError> {
+    let id = resolve_ph_ext_id(&redis_conn, &namespace.0, &ext_id)?;
+
+    Ok(Json(predis::replace_calibration(
+        &redis_conn,
+        &namespace.0,
+        id,
+        calibration.into_inner().points,
+        ideal.0,
+        &topic.0,
+    )?))
+}
+
+/// Every calibration this sensor has had, newest first.
+/// ```
+/// curl http://localhost:8000/sensors/ph/ffffffff-ffff-aaaa-eeee-bbbbddddaaaa/calibration/history -H "Authorization: Bearer $API_TOKEN"
+/// ```
+#[get("/sensors/ph/<uuid>/calibration/history")]
+pub fn ph_calibration_history(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    uuid: String,
+) -> Result<Json<Vec<CalibrationRecord>>, WebError> {
+    let id = Uuid::parse_str(&uuid)?;
+
+    Ok(Json(predis::lookup_calibration_history(
+        &redis_conn,
+        &namespace.0,
+        id,
+    )?))
+}
+
+#[get("/sensors/ph/calibration/history?<ext_id>")]
+pub fn ph_calibration_history_by_ext_id(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    ext_id: String,
+) -> Result<Json<Vec<CalibrationRecord>>, WebError> {
+    let id = resolve_ph_ext_id(&redis_conn, &namespace.0, &ext_id)?;
+
+    Ok(Json(predis::lookup_calibration_history(
+        &redis_conn,
+        &namespace.0,
+        id,
+    )?))
+}
+
+/// Whether a sensor is due for calibration, either because its
+/// calibration is too old, or because the probe's slope efficiency
+/// has fallen too low.  Also reports how far the calibration moved
+/// the last time it was changed.
+/// ```
+/// curl http://localhost:8000/sensors/ph/ffffffff-ffff-aaaa-eeee-bbbbddddaaaa/calibration/status -H "Authorization: Bearer $API_TOKEN"
+/// ```
+#[get("/sensors/ph/<uuid>/calibration/status")]
+pub fn ph_calibration_status(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    settings: State<DueSettings>,
+    uuid: String,
+) -> Result<Json<CalibrationStatus>, WebError> {
+    let id = Uuid::parse_str(&uuid)?;
+
+    Ok(Json(predis::lookup_calibration_status(
+        &redis_conn,
+        &namespace.0,
+        id,
+        &settings,
+    )?))
+}
+
+#[get("/sensors/ph/calibration/status?<ext_id>")]
+pub fn ph_calibration_status_by_ext_id(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    settings: State<DueSettings>,
+    ext_id: String,
+) -> Result<Json<CalibrationStatus>, WebError> {
+    let id = resolve_ph_ext_id(&redis_conn, &namespace.0, &ext_id)?;
+
+    Ok(Json(predis::lookup_calibration_status(
+        &redis_conn,
+        &namespace.0,
+        id,
+        &settings,
+    )?))
+}
+
+/// Calibration status for every pH sensor
+/// ```
+/// curl http://localhost:8000/sensors/ph/calibration/statuses -H "Authorization: Bearer $API_TOKEN"
+/// ```
+#[get("/sensors/ph/calibration/statuses")]
+pub fn ph_calibration_statuses(
+    _auth: Authorized,
+    redis_conn: RedisConn,
+    namespace: State<Namespace>,
+    settings: State<DueSettings>,
+) -> Result<Json<Vec<CalibrationStatus>>, WebError> {
+    Ok(Json(predis::lookup_all_calibration_statuses(
+        &redis_conn,
+        &namespace.0,
+        &settings,
+    )?))
+}
+
+fn resolve_ph_ext_id(
+    redis_conn: &RedisConn,
+    namespace: &str,
+    ext_id: &str,
+) -> Result<Uuid, WebError> {
+    let ext_device_namespace = predis::get_external_device_namespace(redis_conn, namespace, "ph")?;
+    Ok(external_id::resolve(ext_id, ext_device_namespace)?)
+}
@@ -1,8 +1,18 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use uuid::Uuid;
+
+use crate::model::CalibrationError;
+
 /// All possible errors that this web app can throw
 #[derive(Debug)]
 pub enum WebError {
     RedisErr(redis::RedisError),
     ParseErr(uuid::parser::ParseError),
+    JsonErr(serde_json::Error),
+    CalibrationErr(CalibrationError),
+    MissingCalibration { id: Uuid, missing: Vec<String> },
 }
 
 impl From<redis::RedisError> for WebError {
@@ -16,3 +26,57 @@ impl From<uuid::parser::ParseError> for WebError {
         WebError::ParseErr(error)
     }
 }
+
+impl From<serde_json::Error> for WebError {
+    fn from(error: serde_json::Error) -> Self {
+        WebError::JsonErr(error)
+    }
+}
+
+impl From<CalibrationError> for WebError {
+    fn from(error: CalibrationError) -> Self {
+        WebError::CalibrationErr(error)
+    }
+}
+
+/// Tells the caller what went wrong, instead of a bare 500,
+/// so that a device doesn't quietly load a broken calibration.
+impl<'r> Responder<'r> for WebError {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let (status, message) = match &self {
+            WebError::RedisErr(e) => {
+                eprintln!("Redis error: {:?}", e);
+                (Status::InternalServerError, "Redis error".to_string())
+            }
+            WebError::ParseErr(_) => (Status::BadRequest, "Invalid UUID".to_string()),
+            WebError::JsonErr(e) => {
+                eprintln!("JSON error: {:?}", e);
+                (Status::InternalServerError, "JSON error".to_string())
+            }
+            WebError::CalibrationErr(CalibrationError::TooFewPoints(n)) => (
+                Status::UnprocessableEntity,
+                format!("Calibration needs at least two points, found {}", n),
+            ),
+            WebError::CalibrationErr(CalibrationError::Degenerate) => (
+                Status::UnprocessableEntity,
+                "Calibration points need distinct pH and millivolt readings".to_string(),
+            ),
+            WebError::CalibrationErr(CalibrationError::Unreadable(field)) => (
+                Status::UnprocessableEntity,
+                format!("Couldn't read calibration point {}", field),
+            ),
+            WebError::MissingCalibration { id, missing } => (
+                Status::NotFound,
+                format!(
+                    "No pH calibration for sensor {} (missing {})",
+                    id,
+                    missing.join(", ")
+                ),
+            ),
+        };
+
+        Response::build_from(format!("{}\n", message).respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
@@ -15,11 +15,11 @@ fn main() {
     let config = PubSubConfig::new();
 
     let redis_ctx = &config.to_redis_context();
-    let pubsub_ctx = &config.to_pubsub_context();
+    let transport = config.to_transport();
 
     publish_recent(
         redis_ctx,
-        pubsub_ctx,
+        &*transport,
         vec![REvent::StringUpdated {
             key: format!("{}/tanks", redis_ctx.namespace).to_string(),
         }],
@@ -28,7 +28,7 @@ fn main() {
 
     publish_recent(
         redis_ctx,
-        pubsub_ctx,
+        &*transport,
         vec![REvent::HashUpdated {
             key: format!("{}/tanks/1", redis_ctx.namespace).to_string(),
             fields: vec!["name".to_string()],
@@ -0,0 +1,125 @@
+//! Sends many deltas straight to pond's `/push_redis/batch` route,
+//! bypassing Pub/Sub.  Cloning the world produces one delta for
+//! every tank, area and sensor, so this saves a great many
+//! round trips on startup.
+use crate::signing::Signer;
+use crate::transport::{Message, TransportErr};
+use hyper::header::ContentType;
+use redis_delta::{Encoding, RDelta};
+use std::io::Read;
+
+/// Keeps each request comfortably small.
+const BATCH_SIZE: usize = 200;
+
+/// Matches the body accepted by pond's batch route.
+#[derive(Debug, Serialize)]
+struct PushBatch {
+    messages: Vec<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResults {
+    results: Vec<BatchItemStatus>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum BatchItemStatus {
+    Applied,
+    Stale,
+    Duplicate,
+    Unauthorized,
+    Invalid,
+    Skipped,
+}
+
+/// Posts the deltas in chunks.  Messages which pond refuses
+/// are logged, but don't stop the rest from being sent.
+pub fn push_batches(
+    client: &hyper::Client,
+    url: &str,
+    deltas: &[RDelta],
+    signer: &Signer,
+    encoding: Encoding,
+) -> Result<(), TransportErr> {
+    for chunk in deltas.chunks(BATCH_SIZE) {
+        let mut messages = vec![];
+        for delta in chunk {
+            messages.push(Message::signed(delta, signer, encoding)?);
+        }
+        let batch = PushBatch { messages };
+        let body = serde_json::to_string(&batch)?;
+
+        let mut response = client
+            .post(url)
+            .header(ContentType::json())
+            .body(&body)
+            .send()?;
+        if !response.status.is_success() {
+            return Err(TransportErr::Status(response.status.to_u16()));
+        }
+
+        let mut text = String::new();
+        response.read_to_string(&mut text)?;
+        let results: BatchResults = serde_json::from_str(&text)?;
+        let refused = results
+            .results
+            .iter()
+            .filter(|r| **r == BatchItemStatus::Unauthorized || **r == BatchItemStatus::Invalid)
+            .count();
+        if refused > 0 {
+            eprintln!("Pond refused {} of {} deltas", refused, chunk.len());
+        }
+        let skipped = results
+            .results
+            .iter()
+            .filter(|r| **r == BatchItemStatus::Skipped)
+            .count();
+        if skipped > 0 {
+            eprintln!("Pond skipped {} of {} deltas", skipped, chunk.len());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sign;
+
+    #[test]
+    fn test_batch_body() {
+        let delta = RDelta::UpdateString {
+            key: "ns/tanks".to_owned(),
+            val: "2".to_owned(),
+            time: 1_500_000_000,
+        };
+        let batch = PushBatch {
+            messages: vec![
+                Message::signed(&delta, &Signer::hmac(b"sekrit", None), Encoding::Json).unwrap(),
+            ],
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&batch).unwrap()).unwrap();
+        let data = json["messages"][0]["data"].as_str().unwrap();
+        assert_eq!(
+            json["messages"][0]["attributes"]["sig"],
+            sign(data, b"sekrit")
+        );
+    }
+
+    #[test]
+    fn test_results() {
+        let results: BatchResults =
+            serde_json::from_str(r#"{"results":["applied","stale","duplicate"]}"#).unwrap();
+        assert_eq!(
+            results.results,
+            vec![
+                BatchItemStatus::Applied,
+                BatchItemStatus::Stale,
+                BatchItemStatus::Duplicate
+            ]
+        )
+    }
+}
@@ -7,6 +7,8 @@ extern crate redis_context;
 extern crate uuid;
 
 use redis_aggregator::config::PubSubConfig;
+use redis_aggregator::pull::pull_outbox;
+use redis_aggregator::sync::{anti_entropy, resync};
 use redis_aggregator::{clone_the_world, consume_redis_messages, handle_revents};
 
 fn main() {
@@ -14,12 +16,38 @@ fn main() {
 
     let config = PubSubConfig::new();
 
-    println!("Cloning database...");
-
-    clone_the_world(&config).unwrap();
+    let signer = config.to_signer();
+    if let (Some(kid), Some(public_key)) = (signer.key_id(), signer.public_key()) {
+        // pond needs this in SIGNING_PUBLIC_KEYS
+        println!("Signing with Ed25519 key {}:{}", kid, public_key);
+    }
+
+    if config.pond_sync_url.is_some() {
+        println!("Comparing database with pond...");
+
+        // anti_entropy tries again shortly if this fails
+        match resync(&config, &config.to_redis_context()) {
+            Ok(n) => println!("Queued {} keys which differ", n),
+            Err(e) => eprintln!("Resync error: {:?}", e),
+        }
+    } else if config.clone_on_start.unwrap_or(false) {
+        // Pushes everything, but never removes what pond has
+        // and we don't.  Prefer POND_SYNC_URL.
+        println!("Cloning database...");
+
+        clone_the_world(&config).unwrap();
+    } else {
+        println!("POND_SYNC_URL isn't set, so only new changes will be pushed");
+    }
 
     let (tx, rx) = crossbeam_channel::unbounded();
 
+    let pull_config = config.clone();
+    std::thread::spawn(move || pull_outbox(&pull_config));
+
+    let sync_config = config.clone();
+    std::thread::spawn(move || anti_entropy(&sync_config));
+
     std::thread::spawn(move || handle_revents(rx, &config));
 
     consume_redis_messages(&PubSubConfig::new(), tx)
@@ -2,20 +2,38 @@ use hyper::net::HttpsConnector;
 use redis_context::RedisContext;
 
 use crate::pubsub::{PubSubClient, PubSubContext};
+use crate::queue::RetryPolicy;
+use crate::signing::Signer;
+use crate::transport::{HttpTransport, Transport, TransportKind};
 use hyper_native_tls::NativeTlsClient;
+use redis_delta::Encoding;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PubSubConfig {
     pub pubsub_publish_interval_secs: Option<u64>,
     pub pubsub_project_id: Option<String>,
-    pub pubsub_dest_topic_name: String,
+    pub pubsub_dest_topic_name: Option<String>,
     pub pubsub_secret_file: Option<String>,
     pub redis_auth: Option<String>,
     pub redis_host: Option<String>,
     pub redis_port: Option<u16>,
     pub redis_namespace: Option<String>,
     pub redis_source_topic_name: String,
-    pub signing_secret: String,
+    pub signing_secret: Option<String>,
+    pub signing_key_id: Option<String>,
+    pub signing_private_key: Option<String>,
+    pub pond_outbox_url: Option<String>,
+    pub pond_outbox_poll_secs: Option<u64>,
+    pub pond_batch_url: Option<String>,
+    pub pond_push_url: Option<String>,
+    pub pond_sync_url: Option<String>,
+    pub clone_on_start: Option<bool>,
+    pub sync_interval_secs: Option<u64>,
+    pub transport: Option<TransportKind>,
+    pub push_max_attempts: Option<u32>,
+    pub push_backoff_secs: Option<u64>,
+    pub push_max_backoff_secs: Option<u64>,
+    pub push_encoding: Option<Encoding>,
 }
 
 impl PubSubConfig {
@@ -76,14 +94,76 @@ impl PubSubConfig {
             .pubsub_project_id
             .clone()
             .unwrap_or("project".to_string());
-        let topic_name = self.pubsub_dest_topic_name.clone();
+        let topic_name = self
+            .pubsub_dest_topic_name
+            .clone()
+            .expect("PUBSUB_DEST_TOPIC_NAME is required by the pubsub transport");
         let fq_topic = format!("projects/{}/topics/{}", project_id, topic_name);
         let client = self.to_pubsub_client();
-        let signing_secret = self.signing_secret.as_bytes().to_owned();
+        let signer = self.to_signer();
         PubSubContext {
             fq_topic,
             client,
-            signing_secret,
+            signer,
+            encoding: self.push_encoding.unwrap_or_default(),
+        }
+    }
+
+    /// Pub/Sub unless `TRANSPORT=http`.
+    pub fn to_transport(&self) -> Box<dyn Transport> {
+        match self.transport.unwrap_or(TransportKind::PubSub) {
+            TransportKind::PubSub => Box::new(self.to_pubsub_context()),
+            TransportKind::Http => Box::new(self.to_http_transport()),
+        }
+    }
+
+    /// Prefers pond's batch route, if we know where it is.
+    pub fn to_http_transport(&self) -> HttpTransport {
+        let signer = self.to_signer();
+        let encoding = self.push_encoding.unwrap_or_default();
+        match (&self.pond_batch_url, &self.pond_push_url) {
+            (Some(url), _) => HttpTransport::new(url, true, signer, encoding),
+            (None, Some(url)) => HttpTransport::new(url, false, signer, encoding),
+            (None, None) => {
+                panic!("POND_PUSH_URL or POND_BATCH_URL is required by the http transport")
+            }
+        }
+    }
+
+    /// Cloning the world sends everything at once, so
+    /// it uses pond's batch route whenever that's available.
+    pub fn to_clone_transport(&self) -> Box<dyn Transport> {
+        if self.pond_batch_url.is_some() {
+            Box::new(self.to_http_transport())
+        } else {
+            self.to_transport()
+        }
+    }
+
+    /// Signs with `SIGNING_PRIVATE_KEY` (a base64 Ed25519 seed) if
+    /// it's set, and otherwise with `SIGNING_SECRET`.  Either way,
+    /// `SIGNING_KEY_ID` tells pond which key to check against.
+    pub fn to_signer(&self) -> Signer {
+        let key_id = self.signing_key_id.clone();
+        match (&self.signing_private_key, &self.signing_secret) {
+            (Some(seed), _) => match base64::decode(seed) {
+                Ok(ref seed) if seed.len() == 32 => Signer::ed25519(
+                    seed,
+                    key_id.expect("SIGNING_KEY_ID is required with SIGNING_PRIVATE_KEY"),
+                ),
+                _ => panic!("SIGNING_PRIVATE_KEY must be 32 bytes, base64 encoded"),
+            },
+            (None, Some(secret)) => Signer::hmac(secret.as_bytes(), key_id),
+            (None, None) => panic!("SIGNING_SECRET or SIGNING_PRIVATE_KEY is required"),
+        }
+    }
+
+    /// Queued pushes are retried with exponential backoff.
+    pub fn to_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.push_max_attempts.unwrap_or(10),
+            backoff_secs: self.push_backoff_secs.unwrap_or(5),
+            max_backoff_secs: self.push_max_backoff_secs.unwrap_or(600),
         }
     }
 }
@@ -18,25 +18,30 @@ extern crate redis_context;
 extern crate serde_derive;
 extern crate yup_oauth2;
 
+pub mod batch;
 pub mod config;
 pub mod pubsub;
+pub mod pull;
+pub mod queue;
+pub mod signing;
+pub mod sync;
+pub mod transport;
 
 use base64;
 use hashbrown::{HashMap, HashSet};
 use redis::Commands;
 use redis_context::RedisContext;
-use redis_delta::{Key, RDelta, REvent, RField};
+use redis_delta::{Decoded, Encoding, Key, RDelta, REvent, RField, RMember};
 
-use self::pubsub::PubSubContext;
+use self::transport::{Transport, TransportErr};
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha3::Sha3;
-use std::default::Default;
 use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 /// Send *all* relevant redis data upstream
-/// to the cloud instance via pub sub.
+/// to the cloud instance.
 ///
 /// This is expected to be invoked on service startup.
 ///
@@ -49,19 +54,37 @@ use uuid::Uuid;
 /// - Query each individual sensor of each type
 ///
 /// Push as you satisfy each individual step.
+///
+/// If `POND_BATCH_URL` is set, everything is posted directly
+/// to pond in a few large batches, whichever transport is
+/// used afterwards.
+///
+/// Nothing is ever removed from pond, so only use this when
+/// pond can't compare digests (see `sync::resync`).
 pub fn clone_the_world(config: &config::PubSubConfig) -> Result<(), AggErr> {
     let redis_ctx = &config.to_redis_context();
-    let pubsub_ctx = &config.to_pubsub_context();
 
     let all_ids: Vec<REvent> = instantiate_all_ids(redis_ctx)?;
 
-    Ok(publish_recent(redis_ctx, pubsub_ctx, all_ids)?)
+    let transport = config.to_clone_transport();
+    Ok(publish_recent(redis_ctx, &*transport, all_ids)?)
 }
 
 #[derive(Debug)]
 pub enum AggErr {
     Redis(redis::RedisError),
-    PubSub(google_pubsub1::Error),
+    Transport(TransportErr),
+}
+
+impl AggErr {
+    /// See `TransportErr::is_permanent`.  Redis errors are
+    /// local, and may clear up by themselves.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            AggErr::Redis(_) => false,
+            AggErr::Transport(e) => e.is_permanent(),
+        }
+    }
 }
 
 impl From<redis::RedisError> for AggErr {
@@ -69,9 +92,9 @@ impl From<redis::RedisError> for AggErr {
         AggErr::Redis(error)
     }
 }
-impl From<google_pubsub1::Error> for AggErr {
-    fn from(error: google_pubsub1::Error) -> Self {
-        AggErr::PubSub(error)
+impl From<TransportErr> for AggErr {
+    fn from(error: TransportErr) -> Self {
+        AggErr::Transport(error)
     }
 }
 
@@ -80,30 +103,35 @@ fn instantiate_all_ids(redis_ctx: &RedisContext) -> Result<Vec<REvent>, redis::R
 
     let ns = redis_delta::Namespace(redis_ctx.namespace.to_owned());
 
-    let all_tanks_key = Key::AllTanks { ns: ns.clone() }.to_string();
-
-    let maybe_num_tanks: Option<u16> = redis_ctx.conn.get(&all_tanks_key)?;
-
-    if let Some(num_tanks) = maybe_num_tanks {
-        // We know that there's an entry describing the number of
-        // tanks in the system, so we'll return the ID.
-        let all_tanks_event = REvent::StringUpdated { key: all_tanks_key };
-        result.push(all_tanks_event);
-
-        // We should see if there are hash entries for the individual tanks.
-        // Use https://redis.io/commands/hkeys to look up all field names
-        // for each tank that we find.
-        let each_tank_events: Result<Vec<REvent>, redis::RedisError> =
-            tank_hash_events(num_tanks, redis_ctx);
-
-        for e in each_tank_events? {
-            result.push(e);
+    // Tanks and areas are both numbered from 1, and counted
+    // by a string entry.  If there's an entry describing the
+    // number of them, we'll return its ID.  Then we should see
+    // if there are hash entries for the individual tanks or areas.
+    // Use https://redis.io/commands/hkeys to look up all field names
+    // for each one that we find.
+    let containers: [(Key, fn(redis_delta::Namespace, u16) -> Key); 2] = [
+        (Key::AllTanks { ns: ns.clone() }, |ns, id| Key::Tank { ns, id }),
+        (Key::AllAreas { ns: ns.clone() }, |ns, id| Key::Area { ns, id }),
+    ];
+    for (all_key, each_key) in containers.iter() {
+        let all_key = all_key.to_string();
+        let maybe_num: Option<u16> = redis_ctx.conn.get(&all_key)?;
+
+        if let Some(num) = maybe_num {
+            result.push(REvent::StringUpdated { key: all_key });
+
+            for id in 1..=num {
+                let key = each_key(ns.clone(), id).to_string();
+                for hash in hash_event(&key, redis_ctx)? {
+                    result.push(hash)
+                }
+            }
         }
     }
 
     let sensor_types_key = Key::AllSensorTypes { ns: ns.clone() }.to_string();
     let sensor_type_members: Vec<String> = redis_ctx.conn.smembers(&sensor_types_key)?;
-    if sensor_type_members.is_empty() {
+    if !sensor_type_members.is_empty() {
         result.push(REvent::SetUpdated {
             key: sensor_types_key,
         })
@@ -155,25 +183,6 @@ fn sensor_hash_events(
     Ok(r)
 }
 
-fn tank_hash_events(
-    num_tanks: u16,
-    redis_ctx: &RedisContext,
-) -> Result<Vec<REvent>, redis::RedisError> {
-    let mut r: Vec<REvent> = vec![];
-    for id in 1..=num_tanks {
-        let key = Key::Tank {
-            ns: redis_delta::Namespace(redis_ctx.namespace.to_owned()),
-            id,
-        }
-        .to_string();
-        for hash in hash_event(&key, redis_ctx)? {
-            r.push(hash)
-        }
-    }
-
-    Ok(r)
-}
-
 fn hash_event(key: &str, redis_ctx: &RedisContext) -> Result<Option<REvent>, redis::RedisError> {
     let fields: Vec<String> = redis_ctx.conn.hkeys(key)?;
     if fields.is_empty() {
@@ -187,7 +196,7 @@ fn hash_event(key: &str, redis_ctx: &RedisContext) -> Result<Option<REvent>, red
 }
 
 /// Publish a vec of redis changes (hash updates, string updates, etc)
-/// to google pubsub system.
+/// to pond, using whichever transport is configured.
 ///
 /// In order to get this done, we need to first retrieve a recent
 /// copy of each piece of data referred to in the vec of events.
@@ -195,20 +204,29 @@ fn hash_event(key: &str, redis_ctx: &RedisContext) -> Result<Option<REvent>, red
 ///   entire set, or the string itself.
 /// - For hash field updates, we only retrieve the fields
 ///   which have been updated.
+/// - For sorted set updates, we only retrieve the members
+///   whose scores are at least as recent as the update.
 pub fn publish_recent(
     redis_ctx: &RedisContext,
-    pubsub_ctx: &PubSubContext,
+    transport: &dyn Transport,
     redis_events: Vec<REvent>,
-) -> Result<(), google_pubsub1::Error> {
+) -> Result<(), AggErr> {
+    Ok(transport.send(fetch_recent(redis_ctx, redis_events)?)?)
+}
+
+/// If any of the values can't be read, nothing is sent, so
+/// that the events can be tried again rather than lost.
+fn fetch_recent(
+    redis_ctx: &RedisContext,
+    redis_events: Vec<REvent>,
+) -> Result<Vec<RDelta>, redis::RedisError> {
     let mut deltas: Vec<RDelta> = vec![];
     for revent in redis_events {
-        let fetched = fetch(revent, redis_ctx).ok().and_then(|r| r);
-        if let Some(f) = fetched {
+        if let Some(f) = fetch(revent, redis_ctx)? {
             deltas.push(f)
         }
     }
-
-    publish(deltas, pubsub_ctx)
+    Ok(deltas)
 }
 
 fn fetch(event: REvent, ctx: &RedisContext) -> Result<Option<RDelta>, redis::RedisError> {
@@ -218,6 +236,89 @@ fn fetch(event: REvent, ctx: &RedisContext) -> Result<Option<RDelta>, redis::Red
         }
         REvent::StringUpdated { key } => fetch_string_delta(&key, ctx),
         REvent::SetUpdated { key } => fetch_set_delta(&key, ctx),
+        REvent::SortedSetUpdated { key, min_score } => fetch_sorted_set_delta(&key, min_score, ctx),
+        REvent::SetMembersRemoved { key, vals } => fetch_set_removal(key, vals, ctx),
+        REvent::HashFieldsRemoved { key, fields } => fetch_hash_removal(key, fields, ctx),
+        REvent::KeyDeleted { key } => fetch_key_deletion(key, ctx),
+        REvent::ExpireSet { key } => fetch_expiry(key, ctx),
+    }
+}
+
+/// Removals are only passed on while the members are still
+/// missing, in case they were added back before we got here.
+fn fetch_set_removal(
+    key: String,
+    vals: Vec<String>,
+    ctx: &RedisContext,
+) -> Result<Option<RDelta>, redis::RedisError> {
+    let mut removed = vec![];
+    for v in vals {
+        let member: bool = ctx.conn.sismember(&key, &v)?;
+        if !member {
+            removed.push(v)
+        }
+    }
+    if removed.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(RDelta::RemoveSetMembers {
+            key,
+            vals: removed,
+            time: epoch_secs(),
+        }))
+    }
+}
+
+fn fetch_hash_removal(
+    key: String,
+    fields: Vec<String>,
+    ctx: &RedisContext,
+) -> Result<Option<RDelta>, redis::RedisError> {
+    let mut removed = vec![];
+    for f in fields {
+        let exists: bool = ctx.conn.hexists(&key, &f)?;
+        if !exists {
+            removed.push(f)
+        }
+    }
+    if removed.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(RDelta::RemoveHashFields {
+            key,
+            fields: removed,
+            time: epoch_secs(),
+        }))
+    }
+}
+
+fn fetch_key_deletion(
+    key: String,
+    ctx: &RedisContext,
+) -> Result<Option<RDelta>, redis::RedisError> {
+    let exists: bool = ctx.conn.exists(&key)?;
+    if exists {
+        Ok(None)
+    } else {
+        Ok(Some(RDelta::DeleteKey {
+            key,
+            time: epoch_secs(),
+        }))
+    }
+}
+
+/// Sends the TTL which remains, rather than the one which was set.
+fn fetch_expiry(key: String, ctx: &RedisContext) -> Result<Option<RDelta>, redis::RedisError> {
+    // negative when the key is gone, or has no TTL
+    let ttl: i64 = ctx.conn.ttl(&key)?;
+    if ttl < 0 {
+        Ok(None)
+    } else {
+        Ok(Some(RDelta::ExpireKey {
+            key,
+            ttl_secs: ttl as u64,
+            time: epoch_secs(),
+        }))
     }
 }
 
@@ -239,13 +340,35 @@ fn fetch_set_delta(key: &str, ctx: &RedisContext) -> Result<Option<RDelta>, redi
     }))
 }
 
+fn fetch_sorted_set_delta(
+    key: &str,
+    min_score: u64,
+    ctx: &RedisContext,
+) -> Result<Option<RDelta>, redis::RedisError> {
+    let found: Vec<(String, u64)> = ctx.conn.zrangebyscore_withscores(key, min_score, "+inf")?;
+    if found.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(RDelta::UpdateSortedSet {
+            key: key.to_owned(),
+            members: found
+                .into_iter()
+                .map(|(val, score)| RMember { score, val })
+                .collect(),
+            time: epoch_secs(),
+        }))
+    }
+}
+
 fn fetch_hash_delta(
     key: String,
     fields: Vec<String>,
     ctx: &RedisContext,
 ) -> Result<RDelta, redis::RedisError> {
     let fields_forever = fields.clone();
-    let found: Vec<Option<String>> = ctx.conn.hget(&key, fields)?;
+    // HGET, which `hget` sends for a single field,
+    // doesn't answer with a list
+    let found: Vec<Option<String>> = redis::cmd("HMGET").arg(&key).arg(fields).query(&ctx.conn)?;
     let zipped = fields_forever.iter().zip(found);
     let rfields: Vec<RField> = zipped
         .map(|(f, maybe_v)| {
@@ -265,52 +388,6 @@ fn fetch_hash_delta(
     })
 }
 
-/// Publish a message to google cloud pub/sub system.
-/// 
-/// They are signed using HS256 and a shared secret
-/// in order to establish authenticity of the sender.
-/// 
-/// These messages are assumed to be unique since they
-/// are sent every few seconds, and include a timestamp.
-/// 
-/// These messages are consumed by the pond cloud image
-/// "push_redis" route.
-fn publish(data: Vec<RDelta>, pubsub_ctx: &PubSubContext) -> Result<(), google_pubsub1::Error> {
-    // each redis delta will be a separate "message" within a single
-    // google cloud platform "request"
-    let mut messages: Vec<google_pubsub1::PubsubMessage> = vec![];
-
-    for delta in data {
-        let json = serde_json::to_string(&delta).unwrap();
-
-        let message_base64 = base64::encode(json.as_bytes());
-
-        let mut attrs: std::collections::HashMap<String, String> = std::collections::HashMap::new();
-        attrs.insert(
-            "sig".to_owned(),
-            sign(&message_base64, &pubsub_ctx.signing_secret),
-        );
-
-        messages.push(google_pubsub1::PubsubMessage {
-            attributes: Some(attrs),
-            // This must be base64 encoded!
-            data: Some(message_base64),
-            ..Default::default()
-        })
-    }
-
-    let req = google_pubsub1::PublishRequest {
-        messages: Some(messages),
-    };
-
-    pubsub_ctx
-        .client
-        .projects()
-        .topics_publish(req, &pubsub_ctx.fq_topic)
-        .doit()
-        .map(|_r| ())
-}
-
 /// Provides a base64-encoded hmac signature for the base64
 /// message being sent.
 fn sign(message_base64: &str, secret: &[u8]) -> String {
@@ -344,9 +421,12 @@ pub fn consume_redis_messages(config: &config::PubSubConfig, tx: crossbeam::Send
     loop {
         if let Ok(msg) = sub.get_message() {
             let payload = msg.get_payload().unwrap_or("".to_string());
-            let revent: Result<REvent, _> = serde_json::from_str(&payload);
-            if let Ok(e) = revent {
-                tx.send(e).unwrap()
+            match Encoding::Json.decode_envelope::<REvent>(payload.as_bytes()) {
+                Ok(Decoded::Known(e)) => tx.send(e).unwrap(),
+                Ok(Decoded::Unknown { schema, kind }) => {
+                    eprintln!("Skipping {} event with schema {}", kind, schema)
+                }
+                Err(_) => (),
             }
         }
     }
@@ -354,7 +434,8 @@ pub fn consume_redis_messages(config: &config::PubSubConfig, tx: crossbeam::Send
 
 pub fn handle_revents(rx: crossbeam_channel::Receiver<REvent>, config: &config::PubSubConfig) {
     let redis_ctx = config.to_redis_context();
-    let pubsub_ctx = config.to_pubsub_context();
+    let transport = config.to_transport();
+    let retry_policy = config.to_retry_policy();
 
     // For redis hash type, where we also need to track fields
     let mut hash_fields = HashMap::<String, HashSet<String>>::new();
@@ -362,6 +443,10 @@ pub fn handle_revents(rx: crossbeam_channel::Receiver<REvent>, config: &config::
     // For redis string and set types, where we can just store their keys
     let mut kv_events = HashSet::<REvent>::new();
 
+    // For redis sorted set types, where we track the lowest score
+    // which has been written since the last push
+    let mut sorted_set_scores = HashMap::<String, u64>::new();
+
     let mut last_push = SystemTime::now();
     let publish_interval = config.pubsub_publish_interval_secs.unwrap_or(10);
 
@@ -369,7 +454,9 @@ pub fn handle_revents(rx: crossbeam_channel::Receiver<REvent>, config: &config::
         if SystemTime::now().duration_since(last_push).unwrap()
             > Duration::from_secs(publish_interval)
         {
-            let mut events: Vec<REvent> = Vec::with_capacity(&kv_events.len() + &hash_fields.len());
+            let mut events: Vec<REvent> = Vec::with_capacity(
+                &kv_events.len() + &hash_fields.len() + &sorted_set_scores.len(),
+            );
             for ev in kv_events.drain() {
                 events.push(ev);
             }
@@ -380,9 +467,16 @@ pub fn handle_revents(rx: crossbeam_channel::Receiver<REvent>, config: &config::
                 }
                 events.push(REvent::HashUpdated { key, fields });
             }
+            for (key, min_score) in sorted_set_scores.drain() {
+                events.push(REvent::SortedSetUpdated { key, min_score });
+            }
 
-            if let Err(e) = publish_recent(&redis_ctx, &pubsub_ctx, events) {
-                eprintln!("Push error: {:?}", e);
+            // Queued first, so that nothing is lost if pond can't be reached
+            if let Err(e) = queue::enqueue(&redis_ctx, events) {
+                eprintln!("Queue error: {:?}", e);
+            }
+            if let Err(e) = queue::flush(&redis_ctx, &*transport, &retry_policy, epoch_secs()) {
+                eprintln!("Queue error: {:?}", e);
             }
             last_push = SystemTime::now()
         }
@@ -410,8 +504,24 @@ pub fn handle_revents(rx: crossbeam_channel::Receiver<REvent>, config: &config::
                     },
                     setu @ REvent::SetUpdated { key: _ } => { kv_events.insert(setu); },
                     stru @ REvent::StringUpdated { key: _ } => { kv_events.insert(stru); },
+                    // removals are checked against Redis before they're
+                    // pushed, so their order doesn't matter
+                    removal @ REvent::SetMembersRemoved { .. } => { kv_events.insert(removal); },
+                    removal @ REvent::HashFieldsRemoved { .. } => { kv_events.insert(removal); },
+                    removal @ REvent::KeyDeleted { .. } => { kv_events.insert(removal); },
+                    expiry @ REvent::ExpireSet { .. } => { kv_events.insert(expiry); },
+                    REvent::SortedSetUpdated { key, min_score } => {
+                        let lowest = sorted_set_scores
+                            .get(&key)
+                            .map(|s| std::cmp::min(*s, min_score))
+                            .unwrap_or(min_score);
+                        sorted_set_scores.insert(key, lowest);
+                    },
                 }
             }
+            // wake up now and then, so that queued pushes are retried
+            // even when nothing is changing
+            default(Duration::from_secs(publish_interval)) => (),
         }
     }
 }
@@ -424,4 +534,12 @@ mod test {
         let signed = sign("AA==", "sekrit".to_owned().as_bytes());
         assert_eq!(signed, "M7HSodfA0G0vHcvaoAsdoFCZk9hj0Dqo9JFX6C1YXjI=".to_owned())
     }
+
+    #[test]
+    fn test_redis_errors_are_retried() {
+        let down = redis::RedisError::from((redis::ErrorKind::IoError, "down"));
+        assert!(!AggErr::Redis(down).is_permanent());
+        assert!(AggErr::Transport(TransportErr::Status(401)).is_permanent());
+        assert!(!AggErr::Transport(TransportErr::Status(503)).is_permanent());
+    }
 }
\ No newline at end of file
@@ -1,8 +1,13 @@
+use crate::signing::Signer;
+use crate::transport::{Message, Transport, TransportErr};
+use redis_delta::{Encoding, RDelta};
+
 /// Note that fq_topic is a fully qualified topic, i.e. `projects/{project_id}/topics/{topic_name}`
 pub struct PubSubContext {
     pub fq_topic: String,
     pub client: PubSubClient,
-    pub signing_secret: Vec<u8>,
+    pub signer: Signer,
+    pub encoding: Encoding,
 }
 
 /// Note that this pub sub client specifically uses the
@@ -14,3 +19,43 @@ pub struct PubSubContext {
 /// here, for this simple backend application.
 pub type PubSubClient =
     google_pubsub1::Pubsub<hyper::Client, yup_oauth2::ServiceAccountAccess<hyper::Client>>;
+
+/// Publish messages to google cloud pub/sub system.
+///
+/// They are signed using HS256 and a shared secret
+/// in order to establish authenticity of the sender.
+///
+/// These messages are assumed to be unique since they
+/// are sent every few seconds, and include a timestamp.
+///
+/// These messages are consumed by the pond cloud image
+/// "push_redis" route.
+impl Transport for PubSubContext {
+    fn send(&self, deltas: Vec<RDelta>) -> Result<(), TransportErr> {
+        // each redis delta will be a separate "message" within a single
+        // google cloud platform "request"
+        let mut messages: Vec<google_pubsub1::PubsubMessage> = vec![];
+
+        for delta in deltas {
+            let message = Message::signed(&delta, &self.signer, self.encoding)?;
+            messages.push(google_pubsub1::PubsubMessage {
+                attributes: Some(message.attributes),
+                // This must be base64 encoded!
+                data: Some(message.data),
+                ..Default::default()
+            })
+        }
+
+        let req = google_pubsub1::PublishRequest {
+            messages: Some(messages),
+        };
+
+        self.client
+            .projects()
+            .topics_publish(req, &self.fq_topic)
+            .doit()
+            .map(|_r| ())?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+//! Changes made using pond's admin routes (linking sensors
+//! to tanks, renaming tanks, ...) are queued in an outbox
+//! in the cloud.  We poll that outbox, apply each change to
+//! the local Redis, and remember the last one we applied.
+use crate::config::PubSubConfig;
+use crate::epoch_secs;
+use hyper::header::Headers;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use redis::Commands;
+use redis_context::RedisContext;
+use redis_delta::{Envelope, Key, Namespace, RDelta, REvent, Versioned};
+use std::io::Read;
+use std::time::Duration;
+
+const DEFAULT_POLL_SECS: u64 = 30;
+
+/// Matches the body returned by pond's `/outbox` route.
+#[derive(Debug, Deserialize)]
+struct OutboxBatch {
+    entries: Vec<RawOutboxEntry>,
+}
+
+/// An `OutboxEntry` whose delta hasn't been parsed yet,
+/// since a newer pond may send kinds of delta we don't know.
+#[derive(Debug, Deserialize)]
+struct RawOutboxEntry {
+    seq: u64,
+    #[serde(default)]
+    schema: u32,
+    delta: serde_json::Value,
+}
+
+impl RawOutboxEntry {
+    /// `None` if the entry should be skipped.
+    fn delta(self) -> Result<Option<RDelta>, serde_json::Error> {
+        let kind = match self.delta.as_object().and_then(|o| o.keys().next()) {
+            Some(k) => k.to_string(),
+            None => return Ok(Some(serde_json::from_value(self.delta)?)),
+        };
+        if RDelta::is_known(self.schema, &kind) {
+            Ok(Some(serde_json::from_value(self.delta)?))
+        } else {
+            eprintln!("Skipping {} delta with schema {}", kind, self.schema);
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PullErr {
+    Redis(redis::RedisError),
+    Http(hyper::Error),
+    Status(u16),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl From<redis::RedisError> for PullErr {
+    fn from(error: redis::RedisError) -> Self {
+        PullErr::Redis(error)
+    }
+}
+impl From<hyper::Error> for PullErr {
+    fn from(error: hyper::Error) -> Self {
+        PullErr::Http(error)
+    }
+}
+impl From<std::io::Error> for PullErr {
+    fn from(error: std::io::Error) -> Self {
+        PullErr::Io(error)
+    }
+}
+impl From<serde_json::Error> for PullErr {
+    fn from(error: serde_json::Error) -> Self {
+        PullErr::Json(error)
+    }
+}
+
+/// Polls forever.  Does nothing unless `POND_OUTBOX_URL` is set.
+pub fn pull_outbox(config: &PubSubConfig) {
+    let url = match &config.pond_outbox_url {
+        Some(url) => url.to_string(),
+        None => return,
+    };
+    let redis_ctx = config.to_redis_context();
+    let client =
+        hyper::Client::with_connector(HttpsConnector::new(NativeTlsClient::new().unwrap()));
+    let poll = Duration::from_secs(config.pond_outbox_poll_secs.unwrap_or(DEFAULT_POLL_SECS));
+
+    println!("Pulling admin changes from {}", url);
+
+    loop {
+        match pull_once(&client, &url, &redis_ctx, config) {
+            Ok(0) => (),
+            Ok(n) => println!("Applied {} admin changes", n),
+            Err(e) => eprintln!("Outbox error: {:?}", e),
+        }
+        std::thread::sleep(poll)
+    }
+}
+
+fn checkpoint_key(namespace: &str) -> String {
+    Key::OutboxCheckpoint {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
+}
+
+/// Returns the number of changes applied.  The checkpoint is
+/// advanced after each change, so a failure part way through
+/// picks up where it left off.
+fn pull_once(
+    client: &hyper::Client,
+    url: &str,
+    redis_ctx: &RedisContext,
+    config: &PubSubConfig,
+) -> Result<usize, PullErr> {
+    let checkpoint_key = checkpoint_key(&redis_ctx.namespace);
+    let after: u64 = redis_ctx
+        .conn
+        .get(&checkpoint_key)
+        .map(|c: Option<u64>| c.unwrap_or(0))?;
+    let time = epoch_secs();
+
+    let mut headers = Headers::new();
+    config
+        .to_signer()
+        .set_headers(&mut headers, &outbox_message(after, time));
+
+    let mut response = client
+        .get(&format!("{}?after={}&time={}", url, after, time))
+        .headers(headers)
+        .send()?;
+    if !response.status.is_success() {
+        return Err(PullErr::Status(response.status.to_u16()));
+    }
+
+    let mut body = String::new();
+    response.read_to_string(&mut body)?;
+    let batch: OutboxBatch = serde_json::from_str(&body)?;
+
+    let mut applied = 0;
+    for entry in batch.entries {
+        if entry.seq <= after {
+            continue;
+        }
+        let seq = entry.seq;
+        if let Some(delta) = entry.delta()? {
+            apply(&delta, redis_ctx, &config.redis_source_topic_name)?;
+            applied += 1;
+        }
+        let _: () = redis_ctx.conn.set(&checkpoint_key, seq)?;
+    }
+
+    Ok(applied)
+}
+
+/// Must match the message which pond expects to be signed.
+fn outbox_message(after: u64, time: u64) -> String {
+    format!("outbox:{}:{}", after, time)
+}
+
+/// Writes the change to the local Redis, then announces it
+/// the same way our other services do, so that it's pushed
+/// back to the cloud along with everything else.
+fn apply(delta: &RDelta, redis_ctx: &RedisContext, topic: &str) -> Result<(), redis::RedisError> {
+    if let Some(e) = write(delta, redis_ctx)? {
+        let _: () = redis_ctx
+            .conn
+            .publish(topic, serde_json::to_string(&Envelope::wrap(&e)).unwrap())?;
+    }
+
+    Ok(())
+}
+
+/// Returns the event which announces the change.
+fn write(delta: &RDelta, redis_ctx: &RedisContext) -> Result<Option<REvent>, redis::RedisError> {
+    let conn = &redis_ctx.conn;
+    Ok(match delta {
+        RDelta::UpdateHash { key, fields, .. } => {
+            let name_vals: Vec<(&str, &str)> =
+                fields.iter().map(|f| (&f.name[..], &f.val[..])).collect();
+            let _: () = conn.hset_multiple(key, &name_vals)?;
+            Some(REvent::HashUpdated {
+                key: key.to_string(),
+                fields: fields.iter().map(|f| f.name.to_string()).collect(),
+            })
+        }
+        // e.g. unlinking a sensor from its tank
+        RDelta::RemoveHashFields { key, fields, .. } => {
+            let _: () = conn.hdel(key, fields.clone())?;
+            Some(REvent::HashFieldsRemoved {
+                key: key.to_string(),
+                fields: fields.clone(),
+            })
+        }
+        RDelta::RemoveSetMembers { key, vals, .. } => {
+            let _: () = conn.srem(key, vals.clone())?;
+            Some(REvent::SetMembersRemoved {
+                key: key.to_string(),
+                vals: vals.clone(),
+            })
+        }
+        // e.g. retiring a tank
+        RDelta::DeleteKey { key, .. } => {
+            let _: () = conn.del(key)?;
+            Some(REvent::KeyDeleted {
+                key: key.to_string(),
+            })
+        }
+        RDelta::ExpireKey { key, ttl_secs, .. } => {
+            let _: () = conn.expire(key, *ttl_secs as usize)?;
+            Some(REvent::ExpireSet {
+                key: key.to_string(),
+            })
+        }
+        RDelta::UpdateString { key, val, .. } => {
+            let _: () = conn.set(key, val)?;
+            Some(REvent::StringUpdated {
+                key: key.to_string(),
+            })
+        }
+        RDelta::UpdateSet { key, vals, .. } => {
+            let _: () = conn.sadd(key, vals.clone())?;
+            Some(REvent::SetUpdated {
+                key: key.to_string(),
+            })
+        }
+        RDelta::UpdateSortedSet { key, members, .. } => {
+            for m in members {
+                let _: () = conn.zadd(key, &m.val, m.score)?;
+            }
+            members
+                .iter()
+                .map(|m| m.score)
+                .min()
+                .map(|min_score| REvent::SortedSetUpdated {
+                    key: key.to_string(),
+                    min_score,
+                })
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_outbox_message() {
+        // pond verifies a signature over this exact message
+        assert_eq!(outbox_message(10, 1_500_000_000), "outbox:10:1500000000")
+    }
+
+    #[test]
+    fn test_outbox_entries() {
+        let batch: OutboxBatch = serde_json::from_str(
+            r#"{"entries":[
+                {"seq":1,"delta":{"delete_key":{"key":"k","time":0}}},
+                {"seq":2,"schema":1,"delta":{"rename_key":{"from":"k","to":"j"}}},
+                {"seq":3,"schema":2,"delta":{"delete_key":{"key":"k","time":0}}}
+            ]}"#,
+        )
+        .unwrap();
+        let deltas: Vec<Option<RDelta>> = batch
+            .entries
+            .into_iter()
+            .map(|e| e.delta().unwrap())
+            .collect();
+        assert_eq!(
+            deltas,
+            vec![
+                Some(RDelta::DeleteKey {
+                    key: "k".to_string(),
+                    time: 0
+                }),
+                None,
+                None
+            ]
+        )
+    }
+
+    /// The time is when the change was read, and the
+    /// TTL counts down, so it's compared in minutes.
+    fn untimed(delta: RDelta) -> RDelta {
+        match delta {
+            RDelta::RemoveHashFields { key, fields, .. } => RDelta::RemoveHashFields {
+                key,
+                fields,
+                time: 0,
+            },
+            RDelta::RemoveSetMembers { key, vals, .. } => {
+                RDelta::RemoveSetMembers { key, vals, time: 0 }
+            }
+            RDelta::DeleteKey { key, .. } => RDelta::DeleteKey { key, time: 0 },
+            RDelta::ExpireKey { key, ttl_secs, .. } => RDelta::ExpireKey {
+                key,
+                ttl_secs: (ttl_secs + 59) / 60,
+                time: 0,
+            },
+            other => other,
+        }
+    }
+
+    /// Needs redis: `redis-server`
+    #[test]
+    #[ignore]
+    fn test_removals_pushed_back() {
+        let ns = "redis_aggregator_test";
+        let redis_ctx = RedisContext::new("127.0.0.1".to_string(), 6379, None, ns.to_string());
+        let conn = &redis_ctx.conn;
+
+        let sensor = format!("{}/sensors/temp/aaaaaaaa-eeee-aaaa-aaaa-aaaaaaaaaaaa", ns);
+        let sensors = format!("{}/sensors/temp", ns);
+        let tank = format!("{}/tanks/3", ns);
+        let history = format!("{}/tanks/1/history/temp_f", ns);
+        let keys = vec![&sensor, &sensors, &tank, &history];
+        let _: () = conn.del(keys.clone()).unwrap();
+        let _: () = conn
+            .hset_multiple(&sensor, &[("tank", "1"), ("temp_f", "81.2")])
+            .unwrap();
+        let _: () = conn.sadd(&sensors, vec!["a", "b"]).unwrap();
+        let _: () = conn.hset(&tank, "name", "Retired").unwrap();
+        let _: () = conn
+            .zadd(&history, "1500000000:81.2", 1_500_000_000)
+            .unwrap();
+
+        // unlinking a sensor, retiring a tank, ...
+        let deltas = vec![
+            RDelta::RemoveHashFields {
+                key: sensor.clone(),
+                fields: vec!["tank".to_string()],
+                time: 1,
+            },
+            RDelta::RemoveSetMembers {
+                key: sensors.clone(),
+                vals: vec!["b".to_string()],
+                time: 1,
+            },
+            RDelta::DeleteKey {
+                key: tank.clone(),
+                time: 1,
+            },
+            RDelta::ExpireKey {
+                key: history.clone(),
+                ttl_secs: 3600,
+                time: 1,
+            },
+        ];
+        for delta in deltas {
+            // pond sees the same change come back
+            let event = write(&delta, &redis_ctx).unwrap().unwrap();
+            let pushed = crate::fetch(event, &redis_ctx).unwrap().unwrap();
+            assert_eq!(untimed(pushed), untimed(delta));
+        }
+
+        let _: () = conn.del(keys).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! Changes waiting to be pushed are kept in a Redis list, so
+//! that they survive an outage at pond, or a restart of this
+//! service.  We queue the events rather than the values they
+//! refer to: each attempt fetches the latest values, so a push
+//! which succeeds after a long outage isn't already stale.  If
+//! those values can't be read, the attempt fails, just as if
+//! pond couldn't be reached.
+//!
+//! The head of the queue is retried with exponential backoff.
+//! Once it has failed too many times, it's moved to a list of
+//! dead letters, and the rest of the queue carries on.  So is
+//! anything which pond refuses outright, e.g. with a 401.
+use crate::publish_recent;
+use crate::transport::Transport;
+use redis::{Commands, PipelineCommands};
+use redis_context::RedisContext;
+use redis_delta::{Key, Namespace, REvent};
+
+/// Dead letters beyond this many are discarded, oldest first.
+const MAX_DEAD_LETTERS: isize = 1000;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Pending {
+    pub events: Vec<REvent>,
+    pub attempts: u32,
+    /// Epoch seconds.  Don't try again before this.
+    pub retry_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl RetryPolicy {
+    /// How long to wait after the given number of failed attempts.
+    pub fn backoff(&self, attempts: u32) -> u64 {
+        let doublings = attempts.saturating_sub(1).min(32);
+        self.backoff_secs
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff_secs)
+    }
+}
+
+fn queue_key(namespace: &str) -> String {
+    Key::PushQueue {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
+}
+
+fn dead_letters_key(namespace: &str) -> String {
+    Key::PushDeadLetters {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
+}
+
+pub fn enqueue(redis_ctx: &RedisContext, events: Vec<REvent>) -> Result<(), redis::RedisError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let pending = Pending {
+        events,
+        attempts: 0,
+        retry_at: 0,
+    };
+    redis_ctx.conn.rpush(
+        queue_key(&redis_ctx.namespace),
+        serde_json::to_string(&pending).unwrap(),
+    )
+}
+
+/// Pushes queued changes, oldest first, until the queue is
+/// empty or the head of the queue has to wait for a retry.
+/// Returns the number of entries which were pushed.
+pub fn flush(
+    redis_ctx: &RedisContext,
+    transport: &dyn Transport,
+    policy: &RetryPolicy,
+    now: u64,
+) -> Result<usize, redis::RedisError> {
+    let conn = &redis_ctx.conn;
+    let queue_key = queue_key(&redis_ctx.namespace);
+
+    let mut pushed = 0;
+    loop {
+        let head: Option<String> = conn.lindex(&queue_key, 0)?;
+        let head = match head {
+            Some(h) => h,
+            None => return Ok(pushed),
+        };
+
+        let mut pending: Pending = match serde_json::from_str(&head) {
+            Ok(p) => p,
+            Err(_) => {
+                kill(redis_ctx, &head)?;
+                continue;
+            }
+        };
+        if pending.retry_at > now {
+            return Ok(pushed);
+        }
+
+        match publish_recent(redis_ctx, transport, pending.events.clone()) {
+            Ok(()) => {
+                let _: () = conn.lpop(&queue_key)?;
+                pushed += 1;
+            }
+            Err(e) => {
+                pending.attempts += 1;
+                if pending.attempts >= policy.max_attempts || e.is_permanent() {
+                    eprintln!(
+                        "Push error, giving up after {} attempts: {:?}",
+                        pending.attempts, e
+                    );
+                    kill(redis_ctx, &serde_json::to_string(&pending).unwrap())?;
+                    continue;
+                }
+
+                let wait = policy.backoff(pending.attempts);
+                eprintln!("Push error, retrying in {}s: {:?}", wait, e);
+                pending.retry_at = now + wait;
+                let _: () = conn.lset(&queue_key, 0, serde_json::to_string(&pending).unwrap())?;
+                return Ok(pushed);
+            }
+        }
+    }
+}
+
+/// Replaces the head of the queue with a dead letter.
+fn kill(redis_ctx: &RedisContext, dead: &str) -> Result<(), redis::RedisError> {
+    let dead_letters_key = dead_letters_key(&redis_ctx.namespace);
+    redis::pipe()
+        .atomic()
+        .lpop(queue_key(&redis_ctx.namespace))
+        .ignore()
+        .rpush(&dead_letters_key, dead)
+        .ignore()
+        .ltrim(&dead_letters_key, -MAX_DEAD_LETTERS, -1)
+        .ignore()
+        .query(&redis_ctx.conn)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            backoff_secs: 5,
+            max_backoff_secs: 60,
+        };
+        assert_eq!(policy.backoff(1), 5);
+        assert_eq!(policy.backoff(2), 10);
+        assert_eq!(policy.backoff(4), 40);
+        assert_eq!(policy.backoff(5), 60);
+        assert_eq!(policy.backoff(100), 60);
+    }
+
+    #[test]
+    fn test_pending_round_trip() {
+        let pending = Pending {
+            events: vec![REvent::StringUpdated {
+                key: "ns/tanks".to_owned(),
+            }],
+            attempts: 2,
+            retry_at: 1_500_000_000,
+        };
+        let json = serde_json::to_string(&pending).unwrap();
+        assert_eq!(serde_json::from_str::<Pending>(&json).unwrap(), pending)
+    }
+}
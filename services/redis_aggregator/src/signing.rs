@@ -0,0 +1,112 @@
+//! Everything we send to pond is signed, either with an HMAC
+//! secret which pond shares, or with an Ed25519 private key
+//! whose public half pond knows.  A key ID tells pond which
+//! of its keys to check the signature against, so that keys
+//! can be rotated without downtime.
+use crate::sign;
+use crypto::ed25519;
+use hyper::header::Headers;
+use std::collections::HashMap;
+
+#[derive(Clone)]
+enum SigningKey {
+    Hmac(Vec<u8>),
+    /// The 64 byte secret key expanded from a 32 byte seed.
+    Ed25519(Vec<u8>),
+}
+
+#[derive(Clone)]
+pub struct Signer {
+    key: SigningKey,
+    key_id: Option<String>,
+}
+
+impl Signer {
+    /// Pond checks signatures without a key ID against its
+    /// own `SIGNING_SECRET`.
+    pub fn hmac(secret: &[u8], key_id: Option<String>) -> Signer {
+        Signer {
+            key: SigningKey::Hmac(secret.to_vec()),
+            key_id,
+        }
+    }
+
+    /// The seed is 32 random bytes.  Pond must be given the
+    /// public key, under the same key ID.
+    pub fn ed25519(seed: &[u8], key_id: String) -> Signer {
+        let (secret_key, _) = ed25519::keypair(seed);
+        Signer {
+            key: SigningKey::Ed25519(secret_key.to_vec()),
+            key_id: Some(key_id),
+        }
+    }
+
+    /// Base64, for pond's `SIGNING_PUBLIC_KEYS`.
+    pub fn public_key(&self) -> Option<String> {
+        match &self.key {
+            SigningKey::Hmac(_) => None,
+            // the public key makes up the second half of the secret key
+            SigningKey::Ed25519(secret_key) => Some(base64::encode(&secret_key[32..])),
+        }
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_ref().map(|k| &k[..])
+    }
+
+    /// Provides a base64 signature for the message.
+    pub fn sign(&self, message: &str) -> String {
+        match &self.key {
+            SigningKey::Hmac(secret) => sign(message, secret),
+            SigningKey::Ed25519(secret_key) => {
+                base64::encode(&ed25519::signature(message.as_bytes(), secret_key)[..])
+            }
+        }
+    }
+
+    /// The attributes of a message sent to pond's push routes.
+    pub fn attributes(&self, message_base64: &str) -> HashMap<String, String> {
+        let mut attrs = HashMap::new();
+        attrs.insert("sig".to_owned(), self.sign(message_base64));
+        if let Some(kid) = &self.key_id {
+            attrs.insert("kid".to_owned(), kid.to_string());
+        }
+        attrs
+    }
+
+    /// Signs requests made to pond's other routes.
+    pub fn set_headers(&self, headers: &mut Headers, message: &str) {
+        headers.set_raw("X-Pond-Signature", vec![self.sign(message).into_bytes()]);
+        if let Some(kid) = &self.key_id {
+            headers.set_raw("X-Pond-Key-Id", vec![kid.as_bytes().to_vec()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac_matches_legacy() {
+        let signer = Signer::hmac(b"sekrit", None);
+        assert_eq!(
+            signer.sign("AA=="),
+            "M7HSodfA0G0vHcvaoAsdoFCZk9hj0Dqo9JFX6C1YXjI="
+        );
+        assert!(!signer.attributes("AA==").contains_key("kid"))
+    }
+
+    #[test]
+    fn test_ed25519() {
+        let signer = Signer::ed25519(&[7u8; 32], "pi-1".to_string());
+        let (_, public_key) = ed25519::keypair(&[7u8; 32]);
+        assert_eq!(signer.public_key(), Some(base64::encode(&public_key)));
+
+        let sig = base64::decode(&signer.sign("AA==")).unwrap();
+        assert!(ed25519::verify(b"AA==", &public_key, &sig));
+
+        let attrs = signer.attributes("AA==");
+        assert_eq!(attrs.get("kid"), Some(&"pi-1".to_string()))
+    }
+}
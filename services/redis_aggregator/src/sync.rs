@@ -0,0 +1,291 @@
+//! Rather than pushing every tank, area and sensor each time we
+//! start, we send pond a digest of each key we replicate, and pond
+//! tells us which of its copies differ.  Only those are queued for
+//! pushing.  The same comparison is repeated now and then, so that
+//! any drift between the two copies is healed.
+//!
+//! Pond also tells us the fields and members of each hash and set
+//! which differ, and the keys which it has but we didn't send.  We
+//! queue their removal, and whichever are missing here when the
+//! push is made (see `fetch`) are removed from pond.
+use crate::config::PubSubConfig;
+use crate::signing::Signer;
+use crate::{epoch_secs, instantiate_all_ids, queue};
+use hyper::header::{ContentType, Headers};
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use redis::Commands;
+use redis_context::RedisContext;
+use redis_delta::{digest_hash, digest_set, digest_string, Key, KeyDigest, Namespace, REvent};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::time::Duration;
+
+const DEFAULT_INTERVAL_SECS: u64 = 3600;
+
+/// Wait this long before trying again, after a failed sync.
+const RETRY_SECS: u64 = 60;
+
+/// Matches the body accepted by pond's `/sync/digests` route.
+#[derive(Debug, Serialize)]
+struct DigestRequest {
+    digests: Vec<KeyDigest>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigestResponse {
+    differ: HashSet<String>,
+    #[serde(default)]
+    members: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    extra: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum SyncErr {
+    Redis(redis::RedisError),
+    Http(hyper::Error),
+    Status(u16),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl From<redis::RedisError> for SyncErr {
+    fn from(error: redis::RedisError) -> Self {
+        SyncErr::Redis(error)
+    }
+}
+impl From<hyper::Error> for SyncErr {
+    fn from(error: hyper::Error) -> Self {
+        SyncErr::Http(error)
+    }
+}
+impl From<std::io::Error> for SyncErr {
+    fn from(error: std::io::Error) -> Self {
+        SyncErr::Io(error)
+    }
+}
+impl From<serde_json::Error> for SyncErr {
+    fn from(error: serde_json::Error) -> Self {
+        SyncErr::Json(error)
+    }
+}
+
+/// Runs forever, resyncing every `SYNC_INTERVAL_SECS`.  The time
+/// of the last sync is kept in Redis, so restarting the service
+/// doesn't reset the schedule.  Does nothing unless `POND_SYNC_URL`
+/// is set.
+pub fn anti_entropy(config: &PubSubConfig) {
+    if config.pond_sync_url.is_none() {
+        return;
+    }
+    let redis_ctx = config.to_redis_context();
+    let interval = config.sync_interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS);
+
+    loop {
+        let last: u64 = redis_ctx
+            .conn
+            .get(checkpoint_key(&redis_ctx.namespace))
+            .map(|c: Option<u64>| c.unwrap_or(0))
+            .unwrap_or(0);
+        let due = last + interval;
+        let now = epoch_secs();
+        if now < due {
+            std::thread::sleep(Duration::from_secs(due - now));
+            continue;
+        }
+
+        match resync(config, &redis_ctx) {
+            Ok(n) => println!("Resync queued {} keys", n),
+            Err(e) => {
+                eprintln!("Resync error: {:?}", e);
+                std::thread::sleep(Duration::from_secs(RETRY_SECS))
+            }
+        }
+    }
+}
+
+fn checkpoint_key(namespace: &str) -> String {
+    Key::SyncCheckpoint {
+        ns: Namespace(namespace.to_owned()),
+    }
+    .to_string()
+}
+
+/// Queues every replicated key whose digest differs from pond's
+/// copy, along with the removal of anything pond has that we
+/// don't.  Returns the number of keys queued.
+pub fn resync(config: &PubSubConfig, redis_ctx: &RedisContext) -> Result<usize, SyncErr> {
+    let url = match &config.pond_sync_url {
+        Some(url) => url,
+        None => return Ok(0),
+    };
+
+    let events = instantiate_all_ids(redis_ctx)?;
+    let mut digests = vec![];
+    for event in &events {
+        digests.push(digest(event, redis_ctx)?);
+    }
+
+    let mut found = ask_pond(url, digests, &config.to_signer())?;
+    let mut queued: Vec<REvent> = vec![];
+    let mut count = 0;
+    for event in events {
+        if !found.differ.contains(event_key(&event)) {
+            continue;
+        }
+        let removal = removal(&event, &mut found.members);
+        queued.push(event);
+        queued.extend(removal);
+        count += 1;
+    }
+    for key in found.extra {
+        queued.push(REvent::KeyDeleted { key });
+        count += 1;
+    }
+
+    // handle_revents pushes whatever is queued
+    queue::enqueue(redis_ctx, queued)?;
+    let _: () = redis_ctx
+        .conn
+        .set(checkpoint_key(&redis_ctx.namespace), epoch_secs())?;
+
+    Ok(count)
+}
+
+fn ask_pond(
+    url: &str,
+    digests: Vec<KeyDigest>,
+    signer: &Signer,
+) -> Result<HashSet<String>, SyncErr> {
+    let client =
+        hyper::Client::with_connector(HttpsConnector::new(NativeTlsClient::new().unwrap()));
+    let time = epoch_secs();
+
+    let mut headers = Headers::new();
+    headers.set(ContentType::json());
+    signer.set_headers(&mut headers, &sync_message(time));
+
+    let body = serde_json::to_string(&DigestRequest { digests })?;
+    let mut response = client
+        .post(&format!("{}?time={}", url, time))
+        .headers(headers)
+        .body(&body)
+        .send()?;
+    if !response.status.is_success() {
+        return Err(SyncErr::Status(response.status.to_u16()));
+    }
+
+    let mut text = String::new();
+    response.read_to_string(&mut text)?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// Removes all of pond's fields or members, as far as the event
+/// goes.  Those which we still have are left alone when the event
+/// is fetched.
+fn removal(event: &REvent, members: &mut HashMap<String, Vec<String>>) -> Option<REvent> {
+    match event {
+        REvent::HashUpdated { key, .. } => {
+            members.remove(key).map(|fields| REvent::HashFieldsRemoved {
+                key: key.to_string(),
+                fields,
+            })
+        }
+        REvent::SetUpdated { key } => members.remove(key).map(|vals| REvent::SetMembersRemoved {
+            key: key.to_string(),
+            vals,
+        }),
+        _ => None,
+    }
+}
+
+/// Must match the message which pond expects to be signed.
+fn sync_message(time: u64) -> String {
+    format!("sync:{}", time)
+}
+
+fn event_key(event: &REvent) -> &str {
+    match event {
+        REvent::HashUpdated { key, .. } => key,
+        REvent::SetUpdated { key } => key,
+        REvent::StringUpdated { key } => key,
+        REvent::SortedSetUpdated { key, .. } => key,
+        REvent::SetMembersRemoved { key, .. } => key,
+        REvent::HashFieldsRemoved { key, .. } => key,
+        REvent::KeyDeleted { key } => key,
+        REvent::ExpireSet { key } => key,
+    }
+}
+
+/// Digests the whole value, whichever fields the event names.
+fn digest(event: &REvent, redis_ctx: &RedisContext) -> Result<KeyDigest, redis::RedisError> {
+    let conn = &redis_ctx.conn;
+    let key = event_key(event).to_string();
+    let digest = match event {
+        REvent::HashUpdated { .. } => {
+            let fields: HashMap<String, String> = conn.hgetall(&key)?;
+            digest_hash(&fields)
+        }
+        REvent::SetUpdated { .. } => {
+            let members: Vec<String> = conn.smembers(&key)?;
+            digest_set(&members)
+        }
+        REvent::StringUpdated { .. } => {
+            let val: Option<String> = conn.get(&key)?;
+            digest_string(&val.unwrap_or_default())
+        }
+        // Histories aren't compared, and pond never asks for them
+        REvent::SortedSetUpdated { .. } => String::new(),
+        // instantiate_all_ids never asks for these
+        REvent::SetMembersRemoved { .. }
+        | REvent::HashFieldsRemoved { .. }
+        | REvent::KeyDeleted { .. }
+        | REvent::ExpireSet { .. } => String::new(),
+    };
+    Ok(KeyDigest { key, digest })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sync_message() {
+        // pond verifies a signature over this exact message
+        assert_eq!(sync_message(1_500_000_000), "sync:1500000000")
+    }
+
+    #[test]
+    fn test_removal() {
+        let mut members = HashMap::new();
+        members.insert("ns/tanks/1".to_string(), vec!["name".to_string()]);
+        members.insert("ns/sensors".to_string(), vec!["ph".to_string()]);
+
+        let hash = REvent::HashUpdated {
+            key: "ns/tanks/1".to_string(),
+            fields: vec!["temp_f".to_string()],
+        };
+        assert_eq!(
+            removal(&hash, &mut members),
+            Some(REvent::HashFieldsRemoved {
+                key: "ns/tanks/1".to_string(),
+                fields: vec!["name".to_string()],
+            })
+        );
+        let set = REvent::SetUpdated {
+            key: "ns/sensors".to_string(),
+        };
+        assert_eq!(
+            removal(&set, &mut members),
+            Some(REvent::SetMembersRemoved {
+                key: "ns/sensors".to_string(),
+                vals: vec!["ph".to_string()],
+            })
+        );
+        let string = REvent::StringUpdated {
+            key: "ns/tanks".to_string(),
+        };
+        assert_eq!(removal(&string, &mut members), None)
+    }
+}
@@ -0,0 +1,253 @@
+//! Deltas can reach pond either through Google Pub/Sub,
+//! or by posting them to pond directly.  Either way, each
+//! delta is wrapped in a versioned envelope, encoded as JSON or CBOR
+//! (see `redis_delta::Encoding`), then base64, and signed (see `signing`).
+use crate::batch;
+use crate::signing::Signer;
+use hyper::header::ContentType;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use redis_delta::{digest_delta, Encoding, EncodingErr, RDelta, ENCODING_ATTRIBUTE};
+use std::collections::HashMap;
+
+pub trait Transport {
+    fn send(&self, deltas: Vec<RDelta>) -> Result<(), TransportErr>;
+}
+
+/// Chooses how `Transport` sends deltas to pond.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum TransportKind {
+    #[serde(rename = "pubsub")]
+    PubSub,
+    #[serde(rename = "http")]
+    Http,
+}
+
+#[derive(Debug)]
+pub enum TransportErr {
+    PubSub(google_pubsub1::Error),
+    Http(hyper::Error),
+    Status(u16),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Encoding(EncodingErr),
+}
+
+impl TransportErr {
+    /// pond refused the request itself, e.g. because of a bad
+    /// signature, so sending it again won't help.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            TransportErr::Status(code) => {
+                *code >= 400 && *code < 500 && *code != 408 && *code != 429
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<google_pubsub1::Error> for TransportErr {
+    fn from(error: google_pubsub1::Error) -> Self {
+        TransportErr::PubSub(error)
+    }
+}
+impl From<hyper::Error> for TransportErr {
+    fn from(error: hyper::Error) -> Self {
+        TransportErr::Http(error)
+    }
+}
+impl From<std::io::Error> for TransportErr {
+    fn from(error: std::io::Error) -> Self {
+        TransportErr::Io(error)
+    }
+}
+impl From<serde_json::Error> for TransportErr {
+    fn from(error: serde_json::Error) -> Self {
+        TransportErr::Json(error)
+    }
+}
+impl From<EncodingErr> for TransportErr {
+    fn from(error: EncodingErr) -> Self {
+        TransportErr::Encoding(error)
+    }
+}
+
+/// Shaped and signed just as a Pub/Sub message would be,
+/// so that pond can't tell the difference.
+#[derive(Debug, Serialize)]
+pub struct Message {
+    pub attributes: HashMap<String, String>,
+    pub data: String,
+}
+
+impl Message {
+    /// JSON is sent without an `encoding` attribute, so that
+    /// older versions of pond can still read it.
+    ///
+    /// The envelope is given an `id`, a digest of the delta, so that
+    /// pond can refuse the same signed message if it arrives again,
+    /// whether or not it came through Pub/Sub.
+    pub fn signed(
+        delta: &RDelta,
+        signer: &Signer,
+        encoding: Encoding,
+    ) -> Result<Message, TransportErr> {
+        let id = digest_delta(delta);
+        let data = base64::encode(&encoding.encode_envelope_with_id(delta, &id)?);
+        let mut attributes = signer.attributes(&data);
+        if encoding != Encoding::Json {
+            attributes.insert(
+                ENCODING_ATTRIBUTE.to_string(),
+                encoding.attribute().to_string(),
+            );
+        }
+        Ok(Message { attributes, data })
+    }
+}
+
+/// Matches the body which Pub/Sub posts to pond's `/push_redis` route.
+#[derive(Debug, Serialize)]
+struct PushData {
+    message: Message,
+    subscription: String,
+}
+
+/// Posts deltas straight to pond.  Given pond's `/push_redis`
+/// route, each delta is sent in its own request.  Given its
+/// `/push_redis/batch` route, they're sent a few hundred at a time.
+pub struct HttpTransport {
+    url: String,
+    batch: bool,
+    signer: Signer,
+    encoding: Encoding,
+    client: hyper::Client,
+}
+
+impl HttpTransport {
+    pub fn new(url: &str, batch: bool, signer: Signer, encoding: Encoding) -> HttpTransport {
+        HttpTransport {
+            url: url.to_string(),
+            batch,
+            signer,
+            encoding,
+            client: hyper::Client::with_connector(HttpsConnector::new(
+                NativeTlsClient::new().unwrap(),
+            )),
+        }
+    }
+
+    fn push(&self, delta: &RDelta) -> Result<(), TransportErr> {
+        let body = serde_json::to_string(&PushData {
+            message: Message::signed(delta, &self.signer, self.encoding)?,
+            subscription: "redis_aggregator".to_string(),
+        })?;
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header(ContentType::json())
+            .body(&body)
+            .send()?;
+        if response.status.is_success() {
+            Ok(())
+        } else {
+            Err(TransportErr::Status(response.status.to_u16()))
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, deltas: Vec<RDelta>) -> Result<(), TransportErr> {
+        if self.batch {
+            return batch::push_batches(
+                &self.client,
+                &self.url,
+                &deltas,
+                &self.signer,
+                self.encoding,
+            );
+        }
+
+        for delta in deltas {
+            self.push(&delta)?
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sign;
+    use redis_delta::{Decoded, Envelope, SCHEMA_VERSION};
+
+    #[test]
+    fn test_push_data() {
+        let delta = RDelta::UpdateString {
+            key: "ns/tanks".to_owned(),
+            val: "2".to_owned(),
+            time: 1_500_000_000,
+        };
+        let push_data = PushData {
+            message: Message::signed(&delta, &Signer::hmac(b"sekrit", None), Encoding::Json)
+                .unwrap(),
+            subscription: "redis_aggregator".to_string(),
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&push_data).unwrap()).unwrap();
+        let data = json["message"]["data"].as_str().unwrap();
+        assert_eq!(json["message"]["attributes"]["sig"], sign(data, b"sekrit"));
+        let decoded: Envelope<RDelta> =
+            serde_json::from_slice(&base64::decode(data).unwrap()).unwrap();
+        assert_eq!(decoded.schema, SCHEMA_VERSION);
+        assert_eq!(decoded.body.key(), "ns/tanks");
+        assert!(json["message"]["attributes"].get("encoding").is_none());
+        assert_eq!(
+            Encoding::Json.envelope_id(&base64::decode(data).unwrap()),
+            Some(digest_delta(&delta))
+        )
+    }
+
+    #[test]
+    fn test_cbor_message() {
+        let delta = RDelta::UpdateString {
+            key: "ns/tanks".to_owned(),
+            val: "2".to_owned(),
+            time: 1_500_000_000,
+        };
+        let message =
+            Message::signed(&delta, &Signer::hmac(b"sekrit", None), Encoding::Cbor).unwrap();
+        assert_eq!(
+            message.attributes.get("encoding"),
+            Some(&"cbor/1".to_string())
+        );
+        assert_eq!(
+            message.attributes.get("sig"),
+            Some(&sign(&message.data, b"sekrit"))
+        );
+        let decoded = Encoding::Cbor
+            .decode_envelope(&base64::decode(&message.data).unwrap())
+            .unwrap();
+        assert_eq!(decoded, Decoded::Known(delta))
+    }
+
+    #[test]
+    fn test_is_permanent() {
+        assert!(TransportErr::Status(400).is_permanent());
+        assert!(TransportErr::Status(401).is_permanent());
+        assert!(!TransportErr::Status(429).is_permanent());
+        assert!(!TransportErr::Status(500).is_permanent());
+        assert!(
+            !TransportErr::Io(std::io::Error::from(std::io::ErrorKind::TimedOut)).is_permanent()
+        )
+    }
+
+    #[test]
+    fn test_transport_kind() {
+        let kind: TransportKind = serde_json::from_str("\"http\"").unwrap();
+        assert_eq!(kind, TransportKind::Http);
+        let kind: TransportKind = serde_json::from_str("\"pubsub\"").unwrap();
+        assert_eq!(kind, TransportKind::PubSub)
+    }
+}
@@ -1,7 +1,9 @@
 extern crate redis;
+extern crate redis_delta;
 extern crate uuid;
 
 use redis::Commands;
+use redis_delta::{Key, Namespace};
 use uuid::Uuid;
 
 pub struct RedisContext {
@@ -28,7 +30,10 @@ impl RedisContext {
         &self,
         device_type: String,
     ) -> Result<Uuid, redis::RedisError> {
-        let key = format!("{}/external_device_namespace", self.namespace);
+        let key = Key::ExternalDeviceNamespace {
+            ns: Namespace(self.namespace.to_owned()),
+        }
+        .to_string();
         let r: Option<String> = self.conn.hget(&key, device_type)?;
 
         match r {
@@ -0,0 +1,149 @@
+//! Whether a pH sensor is due for calibration, worked out from the
+//! `calibration_*` fields which ph_ref_calibration writes to its
+//! sensor hash.  Both ph_ref_calibration and pond report this, so
+//! they share the rules and the defaults.
+use std::collections::HashMap;
+
+/// The fields of a pH sensor hash which `CalibrationStatus` reads.
+pub const CALIBRATION_FIELDS: &[&str] = &[
+    "calibration_time",
+    "calibration_slope_efficiency",
+    "calibration_slope_change",
+    "calibration_offset_change",
+];
+
+pub const DEFAULT_MAX_AGE_DAYS: u32 = 30;
+pub const DEFAULT_MIN_SLOPE_EFFICIENCY: f32 = 90.0;
+
+const SECS_PER_DAY: f32 = 86400.0;
+
+/// A sensor is due for calibration once its calibration is
+/// older than `max_age_days`, or once the probe's slope efficiency
+/// (a percentage) falls below `min_slope_efficiency`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DueSettings {
+    pub max_age_days: u32,
+    pub min_slope_efficiency: f32,
+}
+
+impl Default for DueSettings {
+    fn default() -> DueSettings {
+        DueSettings {
+            max_age_days: DEFAULT_MAX_AGE_DAYS,
+            min_slope_efficiency: DEFAULT_MIN_SLOPE_EFFICIENCY,
+        }
+    }
+}
+
+/// Why a sensor ought to be recalibrated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DueReason {
+    NeverCalibrated,
+    Expired,
+    LowSlopeEfficiency,
+}
+
+/// - `slope_change`, `offset_change`: how far the fitted line moved
+///   the last time the sensor was calibrated, in percent and pH
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationStatus {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_days: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slope_efficiency: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slope_change: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset_change: Option<f32>,
+    pub due: bool,
+    pub reasons: Vec<DueReason>,
+}
+
+impl CalibrationStatus {
+    pub fn new(
+        id: &str,
+        fields: &HashMap<String, String>,
+        settings: &DueSettings,
+        now: u64,
+    ) -> CalibrationStatus {
+        let get = |f: &str| fields.get(f).and_then(|s| s.parse::<f32>().ok());
+        let calibration_time = fields
+            .get("calibration_time")
+            .and_then(|s| s.parse::<u64>().ok());
+        let age_days = calibration_time.map(|t| now.saturating_sub(t) as f32 / SECS_PER_DAY);
+        let slope_efficiency = get("calibration_slope_efficiency");
+
+        let mut reasons = vec![];
+        match age_days {
+            None => reasons.push(DueReason::NeverCalibrated),
+            Some(age) if age > settings.max_age_days as f32 => reasons.push(DueReason::Expired),
+            _ => (),
+        }
+        if let Some(e) = slope_efficiency {
+            if e < settings.min_slope_efficiency {
+                reasons.push(DueReason::LowSlopeEfficiency)
+            }
+        }
+
+        CalibrationStatus {
+            id: id.to_string(),
+            calibration_time,
+            age_days,
+            slope_efficiency,
+            slope_change: get("calibration_slope_change"),
+            offset_change: get("calibration_offset_change"),
+            due: !reasons.is_empty(),
+            reasons,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_calibrated() {
+        let status = CalibrationStatus::new("a", &HashMap::new(), &DueSettings::default(), 1000);
+        assert!(status.due);
+        assert_eq!(status.reasons, vec![DueReason::NeverCalibrated]);
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"id":"a","due":true,"reasons":["never_calibrated"]}"#
+        );
+    }
+
+    #[test]
+    fn calibration_due() {
+        let settings = DueSettings {
+            max_age_days: 30,
+            min_slope_efficiency: 90.0,
+        };
+        let now = 100 * 86400;
+        let mut fields = HashMap::new();
+
+        fields.insert("calibration_time".to_string(), (now - 86400).to_string());
+        fields.insert(
+            "calibration_slope_efficiency".to_string(),
+            "97.5".to_string(),
+        );
+        fields.insert("calibration_slope_change".to_string(), "-1.8".to_string());
+        let fresh = CalibrationStatus::new("a", &fields, &settings, now);
+        assert!(!fresh.due);
+        assert_eq!(fresh.age_days, Some(1.0));
+        assert_eq!(fresh.slope_change, Some(-1.8));
+        assert_eq!(fresh.offset_change, None);
+
+        fields.insert("calibration_time".to_string(), "0".to_string());
+        fields.insert("calibration_slope_efficiency".to_string(), "85".to_string());
+        let stale = CalibrationStatus::new("a", &fields, &settings, now);
+        assert_eq!(
+            stale.reasons,
+            vec![DueReason::Expired, DueReason::LowSlopeEfficiency]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Short digests of the values kept under replicated keys.
+//! The redis_aggregator and pond each compute digests of their
+//! own copies, so that only the keys which differ need to be sent.
+//!
+//! Digests must be identical on every platform and with every
+//! version of Rust, so we use FNV-1a rather than `std`'s hasher.
+#[cfg(feature = "keys")]
+use crate::Key;
+use crate::RDelta;
+use std::collections::HashMap;
+
+/// The Redis type of a replicated key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RType {
+    Hash,
+    Set,
+    String,
+}
+
+/// A key, and a digest of its value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyDigest {
+    pub key: String,
+    pub digest: String,
+}
+
+#[cfg(feature = "keys")]
+impl Key {
+    /// The keys which are kept in sync with pond as a whole,
+    /// and their Redis types.  Histories are left out, since
+    /// they're pushed as they grow, and trimmed by age.
+    pub fn replicated_type(&self) -> Option<RType> {
+        match self {
+            Key::AllTanks { .. } | Key::AllAreas { .. } => Some(RType::String),
+            Key::AllSensorTypes { .. } | Key::AllSensors { .. } => Some(RType::Set),
+            Key::Tank { .. } | Key::Area { .. } | Key::Sensor { .. } => Some(RType::Hash),
+            _ => None,
+        }
+    }
+}
+
+/// Field order doesn't matter.
+pub fn digest_hash(fields: &HashMap<String, String>) -> String {
+    let mut sorted: Vec<(&String, &String)> = fields.iter().collect();
+    sorted.sort();
+    let mut fnv = Fnv::new(b'h');
+    for (name, val) in sorted {
+        fnv.write(name.as_bytes());
+        fnv.write(val.as_bytes());
+    }
+    fnv.finish()
+}
+
+/// Member order doesn't matter.
+pub fn digest_set(members: &[String]) -> String {
+    let mut sorted: Vec<&String> = members.iter().collect();
+    sorted.sort();
+    let mut fnv = Fnv::new(b's');
+    for m in sorted {
+        fnv.write(m.as_bytes());
+    }
+    fnv.finish()
+}
+
+pub fn digest_string(val: &str) -> String {
+    let mut fnv = Fnv::new(b'v');
+    fnv.write(val.as_bytes());
+    fnv.finish()
+}
+
+/// Names a delta by its contents, e.g. to give it an `id` in its
+/// envelope.  Deltas are stamped with the time they were read, so
+/// the same value sent again later gets a different digest.
+pub fn digest_delta(delta: &RDelta) -> String {
+    let mut fnv = Fnv::new(b'd');
+    fnv.write(&serde_json::to_vec(delta).unwrap_or_default());
+    fnv.finish()
+}
+
+/// 64-bit FNV-1a.  Each item written is followed by a zero
+/// byte, so that `["ab", "c"]` and `["a", "bc"]` differ.
+struct Fnv(u64);
+
+impl Fnv {
+    /// Starts with a tag, so that different types with
+    /// similar contents don't share digests.
+    fn new(tag: u8) -> Fnv {
+        let mut fnv = Fnv(0xcbf2_9ce4_8422_2325);
+        fnv.byte(tag);
+        fnv
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.0 ^= u64::from(b);
+        self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.byte(*b)
+        }
+        self.byte(0)
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_order() {
+        let mut a = HashMap::new();
+        a.insert("temp_f".to_string(), "78.0".to_string());
+        a.insert("name".to_string(), "Nursery".to_string());
+        let mut b = HashMap::new();
+        b.insert("name".to_string(), "Nursery".to_string());
+        b.insert("temp_f".to_string(), "78.0".to_string());
+        assert_eq!(digest_hash(&a), digest_hash(&b));
+
+        b.insert("temp_f".to_string(), "78.1".to_string());
+        assert_ne!(digest_hash(&a), digest_hash(&b));
+    }
+
+    #[test]
+    fn set_order() {
+        let a = vec!["ph".to_string(), "temp".to_string()];
+        let b = vec!["temp".to_string(), "ph".to_string()];
+        assert_eq!(digest_set(&a), digest_set(&b));
+    }
+
+    #[test]
+    fn boundaries() {
+        let a = vec!["ab".to_string(), "c".to_string()];
+        let b = vec!["a".to_string(), "bc".to_string()];
+        assert_ne!(digest_set(&a), digest_set(&b));
+        assert_ne!(digest_set(&["2".to_string()]), digest_string("2"));
+    }
+
+    #[test]
+    fn delta() {
+        let delta = |val: &str, time: u64| RDelta::UpdateString {
+            key: "prawnspace/tanks".to_string(),
+            val: val.to_string(),
+            time,
+        };
+        assert_eq!(digest_delta(&delta("2", 0)), digest_delta(&delta("2", 0)));
+        assert_ne!(digest_delta(&delta("2", 0)), digest_delta(&delta("3", 0)));
+        assert_ne!(digest_delta(&delta("2", 0)), digest_delta(&delta("2", 1)));
+    }
+
+    #[cfg(feature = "keys")]
+    #[test]
+    fn replicated_types() {
+        use crate::Namespace;
+        let ns = Namespace("prawnspace".to_string());
+        assert_eq!(
+            Key::Area {
+                ns: ns.clone(),
+                id: 1
+            }
+            .replicated_type(),
+            Some(RType::Hash)
+        );
+        assert_eq!(
+            Key::AllAreas { ns: ns.clone() }.replicated_type(),
+            Some(RType::String)
+        );
+        assert_eq!(Key::AuthorizedUids { ns }.replicated_type(), None);
+    }
+
+    #[test]
+    fn stable() {
+        // pond and the redis_aggregator must always agree
+        assert_eq!(digest_string(""), "08cedc07b570414b");
+        let mut fields = HashMap::new();
+        fields.insert("name".to_string(), "Nursery".to_string());
+        assert_eq!(digest_hash(&fields), "7ed05c6d26ad60c8");
+    }
+}
@@ -0,0 +1,85 @@
+//! Deltas are JSON by default.  Small numeric updates are much
+//! smaller as packed CBOR, which senders may choose instead.  The
+//! encoding is named in the message's `encoding` attribute, so that
+//! the receiver knows how to read it.  Messages without the
+//! attribute are JSON, as they always were.
+//!
+//! Packed CBOR identifies fields and variants by position, rather
+//! than by name, so its attribute carries a layout version.  The
+//! version must be bumped whenever fields or variants are reordered.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The message attribute which names the encoding.
+pub const ENCODING_ATTRIBUTE: &str = "encoding";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+#[derive(Debug)]
+pub enum EncodingErr {
+    Json(serde_json::Error),
+    #[cfg(feature = "cbor")]
+    Cbor(serde_cbor::error::Error),
+}
+
+impl From<serde_json::Error> for EncodingErr {
+    fn from(error: serde_json::Error) -> Self {
+        EncodingErr::Json(error)
+    }
+}
+#[cfg(feature = "cbor")]
+impl From<serde_cbor::error::Error> for EncodingErr {
+    fn from(error: serde_cbor::error::Error) -> Self {
+        EncodingErr::Cbor(error)
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Json
+    }
+}
+
+impl Encoding {
+    /// The value of the `encoding` attribute.
+    pub fn attribute(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => "cbor/1",
+        }
+    }
+
+    /// A missing attribute means JSON.  Returns `None`
+    /// for encodings, or versions, which we can't read.
+    pub fn from_attribute(attribute: Option<&str>) -> Option<Encoding> {
+        match attribute {
+            None | Some("json") => Some(Encoding::Json),
+            #[cfg(feature = "cbor")]
+            Some("cbor/1") => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, EncodingErr> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => Ok(serde_cbor::ser::to_vec_packed(value)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, EncodingErr> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => Ok(serde_cbor::from_slice(bytes)?),
+        }
+    }
+}
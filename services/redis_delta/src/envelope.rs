@@ -0,0 +1,319 @@
+//! Deltas and events are sent wrapped in an envelope, which names
+//! the schema version and the kind of value inside it:
+//!
+//! ```json
+//! {"schema":1,"kind":"update_string","body":{"update_string":{"key":"prawnspace/tanks","val":"2","time":0}}}
+//! ```
+//!
+//! The services which send and receive them aren't always upgraded
+//! at the same time.  A receiver which doesn't know the kind, or
+//! which is older than the schema, can skip the value instead of
+//! failing to parse it.  Adding a variant doesn't change the schema
+//! version; changing the shape of an existing variant does.
+//!
+//! Values sent before envelopes existed are still accepted.
+//!
+//! An envelope may also carry an `id`.  Since it's inside the
+//! envelope, it's covered by the sender's signature, so a receiver
+//! can trust it when dropping messages which it has seen before.
+use crate::encoding::{Encoding, EncodingErr};
+use crate::{RDelta, REvent};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// An enum which is sent inside an `Envelope`.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The serialized name of every variant known to this version.
+    const KINDS: &'static [&'static str];
+
+    fn kind(&self) -> &'static str;
+
+    fn is_known(schema: u32, kind: &str) -> bool {
+        schema <= SCHEMA_VERSION && Self::KINDS.contains(&kind)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Envelope<T> {
+    pub schema: u32,
+    pub kind: String,
+    pub body: T,
+    /// Must stay last: packed CBOR names fields by position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+impl<'a, T: Versioned> Envelope<&'a T> {
+    pub fn wrap(body: &'a T) -> Envelope<&'a T> {
+        Envelope {
+            schema: SCHEMA_VERSION,
+            kind: body.kind().to_string(),
+            body,
+            id: None,
+        }
+    }
+}
+
+/// Read before the body, so that an unknown body is never parsed.
+#[derive(Deserialize)]
+struct Header {
+    schema: u32,
+    kind: String,
+}
+
+/// Has the same fields, in the same order, as `Envelope`, so that
+/// its `id` can be read from packed CBOR without reading the body.
+#[derive(Deserialize)]
+struct Stamp {
+    #[serde(rename = "schema")]
+    _schema: u32,
+    #[serde(rename = "kind")]
+    _kind: String,
+    #[serde(rename = "body")]
+    _body: serde::de::IgnoredAny,
+    #[serde(default)]
+    id: Option<String>,
+}
+
+/// The result of opening an envelope.
+#[derive(Debug, PartialEq)]
+pub enum Decoded<T> {
+    Known(T),
+    /// Sent by a newer version than ours.  Should be skipped.
+    Unknown {
+        schema: u32,
+        kind: String,
+    },
+}
+
+impl Encoding {
+    pub fn encode_envelope<T: Versioned>(self, value: &T) -> Result<Vec<u8>, EncodingErr> {
+        self.encode(&Envelope::wrap(value))
+    }
+
+    pub fn encode_envelope_with_id<T: Versioned>(
+        self,
+        value: &T,
+        id: &str,
+    ) -> Result<Vec<u8>, EncodingErr> {
+        self.encode(&Envelope {
+            id: Some(id.to_string()),
+            ..Envelope::wrap(value)
+        })
+    }
+
+    /// The `id` of an envelope, if it was given one.
+    /// Bare values never have one.
+    pub fn envelope_id(self, bytes: &[u8]) -> Option<String> {
+        self.decode::<Stamp>(bytes).ok().and_then(|stamp| stamp.id)
+    }
+
+    /// Also accepts a bare value, sent without an envelope.
+    pub fn decode_envelope<T: Versioned>(self, bytes: &[u8]) -> Result<Decoded<T>, EncodingErr> {
+        let header: Header = match self.decode(bytes) {
+            Ok(h) => h,
+            Err(_) => return Ok(Decoded::Known(self.decode(bytes)?)),
+        };
+        if !T::is_known(header.schema, &header.kind) {
+            return Ok(Decoded::Unknown {
+                schema: header.schema,
+                kind: header.kind,
+            });
+        }
+
+        let envelope: Envelope<T> = self.decode(bytes)?;
+        Ok(Decoded::Known(envelope.body))
+    }
+}
+
+impl Versioned for RDelta {
+    const KINDS: &'static [&'static str] = &[
+        "update_set",
+        "update_hash",
+        "update_string",
+        "update_sorted_set",
+        "remove_hash_fields",
+        "remove_set_members",
+        "delete_key",
+        "expire_key",
+    ];
+
+    fn kind(&self) -> &'static str {
+        match self {
+            RDelta::UpdateSet { .. } => "update_set",
+            RDelta::UpdateHash { .. } => "update_hash",
+            RDelta::UpdateString { .. } => "update_string",
+            RDelta::UpdateSortedSet { .. } => "update_sorted_set",
+            RDelta::RemoveHashFields { .. } => "remove_hash_fields",
+            RDelta::RemoveSetMembers { .. } => "remove_set_members",
+            RDelta::DeleteKey { .. } => "delete_key",
+            RDelta::ExpireKey { .. } => "expire_key",
+        }
+    }
+}
+
+impl Versioned for REvent {
+    const KINDS: &'static [&'static str] = &[
+        "set_updated",
+        "hash_updated",
+        "string_updated",
+        "sorted_set_updated",
+        "set_members_removed",
+        "hash_fields_removed",
+        "key_deleted",
+        "expire_set",
+    ];
+
+    fn kind(&self) -> &'static str {
+        match self {
+            REvent::SetUpdated { .. } => "set_updated",
+            REvent::HashUpdated { .. } => "hash_updated",
+            REvent::StringUpdated { .. } => "string_updated",
+            REvent::SortedSetUpdated { .. } => "sorted_set_updated",
+            REvent::SetMembersRemoved { .. } => "set_members_removed",
+            REvent::HashFieldsRemoved { .. } => "hash_fields_removed",
+            REvent::KeyDeleted { .. } => "key_deleted",
+            REvent::ExpireSet { .. } => "expire_set",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta() -> RDelta {
+        RDelta::UpdateString {
+            key: "prawnspace/tanks".to_string(),
+            val: "2".to_string(),
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn shape() {
+        let json = String::from_utf8(Encoding::Json.encode_envelope(&delta()).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"schema":1,"kind":"update_string","body":{"update_string":{"key":"prawnspace/tanks","val":"2","time":0}}}"#
+        )
+    }
+
+    #[test]
+    fn kinds_match_serde() {
+        let event = REvent::KeyDeleted {
+            key: "k".to_string(),
+        };
+        for (kind, json) in &[
+            (delta().kind(), serde_json::to_value(&delta()).unwrap()),
+            (event.kind(), serde_json::to_value(&event).unwrap()),
+        ] {
+            assert!(json.get(kind).is_some());
+        }
+        assert_eq!(RDelta::KINDS.len(), 8);
+        assert_eq!(REvent::KINDS.len(), 8);
+    }
+
+    #[test]
+    fn legacy() {
+        let bare = serde_json::to_vec(&delta()).unwrap();
+        assert_eq!(
+            Encoding::Json.decode_envelope::<RDelta>(&bare).unwrap(),
+            Decoded::Known(delta())
+        );
+    }
+
+    #[test]
+    fn id() {
+        let bytes = Encoding::Json
+            .encode_envelope_with_id(&delta(), "abc")
+            .unwrap();
+        assert_eq!(Encoding::Json.envelope_id(&bytes), Some("abc".to_string()));
+        assert_eq!(
+            Encoding::Json.decode_envelope::<RDelta>(&bytes).unwrap(),
+            Decoded::Known(delta())
+        );
+
+        let without = Encoding::Json.encode_envelope(&delta()).unwrap();
+        assert_eq!(Encoding::Json.envelope_id(&without), None);
+        let bare = serde_json::to_vec(&delta()).unwrap();
+        assert_eq!(Encoding::Json.envelope_id(&bare), None);
+    }
+
+    #[test]
+    fn unknown_kind() {
+        let json =
+            r#"{"schema":1,"kind":"rename_key","body":{"rename_key":{"from":"a","to":"b"}}}"#;
+        assert_eq!(
+            Encoding::Json
+                .decode_envelope::<RDelta>(json.as_bytes())
+                .unwrap(),
+            Decoded::Unknown {
+                schema: 1,
+                kind: "rename_key".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn newer_schema() {
+        let json = r#"{"schema":2,"kind":"update_string","body":{"update_string":{"key":"k"}}}"#;
+        match Encoding::Json.decode_envelope::<RDelta>(json.as_bytes()) {
+            Ok(Decoded::Unknown { schema: 2, .. }) => (),
+            other => panic!("expected to skip, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn garbage() {
+        assert!(Encoding::Json
+            .decode_envelope::<RDelta>(br#"{"schema":1}"#)
+            .is_err());
+        assert!(Encoding::Json
+            .decode_envelope::<RDelta>(br#"{"rename_key":{}}"#)
+            .is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        let bytes = Encoding::Cbor.encode_envelope(&delta()).unwrap();
+        assert_eq!(
+            Encoding::Cbor.decode_envelope::<RDelta>(&bytes).unwrap(),
+            Decoded::Known(delta())
+        );
+
+        let with_id = Encoding::Cbor
+            .encode_envelope_with_id(&delta(), "abc")
+            .unwrap();
+        assert_eq!(
+            Encoding::Cbor.envelope_id(&with_id),
+            Some("abc".to_string())
+        );
+        assert_eq!(Encoding::Cbor.envelope_id(&bytes), None);
+        assert_eq!(
+            Encoding::Cbor.decode_envelope::<RDelta>(&with_id).unwrap(),
+            Decoded::Known(delta())
+        );
+
+        let unknown = Envelope {
+            schema: 1,
+            kind: "rename_key".to_string(),
+            body: "whatever".to_string(),
+            id: None,
+        };
+        let bytes = Encoding::Cbor.encode(&unknown).unwrap();
+        match Encoding::Cbor.decode_envelope::<RDelta>(&bytes) {
+            Ok(Decoded::Unknown { .. }) => (),
+            other => panic!("expected to skip, found {:?}", other),
+        }
+
+        let bare = Encoding::Cbor.encode(&delta()).unwrap();
+        assert_eq!(
+            Encoding::Cbor.decode_envelope::<RDelta>(&bare).unwrap(),
+            Decoded::Known(delta())
+        );
+    }
+}
@@ -4,12 +4,32 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
+mod calibration;
+mod digest;
+mod encoding;
+mod envelope;
+mod record;
+
+pub use crate::calibration::{
+    CalibrationStatus, DueReason, DueSettings, CALIBRATION_FIELDS, DEFAULT_MAX_AGE_DAYS,
+    DEFAULT_MIN_SLOPE_EFFICIENCY,
+};
+pub use crate::digest::{digest_delta, digest_hash, digest_set, digest_string, KeyDigest, RType};
+pub use crate::encoding::{Encoding, EncodingErr, ENCODING_ATTRIBUTE};
+pub use crate::envelope::{Decoded, Envelope, Versioned, SCHEMA_VERSION};
+pub use crate::record::{AreaRecord, HashRecord, SensorRecord, TankRecord};
+#[cfg(feature = "keys")]
+use std::str::FromStr;
+#[cfg(feature = "keys")]
 use uuid::Uuid;
 /// This enum represents various keys which should
 /// exist in our database.  They each have a namespace
 /// parameter `ns`, which indicates a common "root"
 /// shared by all data for this particular prawn grow.
-#[derive(Serialize, Deserialize)]
+///
+/// Keys can be parsed back from strings using `str::parse`.
+#[cfg(feature = "keys")]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Key {
     Tank {
         ns: Namespace,
@@ -30,19 +50,114 @@ pub enum Key {
         ns: Namespace,
         st: SensorType,
     },
+    Area {
+        ns: Namespace,
+        id: u16,
+    },
+    AllAreas {
+        ns: Namespace,
+    },
+    /// The time series kept for a single field of
+    /// a tank or area hash, e.g. `temp_f`.
+    History {
+        container: Box<Key>,
+        field: String,
+    },
+    /// The UUID namespace used to derive sensor IDs
+    /// from external device IDs.
+    ExternalDeviceNamespace {
+        ns: Namespace,
+    },
+    /// Firebase UIDs which may use pond.
+    AuthorizedUids {
+        ns: Namespace,
+    },
+    /// Firebase UIDs which may use pond's admin routes.
+    AdminUids {
+        ns: Namespace,
+    },
+    /// Google's public keys, used by pond to verify Firebase JWTs.
+    SigningKeys {
+        ns: Namespace,
+    },
+    /// The reference points for a pH sensor's calibration.
+    PhCalibration {
+        ns: Namespace,
+        id: Uuid,
+    },
+    PhCalibrationHistory {
+        ns: Namespace,
+        id: Uuid,
+    },
+    /// Changes made using pond's admin routes, waiting
+    /// for the local site to pick them up.
+    Outbox {
+        ns: Namespace,
+    },
+    OutboxSeq {
+        ns: Namespace,
+    },
+    /// The last outbox entry applied at the local site.
+    OutboxCheckpoint {
+        ns: Namespace,
+    },
+    /// Marks a Pub/Sub message which pond has already ingested.
+    PushMessage {
+        ns: Namespace,
+        id: String,
+    },
+    /// Changes which redis_aggregator has yet to push to pond.
+    PushQueue {
+        ns: Namespace,
+    },
+    /// Changes which redis_aggregator gave up trying to push.
+    PushDeadLetters {
+        ns: Namespace,
+    },
+    /// When redis_aggregator last compared digests with pond.
+    SyncCheckpoint {
+        ns: Namespace,
+    },
+    /// How many messages sensor_tracker has received,
+    /// rejected, or failed to apply.
+    TrackerCounters {
+        ns: Namespace,
+    },
+    /// Alert rules which apply to every tank, e.g. `ph_min`.
+    AlertRules {
+        ns: Namespace,
+    },
+    /// Alert rules for a single tank, which take
+    /// precedence over `AlertRules`.
+    TankAlertRules {
+        ns: Namespace,
+        id: u16,
+    },
+    /// Which of a tank's alerts are open, and the
+    /// readings which opened or resolved them.
+    AlertState {
+        ns: Namespace,
+        id: u16,
+    },
+    /// The `time` of the delta which last wrote each field of
+    /// a replicated hash.  Older deltas can't overwrite newer ones.
+    FieldTimes {
+        hash: String,
+    },
 }
 
 /// Namespace precedes the rest of a key, e.g.
 /// `prawnspace/tanks`
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Namespace(pub String);
 
 /// A type of sensor.  ph, temp, ...
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SensorType(pub String);
 
 /// Yields the key which allows you to access a specific
 /// record in redis.
+#[cfg(feature = "keys")]
 impl Key {
     pub fn to_string(&self) -> String {
         match self {
@@ -68,10 +183,166 @@ impl Key {
                 Key::AllSensorTypes { ns: ns.clone() }.to_string(),
                 st
             ),
+            Key::Area { ns, id } => {
+                format!("{}/{}", Key::AllAreas { ns: ns.clone() }.to_string(), id)
+            }
+            Key::AllAreas { ns: Namespace(n) } => format!("{}/areas", n),
+            Key::History { container, field } => {
+                format!("{}/history/{}", container.to_string(), field)
+            }
+            Key::ExternalDeviceNamespace { ns: Namespace(n) } => {
+                format!("{}/external_device_namespace", n)
+            }
+            Key::AuthorizedUids { ns: Namespace(n) } => {
+                format!("{}/pond/firebase/authorized_uids", n)
+            }
+            Key::AdminUids { ns: Namespace(n) } => format!("{}/pond/firebase/admin_uids", n),
+            Key::SigningKeys { ns: Namespace(n) } => {
+                format!("{}/pond/firebase/public_signing_keys", n)
+            }
+            Key::PhCalibration { ns, id } => format!(
+                "{}/calibration",
+                Key::Sensor {
+                    ns: ns.clone(),
+                    st: SensorType(PH.to_owned()),
+                    id: *id
+                }
+                .to_string()
+            ),
+            Key::PhCalibrationHistory { ns, id } => format!(
+                "{}/history",
+                Key::PhCalibration {
+                    ns: ns.clone(),
+                    id: *id
+                }
+                .to_string()
+            ),
+            Key::Outbox { ns: Namespace(n) } => format!("{}/pond/outbox", n),
+            Key::OutboxSeq { ns } => {
+                format!("{}/seq", Key::Outbox { ns: ns.clone() }.to_string())
+            }
+            Key::OutboxCheckpoint { ns } => {
+                format!("{}/checkpoint", Key::Outbox { ns: ns.clone() }.to_string())
+            }
+            Key::PushMessage {
+                ns: Namespace(n),
+                id,
+            } => format!("{}/pond/push/messages/{}", n, id),
+            Key::PushQueue { ns: Namespace(n) } => format!("{}/redis_aggregator/push_queue", n),
+            Key::PushDeadLetters { ns: Namespace(n) } => {
+                format!("{}/redis_aggregator/dead_letters", n)
+            }
+            Key::SyncCheckpoint { ns: Namespace(n) } => {
+                format!("{}/redis_aggregator/sync_checkpoint", n)
+            }
+            Key::TrackerCounters { ns: Namespace(n) } => {
+                format!("{}/sensor_tracker/counters", n)
+            }
+            Key::AlertRules { ns: Namespace(n) } => format!("{}/alerts/rules", n),
+            Key::TankAlertRules { ns, id } => format!(
+                "{}/tanks/{}",
+                Key::AlertRules { ns: ns.clone() }.to_string(),
+                id
+            ),
+            Key::AlertState {
+                ns: Namespace(n),
+                id,
+            } => format!("{}/alerts/tanks/{}", n, id),
+            Key::FieldTimes { hash } => format!("{}{}", hash, FIELD_TIMES_SUFFIX),
         }
     }
 }
 
+#[cfg(feature = "keys")]
+const PH: &str = "ph";
+#[cfg(feature = "keys")]
+const FIELD_TIMES_SUFFIX: &str = "/field_times";
+
+/// A string which doesn't match any of our keys.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownKey(pub String);
+
+/// The namespace may itself contain slashes, so we try
+/// each possible namespace, shortest first, until the
+/// remainder of the string is recognized.
+#[cfg(feature = "keys")]
+impl FromStr for Key {
+    type Err = UnknownKey;
+
+    fn from_str(s: &str) -> Result<Key, UnknownKey> {
+        if s.ends_with(FIELD_TIMES_SUFFIX) && s.len() > FIELD_TIMES_SUFFIX.len() {
+            return Ok(Key::FieldTimes {
+                hash: s[..s.len() - FIELD_TIMES_SUFFIX.len()].to_string(),
+            });
+        }
+
+        let parts: Vec<&str> = s.split('/').collect();
+        for i in 1..parts.len() {
+            let ns = Namespace(parts[..i].join("/"));
+            if let Some(key) = parse_after_namespace(ns, &parts[i..]) {
+                return Ok(key);
+            }
+        }
+
+        Err(UnknownKey(s.to_string()))
+    }
+}
+
+#[cfg(feature = "keys")]
+fn parse_after_namespace(ns: Namespace, rest: &[&str]) -> Option<Key> {
+    match rest {
+        ["tanks"] => Some(Key::AllTanks { ns }),
+        ["tanks", id] => id.parse().ok().map(|id| Key::Tank { ns, id }),
+        ["tanks", id, "history", field] => id.parse().ok().map(|id| Key::History {
+            container: Box::new(Key::Tank { ns, id }),
+            field: field.to_string(),
+        }),
+        ["areas"] => Some(Key::AllAreas { ns }),
+        ["areas", id] => id.parse().ok().map(|id| Key::Area { ns, id }),
+        ["areas", id, "history", field] => id.parse().ok().map(|id| Key::History {
+            container: Box::new(Key::Area { ns, id }),
+            field: field.to_string(),
+        }),
+        ["sensors"] => Some(Key::AllSensorTypes { ns }),
+        ["sensors", st] => Some(Key::AllSensors {
+            ns,
+            st: SensorType(st.to_string()),
+        }),
+        ["sensors", st, id] => Uuid::parse_str(id).ok().map(|id| Key::Sensor {
+            ns,
+            st: SensorType(st.to_string()),
+            id,
+        }),
+        ["sensors", "ph", id, "calibration"] => Uuid::parse_str(id)
+            .ok()
+            .map(|id| Key::PhCalibration { ns, id }),
+        ["sensors", "ph", id, "calibration", "history"] => Uuid::parse_str(id)
+            .ok()
+            .map(|id| Key::PhCalibrationHistory { ns, id }),
+        ["external_device_namespace"] => Some(Key::ExternalDeviceNamespace { ns }),
+        ["pond", "firebase", "authorized_uids"] => Some(Key::AuthorizedUids { ns }),
+        ["pond", "firebase", "admin_uids"] => Some(Key::AdminUids { ns }),
+        ["pond", "firebase", "public_signing_keys"] => Some(Key::SigningKeys { ns }),
+        ["pond", "outbox"] => Some(Key::Outbox { ns }),
+        ["pond", "outbox", "seq"] => Some(Key::OutboxSeq { ns }),
+        ["pond", "outbox", "checkpoint"] => Some(Key::OutboxCheckpoint { ns }),
+        ["pond", "push", "messages", id] => Some(Key::PushMessage {
+            ns,
+            id: id.to_string(),
+        }),
+        ["redis_aggregator", "push_queue"] => Some(Key::PushQueue { ns }),
+        ["redis_aggregator", "dead_letters"] => Some(Key::PushDeadLetters { ns }),
+        ["redis_aggregator", "sync_checkpoint"] => Some(Key::SyncCheckpoint { ns }),
+        ["sensor_tracker", "counters"] => Some(Key::TrackerCounters { ns }),
+        ["alerts", "rules"] => Some(Key::AlertRules { ns }),
+        ["alerts", "rules", "tanks", id] => {
+            id.parse().ok().map(|id| Key::TankAlertRules { ns, id })
+        }
+        ["alerts", "tanks", id] => id.parse().ok().map(|id| Key::AlertState { ns, id }),
+        _ => None,
+    }
+}
+
 /// Represents a change to a value in Redis.
 /// Currently only supports the minimum combinations
 /// of key/value used by the prawnalith.
@@ -95,6 +366,67 @@ pub enum RDelta {
         val: String,
         time: u64,
     },
+    /// Members replace any existing members which share
+    /// the same score.
+    UpdateSortedSet {
+        key: String,
+        members: Vec<RMember>,
+        time: u64,
+    },
+    /// Fields which should be deleted from a hash,
+    /// e.g. when a sensor is unlinked from its tank.
+    RemoveHashFields {
+        key: String,
+        fields: Vec<String>,
+        time: u64,
+    },
+    /// Members which should be removed from a set,
+    /// e.g. when a sensor is retired.
+    RemoveSetMembers {
+        key: String,
+        vals: Vec<String>,
+        time: u64,
+    },
+    /// The whole key should be deleted, e.g. when a tank is retired.
+    DeleteKey {
+        key: String,
+        time: u64,
+    },
+    /// The key should expire `ttl_secs` after it's applied.
+    ExpireKey {
+        key: String,
+        ttl_secs: u64,
+        time: u64,
+    },
+}
+
+impl RDelta {
+    pub fn key(&self) -> &str {
+        match self {
+            RDelta::UpdateSet { key, .. } => key,
+            RDelta::UpdateHash { key, .. } => key,
+            RDelta::UpdateString { key, .. } => key,
+            RDelta::UpdateSortedSet { key, .. } => key,
+            RDelta::RemoveHashFields { key, .. } => key,
+            RDelta::RemoveSetMembers { key, .. } => key,
+            RDelta::DeleteKey { key, .. } => key,
+            RDelta::ExpireKey { key, .. } => key,
+        }
+    }
+
+    /// Epoch secs when the delta was retrieved.
+    pub fn time(&self) -> u64 {
+        match self {
+            RDelta::UpdateSet { time, .. } => *time,
+            RDelta::UpdateHash { time, .. } => *time,
+            RDelta::UpdateString { time, .. } => *time,
+            RDelta::UpdateSortedSet { time, .. } => *time,
+            RDelta::RemoveHashFields { time, .. } => *time,
+            RDelta::RemoveSetMembers { time, .. } => *time,
+            RDelta::DeleteKey { time, .. } => *time,
+            RDelta::ExpireKey { time, .. } => *time,
+        }
+    }
 }
 
 /// A field which is stored in Redis.
@@ -104,19 +436,68 @@ pub struct RField {
     pub val: String,
 }
 
+/// A member of a sorted set which is stored in Redis.
+/// Our sorted sets are time series, so the score is
+/// always expressed in epoch secs.
+#[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
+pub struct RMember {
+    pub score: u64,
+    pub val: String,
+}
+
+/// A change made in the cloud, waiting for the local
+/// site to pick it up.  `seq` increases with each change,
+/// so the local site only needs to remember the last one it saw.
+///
+/// `schema` is zero for entries queued before schema versions
+/// existed, and is left out of their JSON.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub seq: u64,
+    #[serde(default, skip_serializing_if = "is_unversioned")]
+    pub schema: u32,
+    pub delta: RDelta,
+}
+
+impl OutboxEntry {
+    pub fn new(seq: u64, delta: RDelta) -> OutboxEntry {
+        OutboxEntry {
+            seq,
+            schema: SCHEMA_VERSION,
+            delta,
+        }
+    }
+}
+
+fn is_unversioned(schema: &u32) -> bool {
+    *schema == 0
+}
+
 /// Represents a message that lets you know that a specific
 /// string, hash, or set has changed.  It does not include
 /// the data which has changed, though in the case of hashes,
 /// it *does* include a list of fields which were changed.
+/// For sorted sets, `min_score` is the lowest score which
+/// was written, so that only recent members need to be fetched.
+///
+/// Removals name the members or fields which were removed, since
+/// they can no longer be read back.  The redis_aggregator checks
+/// that they're still gone before passing them on.
 #[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum REvent {
     SetUpdated { key: String },
     HashUpdated { key: String, fields: Vec<String> },
     StringUpdated { key: String },
+    SortedSetUpdated { key: String, min_score: u64 },
+    SetMembersRemoved { key: String, vals: Vec<String> },
+    HashFieldsRemoved { key: String, fields: Vec<String> },
+    KeyDeleted { key: String },
+    /// A TTL was set on the key.
+    ExpireSet { key: String },
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "keys"))]
 mod key_test {
     use super::*;
     use uuid::Uuid;
@@ -177,9 +558,128 @@ mod key_test {
         );
     }
 
+    #[test]
+    fn test_single_area() {
+        let area = Key::Area {
+            ns: prawnspace(),
+            id: 2,
+        };
+        assert_eq!(area.to_string(), "prawnspace/areas/2");
+    }
+
+    #[test]
+    fn test_history() {
+        let history = Key::History {
+            container: Box::new(Key::Tank {
+                ns: prawnspace(),
+                id: 1,
+            }),
+            field: "temp_f".to_owned(),
+        };
+        assert_eq!(history.to_string(), "prawnspace/tanks/1/history/temp_f");
+    }
+
+    #[test]
+    fn test_pond_keys() {
+        assert_eq!(
+            Key::AuthorizedUids { ns: prawnspace() }.to_string(),
+            "prawnspace/pond/firebase/authorized_uids"
+        );
+        assert_eq!(
+            Key::SigningKeys { ns: prawnspace() }.to_string(),
+            "prawnspace/pond/firebase/public_signing_keys"
+        );
+        assert_eq!(
+            Key::OutboxCheckpoint { ns: prawnspace() }.to_string(),
+            "prawnspace/pond/outbox/checkpoint"
+        );
+    }
+
+    #[test]
+    fn test_ph_calibration() {
+        let ph_id = Uuid::new_v4();
+        assert_eq!(
+            Key::PhCalibrationHistory {
+                ns: prawnspace(),
+                id: ph_id
+            }
+            .to_string(),
+            format!("prawnspace/sensors/ph/{}/calibration/history", ph_id)
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let ns = prawnspace;
+        let id = Uuid::new_v4();
+        let keys = vec![
+            Key::Tank { ns: ns(), id: 1 },
+            Key::Sensor {
+                ns: ns(),
+                st: SensorType("temp".to_owned()),
+                id,
+            },
+            Key::AllTanks { ns: ns() },
+            Key::AllSensorTypes { ns: ns() },
+            Key::AllSensors {
+                ns: ns(),
+                st: SensorType("dht".to_owned()),
+            },
+            Key::Area { ns: ns(), id: 3 },
+            Key::AllAreas { ns: ns() },
+            Key::History {
+                container: Box::new(Key::Area { ns: ns(), id: 3 }),
+                field: "humidity".to_owned(),
+            },
+            Key::ExternalDeviceNamespace { ns: ns() },
+            Key::AuthorizedUids { ns: ns() },
+            Key::AdminUids { ns: ns() },
+            Key::SigningKeys { ns: ns() },
+            Key::PhCalibration { ns: ns(), id },
+            Key::PhCalibrationHistory { ns: ns(), id },
+            Key::Outbox { ns: ns() },
+            Key::OutboxSeq { ns: ns() },
+            Key::OutboxCheckpoint { ns: ns() },
+            Key::PushMessage {
+                ns: ns(),
+                id: "136969346945".to_owned(),
+            },
+            Key::PushQueue { ns: ns() },
+            Key::PushDeadLetters { ns: ns() },
+            Key::SyncCheckpoint { ns: ns() },
+            Key::TrackerCounters { ns: ns() },
+            Key::AlertRules { ns: ns() },
+            Key::TankAlertRules { ns: ns(), id: 2 },
+            Key::AlertState { ns: ns(), id: 2 },
+            Key::FieldTimes {
+                hash: Key::Tank { ns: ns(), id: 1 }.to_string(),
+            },
+        ];
+        for key in keys {
+            assert_eq!(key.to_string().parse::<Key>(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn test_parse_namespace_with_slashes() {
+        assert_eq!(
+            "prawn/space/tanks/4".parse::<Key>(),
+            Ok(Key::Tank {
+                ns: Namespace("prawn/space".to_owned()),
+                id: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown() {
+        assert!("prawnspace/tanks/one".parse::<Key>().is_err());
+        assert!("prawnspace/sensors/temp/not-a-uuid".parse::<Key>().is_err());
+        assert!("tanks".parse::<Key>().is_err());
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "keys"))]
 mod rdelta_test {
     use super::*;
     use serde_json;
@@ -258,4 +758,376 @@ mod rdelta_test {
             _ => assert!(false),
         }
     }
+
+    #[test]
+    fn update_sorted_set_ser() {
+        let update = &RDelta::UpdateSortedSet {
+            key: format!(
+                "{}/history/temp_f",
+                Key::Tank { ns: ns(), id: 1 }.to_string()
+            ),
+            members: vec![RMember {
+                score: 1541082840,
+                val: "1541082840:81.16".to_string(),
+            }],
+            time: 0,
+        };
+
+        let expected = r#"{"update_sorted_set":{"key":"prawnspace/tanks/1/history/temp_f","members":[{"score":1541082840,"val":"1541082840:81.16"}],"time":0}}"#;
+        assert_eq!(serde_json::to_string(update).unwrap(), expected.to_string());
+
+        let deser: RDelta = serde_json::from_str(expected).unwrap();
+        assert_eq!(&deser, update);
+    }
+
+    #[test]
+    fn outbox_entry_ser() {
+        let entry = OutboxEntry {
+            seq: 7,
+            schema: 0,
+            delta: RDelta::RemoveHashFields {
+                key: Key::Sensor {
+                    ns: ns(),
+                    st: SensorType("temp".to_owned()),
+                    id: id(),
+                }
+                .to_string(),
+                fields: vec!["tank".to_string()],
+                time: 0,
+            },
+        };
+
+        let expected = r#"{"seq":7,"delta":{"remove_hash_fields":{"key":"prawnspace/sensors/temp/123e4567-e89b-12d3-a456-426655440000","fields":["tank"],"time":0}}}"#;
+        assert_eq!(serde_json::to_string(&entry).unwrap(), expected.to_string());
+
+        let deser: OutboxEntry = serde_json::from_str(expected).unwrap();
+        assert_eq!(deser, entry);
+    }
+
+    #[test]
+    fn sorted_set_updated_ser() {
+        let event = REvent::SortedSetUpdated {
+            key: "prawnspace/tanks/1/history/ph".to_string(),
+            min_score: 1541082840,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"sorted_set_updated":{"key":"prawnspace/tanks/1/history/ph","min_score":1541082840}}"#
+        );
+    }
+
+    #[test]
+    fn removal_ser() {
+        let removed = &RDelta::RemoveSetMembers {
+            key: "prawnspace/sensors/temp".to_string(),
+            vals: vec![id().to_string()],
+            time: 0,
+        };
+        let expected = r#"{"remove_set_members":{"key":"prawnspace/sensors/temp","vals":["123e4567-e89b-12d3-a456-426655440000"],"time":0}}"#;
+        assert_eq!(serde_json::to_string(removed).unwrap(), expected);
+        let deser: RDelta = serde_json::from_str(expected).unwrap();
+        assert_eq!(&deser, removed);
+
+        let deleted = &RDelta::DeleteKey {
+            key: Key::Tank { ns: ns(), id: 3 }.to_string(),
+            time: 0,
+        };
+        let expected = r#"{"delete_key":{"key":"prawnspace/tanks/3","time":0}}"#;
+        assert_eq!(serde_json::to_string(deleted).unwrap(), expected);
+        let deser: RDelta = serde_json::from_str(expected).unwrap();
+        assert_eq!(&deser, deleted);
+
+        let expire = &RDelta::ExpireKey {
+            key: "prawnspace/tanks/3".to_string(),
+            ttl_secs: 60,
+            time: 0,
+        };
+        let expected = r#"{"expire_key":{"key":"prawnspace/tanks/3","ttl_secs":60,"time":0}}"#;
+        assert_eq!(serde_json::to_string(expire).unwrap(), expected);
+        let deser: RDelta = serde_json::from_str(expected).unwrap();
+        assert_eq!(&deser, expire);
+    }
+
+    #[test]
+    fn removal_event_ser() {
+        let event = REvent::SetMembersRemoved {
+            key: "prawnspace/sensors/temp".to_string(),
+            vals: vec!["a".to_string()],
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"set_members_removed":{"key":"prawnspace/sensors/temp","vals":["a"]}}"#
+        );
+        let event = REvent::KeyDeleted {
+            key: "prawnspace/tanks/3".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"key_deleted":{"key":"prawnspace/tanks/3"}}"#
+        );
+    }
+}
+
+#[cfg(all(test, feature = "cbor"))]
+mod encoding_test {
+    use super::*;
+
+    fn deltas() -> Vec<RDelta> {
+        vec![
+            RDelta::UpdateSet {
+                key: "prawnspace/sensors".to_string(),
+                vals: vec!["temp".to_string(), "ph".to_string()],
+                time: 1541082840,
+            },
+            RDelta::UpdateHash {
+                key: "prawnspace/tanks/1".to_string(),
+                fields: vec![RField {
+                    name: "temp_f".to_string(),
+                    val: "81.16".to_string(),
+                }],
+                time: 1541082840,
+            },
+            RDelta::UpdateString {
+                key: "prawnspace/tanks".to_string(),
+                val: "2".to_string(),
+                time: 1541082840,
+            },
+            RDelta::UpdateSortedSet {
+                key: "prawnspace/tanks/1/history/temp_f".to_string(),
+                members: vec![RMember {
+                    score: 1541082840,
+                    val: "1541082840:81.16".to_string(),
+                }],
+                time: 1541082840,
+            },
+            RDelta::RemoveHashFields {
+                key: "prawnspace/tanks/1".to_string(),
+                fields: vec!["ph".to_string()],
+                time: 1541082840,
+            },
+            RDelta::RemoveSetMembers {
+                key: "prawnspace/sensors".to_string(),
+                vals: vec!["ph".to_string()],
+                time: 1541082840,
+            },
+            RDelta::DeleteKey {
+                key: "prawnspace/tanks/2".to_string(),
+                time: 1541082840,
+            },
+            RDelta::ExpireKey {
+                key: "prawnspace/tanks/2".to_string(),
+                ttl_secs: 60,
+                time: 1541082840,
+            },
+        ]
+    }
+
+    #[test]
+    fn cbor_round_trip() {
+        for delta in deltas() {
+            let bytes = Encoding::Cbor.encode(&delta).unwrap();
+            let decoded: RDelta = Encoding::Cbor.decode(&bytes).unwrap();
+            assert_eq!(decoded, delta);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for delta in deltas() {
+            let bytes = Encoding::Json.encode(&delta).unwrap();
+            assert_eq!(bytes, serde_json::to_vec(&delta).unwrap());
+            let decoded: RDelta = Encoding::Json.decode(&bytes).unwrap();
+            assert_eq!(decoded, delta);
+        }
+    }
+
+    #[test]
+    fn cbor_is_smaller() {
+        for delta in deltas() {
+            let cbor = Encoding::Cbor.encode(&delta).unwrap();
+            let json = Encoding::Json.encode(&delta).unwrap();
+            assert!(cbor.len() < json.len());
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(Encoding::from_attribute(None), Some(Encoding::Json));
+        for encoding in &[Encoding::Json, Encoding::Cbor] {
+            assert_eq!(
+                Encoding::from_attribute(Some(encoding.attribute())),
+                Some(*encoding)
+            );
+        }
+        assert_eq!(Encoding::from_attribute(Some("cbor/2")), None);
+    }
+
+    #[test]
+    fn cbor_garbage() {
+        let found: Result<RDelta, _> = Encoding::Cbor.decode(b"{\"update_set\":{}}");
+        assert!(found.is_err())
+    }
+}
+
+/// Older services read these exact shapes, so they
+/// mustn't change without bumping `SCHEMA_VERSION`.
+#[cfg(test)]
+mod compat_test {
+    use super::*;
+
+    fn field() -> RField {
+        RField {
+            name: "temp_f".to_string(),
+            val: "81.16".to_string(),
+        }
+    }
+
+    fn member() -> RMember {
+        RMember {
+            score: 1,
+            val: "1:81.16".to_string(),
+        }
+    }
+
+    #[test]
+    fn rdelta_shapes() {
+        let k = || "k".to_string();
+        let pinned = vec![
+            (
+                RDelta::UpdateSet {
+                    key: k(),
+                    vals: vec!["a".to_string()],
+                    time: 0,
+                },
+                r#"{"update_set":{"key":"k","vals":["a"],"time":0}}"#,
+            ),
+            (
+                RDelta::UpdateHash {
+                    key: k(),
+                    fields: vec![field()],
+                    time: 0,
+                },
+                r#"{"update_hash":{"key":"k","fields":[{"name":"temp_f","val":"81.16"}],"time":0}}"#,
+            ),
+            (
+                RDelta::UpdateString {
+                    key: k(),
+                    val: "2".to_string(),
+                    time: 0,
+                },
+                r#"{"update_string":{"key":"k","val":"2","time":0}}"#,
+            ),
+            (
+                RDelta::UpdateSortedSet {
+                    key: k(),
+                    members: vec![member()],
+                    time: 0,
+                },
+                r#"{"update_sorted_set":{"key":"k","members":[{"score":1,"val":"1:81.16"}],"time":0}}"#,
+            ),
+            (
+                RDelta::RemoveHashFields {
+                    key: k(),
+                    fields: vec!["tank".to_string()],
+                    time: 0,
+                },
+                r#"{"remove_hash_fields":{"key":"k","fields":["tank"],"time":0}}"#,
+            ),
+            (
+                RDelta::RemoveSetMembers {
+                    key: k(),
+                    vals: vec!["a".to_string()],
+                    time: 0,
+                },
+                r#"{"remove_set_members":{"key":"k","vals":["a"],"time":0}}"#,
+            ),
+            (
+                RDelta::DeleteKey { key: k(), time: 0 },
+                r#"{"delete_key":{"key":"k","time":0}}"#,
+            ),
+            (
+                RDelta::ExpireKey {
+                    key: k(),
+                    ttl_secs: 60,
+                    time: 0,
+                },
+                r#"{"expire_key":{"key":"k","ttl_secs":60,"time":0}}"#,
+            ),
+        ];
+        assert_eq!(pinned.len(), RDelta::KINDS.len());
+        for (delta, json) in pinned {
+            assert_eq!(serde_json::to_string(&delta).unwrap(), json);
+            assert_eq!(serde_json::from_str::<RDelta>(json).unwrap(), delta);
+            assert!(json.starts_with(&format!("{{\"{}\":", delta.kind())));
+        }
+    }
+
+    #[test]
+    fn revent_shapes() {
+        let k = || "k".to_string();
+        let pinned = vec![
+            (REvent::SetUpdated { key: k() }, r#"{"set_updated":{"key":"k"}}"#),
+            (
+                REvent::HashUpdated {
+                    key: k(),
+                    fields: vec!["temp_f".to_string()],
+                },
+                r#"{"hash_updated":{"key":"k","fields":["temp_f"]}}"#,
+            ),
+            (
+                REvent::StringUpdated { key: k() },
+                r#"{"string_updated":{"key":"k"}}"#,
+            ),
+            (
+                REvent::SortedSetUpdated {
+                    key: k(),
+                    min_score: 1,
+                },
+                r#"{"sorted_set_updated":{"key":"k","min_score":1}}"#,
+            ),
+            (
+                REvent::SetMembersRemoved {
+                    key: k(),
+                    vals: vec!["a".to_string()],
+                },
+                r#"{"set_members_removed":{"key":"k","vals":["a"]}}"#,
+            ),
+            (
+                REvent::HashFieldsRemoved {
+                    key: k(),
+                    fields: vec!["tank".to_string()],
+                },
+                r#"{"hash_fields_removed":{"key":"k","fields":["tank"]}}"#,
+            ),
+            (REvent::KeyDeleted { key: k() }, r#"{"key_deleted":{"key":"k"}}"#),
+            (REvent::ExpireSet { key: k() }, r#"{"expire_set":{"key":"k"}}"#),
+        ];
+        assert_eq!(pinned.len(), REvent::KINDS.len());
+        for (event, json) in pinned {
+            assert_eq!(serde_json::to_string(&event).unwrap(), json);
+            assert_eq!(serde_json::from_str::<REvent>(json).unwrap(), event);
+            // the name in the envelope matches the name serde uses
+            assert!(json.starts_with(&format!("{{\"{}\":", event.kind())));
+        }
+    }
+
+    #[test]
+    fn outbox_entry_shape() {
+        let entry = OutboxEntry::new(
+            7,
+            RDelta::DeleteKey {
+                key: "k".to_string(),
+                time: 0,
+            },
+        );
+        let json = r#"{"seq":7,"schema":1,"delta":{"delete_key":{"key":"k","time":0}}}"#;
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+        assert_eq!(serde_json::from_str::<OutboxEntry>(json).unwrap(), entry);
+
+        let legacy = r#"{"seq":7,"delta":{"delete_key":{"key":"k","time":0}}}"#;
+        assert_eq!(
+            serde_json::from_str::<OutboxEntry>(legacy).unwrap().schema,
+            0
+        );
+    }
 }
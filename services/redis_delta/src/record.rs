@@ -0,0 +1,275 @@
+//! Typed views of the hashes which we keep for each tank,
+//! area, and sensor.  Every field is optional, since any
+//! given hash only holds the fields which have been written
+//! to it so far.  Values are stored in Redis as strings, and
+//! fields which fail to parse are treated as missing.
+use crate::RField;
+use std::collections::HashMap;
+
+/// Converts between a typed record and the fields
+/// of a Redis hash.
+pub trait HashRecord: Sized + Default {
+    /// Every field name, in declaration order.  Useful with `HMGET`.
+    const FIELDS: &'static [&'static str];
+
+    /// Name/value pairs for each field which is present.
+    fn to_pairs(&self) -> Vec<(&'static str, String)>;
+
+    fn from_hash_map(map: &HashMap<String, String>) -> Self;
+
+    fn to_rfields(&self) -> Vec<RField> {
+        self.to_pairs()
+            .into_iter()
+            .map(|(name, val)| RField {
+                name: name.to_string(),
+                val,
+            })
+            .collect()
+    }
+
+    fn from_rfields(fields: &[RField]) -> Self {
+        Self::from_hash_map(
+            &fields
+                .iter()
+                .map(|f| (f.name.to_string(), f.val.to_string()))
+                .collect(),
+        )
+    }
+
+    fn to_hash_map(&self) -> HashMap<String, String> {
+        self.to_pairs()
+            .into_iter()
+            .map(|(name, val)| (name.to_string(), val))
+            .collect()
+    }
+
+    /// Pairs the values returned by `HMGET` with `FIELDS`.
+    fn from_values(vals: Vec<Option<String>>) -> Self {
+        let mut map = HashMap::new();
+        for (name, maybe_val) in Self::FIELDS.iter().zip(vals) {
+            if let Some(val) = maybe_val {
+                map.insert(name.to_string(), val);
+            }
+        }
+        Self::from_hash_map(&map)
+    }
+}
+
+macro_rules! hash_record {
+    ($(#[$meta:meta])* $name:ident { $($field:ident : $ty:ty,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+        pub struct $name {
+            $(
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $field: Option<$ty>,
+            )*
+        }
+
+        impl HashRecord for $name {
+            const FIELDS: &'static [&'static str] = &[$(stringify!($field)),*];
+
+            fn to_pairs(&self) -> Vec<(&'static str, String)> {
+                let mut pairs = vec![];
+                $(
+                    if let Some(v) = &self.$field {
+                        pairs.push((stringify!($field), v.to_string()))
+                    }
+                )*
+                pairs
+            }
+
+            fn from_hash_map(map: &HashMap<String, String>) -> Self {
+                $name {
+                    $(
+                        $field: map
+                            .get(stringify!($field))
+                            .and_then(|v| v.parse::<$ty>().ok()),
+                    )*
+                }
+            }
+        }
+    };
+}
+
+hash_record! {
+    /// The most recent readings from the sensors linked to a tank,
+    /// e.g. `prawnspace/tanks/1`.  `<type>_sensors` lists the
+    /// sensors whose readings were combined, separated by commas.
+    TankRecord {
+        name: String,
+        temp_f: f64,
+        temp_c: f64,
+        temp_update_time: u64,
+        temp_update_count: u64,
+        temp_sensors: String,
+        ph: f64,
+        ph_mv: f64,
+        ph_device: f64,
+        ph_disagreement: bool,
+        ph_update_time: u64,
+        ph_update_count: u64,
+        ph_sensors: String,
+        do_mg_l: f64,
+        do_saturation: f64,
+        do_update_time: u64,
+        do_update_count: u64,
+        do_sensors: String,
+        ec_us_cm: f64,
+        tds_ppm: f64,
+        ec_update_time: u64,
+        ec_update_count: u64,
+        ec_sensors: String,
+    }
+}
+
+hash_record! {
+    /// The most recent readings from the sensors linked to an area,
+    /// e.g. `prawnspace/areas/1`
+    AreaRecord {
+        name: String,
+        status: String,
+        humidity: f64,
+        temp_f: f64,
+        temp_c: f64,
+        heat_index_f: f64,
+        heat_index_c: f64,
+        temp_update_time: u64,
+        temp_update_count: u64,
+        temp_sensors: String,
+        dht_update_time: u64,
+        dht_update_count: u64,
+        dht_sensors: String,
+    }
+}
+
+hash_record! {
+    /// An individual sensor, e.g. `prawnspace/sensors/ph/<uuid>`.
+    /// A sensor is linked to at most one tank or area.  The
+    /// calibration fields are written by ph_ref_calibration.
+    /// The quarantine fields describe the most recent reading
+    /// which sensor_tracker rejected.  `primary` marks the sensor
+    /// which a tank or area prefers, under the `primary` policy.
+    SensorRecord {
+        tank: u16,
+        area: u16,
+        ext_device_id: String,
+        create_time: u64,
+        primary: bool,
+        status: String,
+        humidity: f64,
+        temp_f: f64,
+        temp_c: f64,
+        heat_index_f: f64,
+        heat_index_c: f64,
+        ph: f64,
+        ph_mv: f64,
+        ph_device: f64,
+        ph_disagreement: bool,
+        do_mg_l: f64,
+        do_saturation: f64,
+        ec_us_cm: f64,
+        tds_ppm: f64,
+        temp_update_time: u64,
+        temp_update_count: u64,
+        ph_update_time: u64,
+        ph_update_count: u64,
+        dht_update_time: u64,
+        dht_update_count: u64,
+        do_update_time: u64,
+        do_update_count: u64,
+        ec_update_time: u64,
+        ec_update_count: u64,
+        low_ph_ref: f64,
+        low_mv: f64,
+        hi_ph_ref: f64,
+        hi_mv: f64,
+        calibration_slope: f64,
+        calibration_offset: f64,
+        calibration_r_squared: f64,
+        calibration_slope_efficiency: f64,
+        calibration_time: u64,
+        calibration_slope_change: f64,
+        calibration_offset_change: f64,
+        quarantine_reason: String,
+        quarantine_reading: String,
+        quarantine_time: u64,
+        quarantine_count: u64,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tank_round_trip() {
+        let tank = TankRecord {
+            name: Some("The Mothership".to_string()),
+            temp_f: Some(81.61),
+            ph: Some(7.72),
+            ph_update_count: Some(2454127),
+            ..Default::default()
+        };
+
+        assert_eq!(TankRecord::from_rfields(&tank.to_rfields()), tank);
+        assert_eq!(TankRecord::from_hash_map(&tank.to_hash_map()), tank);
+    }
+
+    #[test]
+    fn unparseable_fields_are_missing() {
+        let mut map = HashMap::new();
+        map.insert("temp_f".to_string(), "warm".to_string());
+        map.insert("temp_c".to_string(), "27.56".to_string());
+
+        let tank = TankRecord::from_hash_map(&map);
+        assert_eq!(tank.temp_f, None);
+        assert_eq!(tank.temp_c, Some(27.56));
+    }
+
+    #[test]
+    fn oxygen_and_conductivity() {
+        let mut map = HashMap::new();
+        map.insert("do_mg_l".to_string(), "6.8".to_string());
+        map.insert("ec_us_cm".to_string(), "1180".to_string());
+        map.insert("do_update_time".to_string(), "1541082869".to_string());
+
+        let tank = TankRecord::from_hash_map(&map);
+        assert_eq!(tank.do_mg_l, Some(6.8));
+        assert_eq!(tank.do_saturation, None);
+        assert_eq!(tank.ec_us_cm, Some(1180.0));
+        assert_eq!(tank.do_update_time, Some(1541082869));
+        assert_eq!(TankRecord::from_hash_map(&tank.to_hash_map()), tank);
+    }
+
+    #[test]
+    fn from_hmget_values() {
+        let mut vals: Vec<Option<String>> = vec![None; SensorRecord::FIELDS.len()];
+        vals[0] = Some("2".to_string());
+        vals[2] = Some("28 ff 3a 9b 61 16 03 a1".to_string());
+
+        let sensor = SensorRecord::from_values(vals);
+        assert_eq!(sensor.tank, Some(2));
+        assert_eq!(sensor.area, None);
+        assert_eq!(
+            sensor.ext_device_id,
+            Some("28 ff 3a 9b 61 16 03 a1".to_string())
+        );
+    }
+
+    #[test]
+    fn json_skips_missing_fields() {
+        let area = AreaRecord {
+            humidity: Some(61.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&area).unwrap(),
+            r#"{"humidity":61.0}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<AreaRecord>(r#"{"humidity":61.0}"#).unwrap(),
+            area
+        );
+    }
+}
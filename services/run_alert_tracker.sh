@@ -0,0 +1,4 @@
+#!/bin/bash
+
+cd alert_tracker
+sh run.sh
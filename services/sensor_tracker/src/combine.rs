@@ -0,0 +1,275 @@
+use redis::Commands;
+use redis_context::RedisContext;
+use redis_delta::{Key, Namespace, SensorType};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::model::{Measurement, Value};
+use crate::probes;
+use crate::screening::median;
+
+/// How the readings of several sensors linked to the
+/// same tank or area are combined.
+///
+/// - `latest`: the most recent reading wins
+/// - `mean`, `median`: of each numeric field, across every sensor
+///   with a recent reading.  Other fields come from the latest.
+/// - `primary`: the reading of the sensor whose hash has
+///   `primary` set to `true`, unless it has gone quiet,
+///   in which case the latest reading is used
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    Latest,
+    Mean,
+    Median,
+    Primary,
+}
+
+/// - `policy`: see `Policy`
+/// - `max_age_secs`: readings older than this are left out,
+///   so that a dead probe doesn't hold the tank at its last value
+#[derive(Debug, Clone)]
+pub struct CombineConfig {
+    pub policy: Policy,
+    pub max_age_secs: u64,
+}
+
+pub const DEFAULT_POLICY: Policy = Policy::Latest;
+pub const DEFAULT_MAX_AGE_SECS: u64 = 600;
+
+/// A recent reading from one of the sensors linked to a tank or area.
+#[derive(Debug)]
+pub struct Contribution {
+    pub sensor: Uuid,
+    pub measure: Measurement,
+    pub time: u64,
+    pub primary: bool,
+}
+
+/// Finds the recent readings of every sensor of the same type which
+/// is linked to the container, e.g. `tank` 1.  `measure` is the reading
+/// which has just arrived from `sensor`, and is always included.
+///
+/// With the `latest` policy, there is no need to look any further.
+pub fn gather(
+    redis_ctx: &RedisContext,
+    link_field: &str,
+    container_num: u64,
+    measure: &Measurement,
+    sensor: Uuid,
+    now: u64,
+    config: &CombineConfig,
+) -> Result<Vec<Contribution>, redis::RedisError> {
+    let mut result = vec![];
+    if config.policy == Policy::Latest {
+        result.push(Contribution {
+            sensor,
+            measure: measure.clone(),
+            time: now,
+            primary: false,
+        });
+        return Ok(result);
+    }
+
+    let probe = match probes::find(measure.sensor_type) {
+        Some(probe) => probe,
+        None => return Ok(result),
+    };
+    let ns = Namespace(redis_ctx.namespace.to_string());
+    let st = SensorType(measure.name());
+    let update_time = format!("{}_update_time", measure.name());
+
+    let ids: Vec<String> = redis_ctx.conn.smembers(
+        Key::AllSensors {
+            ns: ns.clone(),
+            st: st.clone(),
+        }
+        .to_string(),
+    )?;
+    for id in ids.iter().filter_map(|id| Uuid::parse_str(id).ok()) {
+        let hash: HashMap<String, String> = redis_ctx.conn.hgetall(
+            Key::Sensor {
+                ns: ns.clone(),
+                st: st.clone(),
+                id,
+            }
+            .to_string(),
+        )?;
+        let primary = hash.get("primary").map(|p| p == "true").unwrap_or(false);
+
+        if id == sensor {
+            result.push(Contribution {
+                sensor,
+                measure: measure.clone(),
+                time: now,
+                primary,
+            });
+            continue;
+        }
+
+        let linked = hash.get(link_field) == Some(&container_num.to_string());
+        let time = hash.get(&update_time).and_then(|t| t.parse::<u64>().ok());
+        if let (true, Some(time)) = (linked, time) {
+            if now.saturating_sub(time) <= config.max_age_secs {
+                if let Some(m) = probe.read_hash(&hash) {
+                    result.push(Contribution {
+                        sensor: id,
+                        measure: m,
+                        time,
+                        primary,
+                    })
+                }
+            }
+        }
+    }
+
+    // the sensor which sent this reading may not
+    // have been added to its set yet
+    if !result.iter().any(|c| c.sensor == sensor) {
+        result.push(Contribution {
+            sensor,
+            measure: measure.clone(),
+            time: now,
+            primary: false,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Combines the readings, returning the IDs of
+/// the sensors which contributed to the result.
+pub fn combine(policy: Policy, contributions: &[Contribution]) -> Option<(Measurement, Vec<Uuid>)> {
+    let latest = contributions.iter().max_by_key(|c| c.time)?;
+    let alone = |c: &Contribution| Some((c.measure.clone(), vec![c.sensor]));
+
+    match policy {
+        Policy::Latest => alone(latest),
+        Policy::Primary => contributions
+            .iter()
+            .filter(|c| c.primary)
+            .max_by_key(|c| c.time)
+            .and_then(alone)
+            .or_else(|| alone(latest)),
+        Policy::Mean | Policy::Median => {
+            let mut combined = latest.measure.clone();
+            for (field, value) in &latest.measure.values {
+                if let Value::Number(_) = value {
+                    let numbers: Vec<f64> = contributions
+                        .iter()
+                        .filter_map(|c| c.measure.number(field))
+                        .collect();
+                    let n = if policy == Policy::Mean {
+                        mean(&numbers)
+                    } else {
+                        median(&numbers)
+                    };
+                    if let Some(n) = n {
+                        combined = combined.with(field, Value::Number(n));
+                    }
+                }
+            }
+            Some((combined, contributions.iter().map(|c| c.sensor).collect()))
+        }
+    }
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(n: u8) -> Uuid {
+        Uuid::from_bytes([n; 16])
+    }
+
+    fn contribution(n: u8, c: f64, time: u64, primary: bool) -> Contribution {
+        Contribution {
+            sensor: id(n),
+            measure: Measurement {
+                sensor_type: "temp",
+                values: vec![
+                    ("temp_c", Value::Number(c)),
+                    ("temp_f", Value::Number(80.0)),
+                ],
+            },
+            time,
+            primary,
+        }
+    }
+
+    fn combined(policy: Policy, contributions: &[Contribution]) -> (Option<f64>, Vec<Uuid>) {
+        let (measure, sensors) = combine(policy, contributions).unwrap();
+        (measure.number("temp_c"), sensors)
+    }
+
+    fn contributions() -> Vec<Contribution> {
+        vec![
+            contribution(1, 20.0, 100, false),
+            contribution(2, 30.0, 300, false),
+            contribution(3, 22.0, 200, true),
+        ]
+    }
+
+    #[test]
+    fn test_empty() {
+        for policy in &[
+            Policy::Latest,
+            Policy::Mean,
+            Policy::Median,
+            Policy::Primary,
+        ] {
+            assert!(combine(*policy, &[]).is_none())
+        }
+    }
+
+    #[test]
+    fn test_latest() {
+        assert_eq!(
+            combined(Policy::Latest, &contributions()),
+            (Some(30.0), vec![id(2)])
+        )
+    }
+
+    #[test]
+    fn test_mean() {
+        assert_eq!(
+            combined(Policy::Mean, &contributions()),
+            (Some(24.0), vec![id(1), id(2), id(3)])
+        )
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(
+            combined(Policy::Median, &contributions()),
+            (Some(22.0), vec![id(1), id(2), id(3)])
+        )
+    }
+
+    #[test]
+    fn test_primary() {
+        assert_eq!(
+            combined(Policy::Primary, &contributions()),
+            (Some(22.0), vec![id(3)])
+        )
+    }
+
+    #[test]
+    fn test_missing_primary() {
+        let mut contributions = contributions();
+        contributions.pop();
+        assert_eq!(
+            combined(Policy::Primary, &contributions),
+            (Some(30.0), vec![id(2)])
+        )
+    }
+}
@@ -1,3 +1,8 @@
+use crate::combine::{self, CombineConfig, Policy};
+use crate::history::{self, HistoryConfig};
+use crate::logging::LogFormat;
+use crate::ph::{self, PhConfig};
+use crate::screening::{self, ScreeningConfig};
 use redis_context::RedisContext;
 
 #[derive(Deserialize, Debug, Clone)]
@@ -12,6 +17,14 @@ pub struct TrackerConfig {
     pub mqtt_topic: String,
     pub mqtt_keep_alive: Option<u16>,
     pub mqtt_qos: Option<u8>,
+    pub history_retention_secs: Option<u64>,
+    pub history_resolution_secs: Option<u64>,
+    pub ph_recompute: Option<bool>,
+    pub ph_disagreement_threshold: Option<f64>,
+    pub spike_window: Option<usize>,
+    pub combine_policy: Option<Policy>,
+    pub combine_max_age_secs: Option<u64>,
+    pub log_format: Option<LogFormat>,
 }
 
 impl TrackerConfig {
@@ -30,4 +43,39 @@ impl TrackerConfig {
             self.redis_namespace.clone().unwrap_or("".to_string()),
         )
     }
+
+    pub fn to_history_config(&self) -> HistoryConfig {
+        HistoryConfig {
+            retention_secs: self
+                .history_retention_secs
+                .unwrap_or(history::DEFAULT_RETENTION_SECS),
+            resolution_secs: self
+                .history_resolution_secs
+                .unwrap_or(history::DEFAULT_RESOLUTION_SECS),
+        }
+    }
+
+    pub fn to_ph_config(&self) -> PhConfig {
+        PhConfig {
+            recompute: self.ph_recompute.unwrap_or(false),
+            disagreement_threshold: self
+                .ph_disagreement_threshold
+                .unwrap_or(ph::DEFAULT_DISAGREEMENT_THRESHOLD),
+        }
+    }
+
+    pub fn to_screening_config(&self) -> ScreeningConfig {
+        ScreeningConfig {
+            spike_window: self.spike_window.unwrap_or(screening::DEFAULT_SPIKE_WINDOW),
+        }
+    }
+
+    pub fn to_combine_config(&self) -> CombineConfig {
+        CombineConfig {
+            policy: self.combine_policy.unwrap_or(combine::DEFAULT_POLICY),
+            max_age_secs: self
+                .combine_max_age_secs
+                .unwrap_or(combine::DEFAULT_MAX_AGE_SECS),
+        }
+    }
 }
@@ -0,0 +1,53 @@
+use redis::Commands;
+use redis_context::RedisContext;
+use redis_delta::{Key, Namespace};
+
+use crate::logging;
+
+/// What became of the messages and readings which sensor_tracker
+/// received.  Each is a field of the counters hash, which can be
+/// read with `HGETALL <namespace>/sensor_tracker/counters`.
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    MessagesReceived,
+    /// Not UTF-8, or not JSON, or missing `device_id`
+    MessagesUndecodable,
+    /// Decoded, but without all the fields of any probe
+    MessagesWithoutReadings,
+    ReadingsApplied,
+    ReadingsQuarantined,
+    /// Redis refused the update
+    ReadingsFailed,
+    PublishFailed,
+}
+
+impl Counter {
+    pub fn field(self) -> &'static str {
+        match self {
+            Counter::MessagesReceived => "messages_received",
+            Counter::MessagesUndecodable => "messages_undecodable",
+            Counter::MessagesWithoutReadings => "messages_without_readings",
+            Counter::ReadingsApplied => "readings_applied",
+            Counter::ReadingsQuarantined => "readings_quarantined",
+            Counter::ReadingsFailed => "readings_failed",
+            Counter::PublishFailed => "publish_failed",
+        }
+    }
+}
+
+/// Counting is best effort.  If Redis is down, the
+/// reading which we're counting has failed, too,
+/// and will have been logged.
+pub fn incr(redis_ctx: &RedisContext, counter: Counter) {
+    let key = Key::TrackerCounters {
+        ns: Namespace(redis_ctx.namespace.to_string()),
+    }
+    .to_string();
+    let counted: Result<u64, _> = redis_ctx.conn.hincr(&key, counter.field(), 1);
+    if let Err(e) = counted {
+        logging::warn("couldn't count")
+            .with("counter", counter.field())
+            .with("error", format!("{:?}", e))
+            .log()
+    }
+}
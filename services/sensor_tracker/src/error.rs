@@ -0,0 +1,27 @@
+/// Anything which can go wrong between receiving a message
+/// and publishing the changes which it made to Redis.
+#[derive(Debug)]
+pub enum TrackerErr {
+    Redis(redis::RedisError),
+    Utf8(std::str::Utf8Error),
+    Json(serde_json::Error),
+    /// The `tank` or `area` of a sensor hash, which
+    /// doesn't fit in the u16 that tanks are numbered by.
+    ContainerNum(u64),
+}
+
+impl From<redis::RedisError> for TrackerErr {
+    fn from(error: redis::RedisError) -> Self {
+        TrackerErr::Redis(error)
+    }
+}
+impl From<std::str::Utf8Error> for TrackerErr {
+    fn from(error: std::str::Utf8Error) -> Self {
+        TrackerErr::Utf8(error)
+    }
+}
+impl From<serde_json::Error> for TrackerErr {
+    fn from(error: serde_json::Error) -> Self {
+        TrackerErr::Json(error)
+    }
+}
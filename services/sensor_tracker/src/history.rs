@@ -0,0 +1,92 @@
+use redis::PipelineCommands;
+use redis_context::RedisContext;
+use redis_delta::{Key, REvent};
+
+use crate::model::Measurement;
+
+/// Controls how much history we keep for each tank and area.
+///
+/// - `retention_secs`: readings older than this are trimmed away
+/// - `resolution_secs`: readings are downsampled into buckets of this
+///   width, and only the most recent reading in each bucket is kept
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    pub retention_secs: u64,
+    pub resolution_secs: u64,
+}
+
+/// Default to one week of history, one point per minute.
+pub const DEFAULT_RETENTION_SECS: u64 = 604_800;
+pub const DEFAULT_RESOLUTION_SECS: u64 = 60;
+
+/// The time series for an individual field of a tank or area hash,
+/// e.g. `prawnspace/tanks/1/history/temp_f`
+pub fn history_key(container: &Key, field: &str) -> String {
+    Key::History {
+        container: Box::new(container.clone()),
+        field: field.to_string(),
+    }
+    .to_string()
+}
+
+/// Appends each numeric field of the measurement to a redis sorted
+/// set associated with the tank or area.  The score of each member
+/// is the epoch time (in seconds) of the start of its bucket, and the
+/// member itself is formatted as `<bucket_time>:<value>`, so that
+/// identical values recorded at different times don't collide.
+///
+/// Each time series expires once nothing has been recorded to it
+/// for the retention period, e.g. after its tank is retired.
+///
+/// Returns events for each time series which was written, so that
+/// the new members and the TTL can be replicated.
+pub fn record(
+    redis_ctx: &RedisContext,
+    container: &Key,
+    measure: &Measurement,
+    time: u64,
+    config: &HistoryConfig,
+) -> Result<Vec<REvent>, redis::RedisError> {
+    let resolution = if config.resolution_secs == 0 {
+        1
+    } else {
+        config.resolution_secs
+    };
+    let bucket = time - time % resolution;
+    let expired = time.saturating_sub(config.retention_secs);
+    // the newest bucket may have started a little while ago
+    let ttl = config.retention_secs.saturating_add(resolution);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    let mut keys: Vec<String> = vec![];
+    for (field, val) in measure.to_redis() {
+        if let Ok(v) = val.parse::<f64>() {
+            let key = history_key(container, field);
+            pipe.zrembyscore(&key, bucket, bucket)
+                .ignore()
+                .zadd(&key, format!("{}:{}", bucket, v), bucket)
+                .ignore()
+                .zrembyscore(&key, "-inf", format!("({}", expired))
+                .ignore()
+                .expire(&key, ttl as usize)
+                .ignore();
+            keys.push(key);
+        }
+    }
+
+    if !keys.is_empty() {
+        pipe.query::<()>(&redis_ctx.conn)?;
+    }
+
+    let mut events = vec![];
+    for key in keys {
+        events.push(REvent::SortedSetUpdated {
+            key: key.clone(),
+            min_score: bucket,
+        });
+        events.push(REvent::ExpireSet { key });
+    }
+    Ok(events)
+}
@@ -0,0 +1,155 @@
+//! Log lines carry fields, such as the device, tank and measurement
+//! they concern, alongside their message.  By default they're written
+//! as text, e.g.
+//!
+//! ```text
+//! WARN  reading quarantined device_id=28654597090000e4 sensor_type=temp reason="temp_c -127 is outside of -10 to 50"
+//! ```
+//!
+//! With `LOG_FORMAT=json`, each line is a JSON object instead,
+//! which is easier to search once it's been shipped off the Pi.
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub const DEFAULT_FORMAT: LogFormat = LogFormat::Text;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Call once at startup, before anything is logged.
+pub fn init(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// A log line which is being built up.  Nothing
+/// is written until `log` is called.
+#[derive(Debug)]
+pub struct Entry {
+    level: Level,
+    message: String,
+    fields: Map<String, Value>,
+}
+
+pub fn info(message: &str) -> Entry {
+    Entry::new(Level::Info, message)
+}
+
+pub fn warn(message: &str) -> Entry {
+    Entry::new(Level::Warn, message)
+}
+
+pub fn error(message: &str) -> Entry {
+    Entry::new(Level::Error, message)
+}
+
+impl Entry {
+    fn new(level: Level, message: &str) -> Entry {
+        Entry {
+            level,
+            message: message.to_string(),
+            fields: Map::new(),
+        }
+    }
+
+    pub fn with<V: Into<Value>>(mut self, key: &str, value: V) -> Entry {
+        self.fields.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn log(self) {
+        if JSON.load(Ordering::Relaxed) {
+            println!("{}", self.to_json())
+        } else {
+            println!("{}", self.to_text())
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut line = Map::new();
+        line.insert("time".to_string(), epoch_secs().into());
+        line.insert("level".to_string(), self.level.name().into());
+        line.insert("msg".to_string(), self.message.clone().into());
+        line.extend(self.fields.clone());
+        Value::Object(line)
+    }
+
+    fn to_text(&self) -> String {
+        let mut line = format!("{:<5} {}", self.level.name().to_uppercase(), self.message);
+        for (key, value) in &self.fields {
+            match value {
+                Value::String(s) if !s.contains(' ') && !s.is_empty() => {
+                    line.push_str(&format!(" {}={}", key, s))
+                }
+                other => line.push_str(&format!(" {}={}", key, other)),
+            }
+        }
+        line
+    }
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry() -> Entry {
+        warn("reading quarantined")
+            .with("device_id", "28654597090000e4")
+            .with("reason", "temp_c -127 is outside of -10 to 50")
+            .with("tank", 1)
+    }
+
+    #[test]
+    fn test_to_text() {
+        assert_eq!(
+            entry().to_text(),
+            r#"WARN  reading quarantined device_id=28654597090000e4 reason="temp_c -127 is outside of -10 to 50" tank=1"#
+        );
+        assert_eq!(
+            info("started").with("topic", "").to_text(),
+            r#"INFO  started topic="""#
+        )
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = entry().to_json();
+        assert!(json["time"].is_u64());
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["msg"], "reading quarantined");
+        assert_eq!(json["device_id"], "28654597090000e4");
+        assert_eq!(json["reason"], "temp_c -127 is outside of -10 to 50");
+        assert_eq!(json["tank"], 1);
+        assert_eq!(json.as_object().unwrap().len(), 6)
+    }
+}
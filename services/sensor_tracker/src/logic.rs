@@ -2,44 +2,144 @@ use crossbeam_channel::{select, Receiver};
 use redis_context::RedisContext;
 use rumqtt::Notification;
 
+use crate::combine::CombineConfig;
+use crate::counters::{self, Counter};
+use crate::error::TrackerErr;
+use crate::history::HistoryConfig;
+use crate::logging;
 use crate::model::SensorMessage;
+use crate::ph::PhConfig;
 use crate::predis;
+use crate::screening::{Screen, ScreeningConfig};
 
 pub fn receive_updates(
     update_r: Receiver<Notification>,
     redis_ctx: &RedisContext,
     delta_event_topic: &str,
+    history_config: &HistoryConfig,
+    ph_config: &PhConfig,
+    combine_config: &CombineConfig,
+    screening_config: ScreeningConfig,
 ) {
+    let mut screen = Screen::new(screening_config);
     loop {
         select! {
             recv(update_r) -> msg => match msg {
-                Ok(Notification::Publish(p)) => {
-                    let payload = p.payload;
-                    if let Some(sensor_message) = deser_message(&payload) {
-                        let ext_device_id: &str = &sensor_message.device_id;
-
-                        sensor_message.measurements().iter().for_each(|measure| {
-                            if let Ok(delta_events) = predis::update(redis_ctx, &measure, ext_device_id)
-                            {
-                                // emit all changed keys & hash field names to redis
-                                // on the appropriate redis pub/sub topic.
-                                // these will be processed later by the gcloud_push utility
-                                predis::publish_updates(redis_ctx, delta_event_topic, delta_events)
-                            }
-                        });
-                    } else {
-                        println!("couldnt deserialize message payload: {:?}", payload)
-                    }
-                },
-                Ok(n) => println!("IGNORE  {:?}", n),
-                Err(e) => println!("ERROR    {:?}", e),
+                Ok(Notification::Publish(p)) => handle_message(
+                    &p.payload,
+                    redis_ctx,
+                    delta_event_topic,
+                    history_config,
+                    ph_config,
+                    combine_config,
+                    &mut screen,
+                ),
+                Ok(n) => logging::info("ignored notification")
+                    .with("notification", format!("{:?}", n))
+                    .log(),
+                Err(e) => logging::error("couldn't receive from MQTT")
+                    .with("error", format!("{:?}", e))
+                    .log(),
             }
         }
     }
 }
 
-fn deser_message(payload: &[u8]) -> Option<SensorMessage> {
-    let r = std::str::from_utf8(&payload);
-    r.ok()
-        .and_then(|s| serde_json::from_str(s).map(|r| Some(r)).unwrap_or(None))
+/// Screens and applies each reading in the message.  A reading
+/// which fails doesn't stop the rest of the message from being applied.
+/// pH is recomputed, if need be, before it's screened.
+fn handle_message(
+    payload: &[u8],
+    redis_ctx: &RedisContext,
+    delta_event_topic: &str,
+    history_config: &HistoryConfig,
+    ph_config: &PhConfig,
+    combine_config: &CombineConfig,
+    screen: &mut Screen,
+) {
+    counters::incr(redis_ctx, Counter::MessagesReceived);
+
+    let sensor_message = match deser_message(payload) {
+        Ok(sensor_message) => sensor_message,
+        Err(e) => {
+            counters::incr(redis_ctx, Counter::MessagesUndecodable);
+            logging::warn("couldn't decode message")
+                .with("error", format!("{:?}", e))
+                .with("payload", String::from_utf8_lossy(payload).to_string())
+                .log();
+            return;
+        }
+    };
+    let ext_device_id: &str = &sensor_message.device_id;
+
+    let measurements = sensor_message.measurements();
+    if measurements.is_empty() {
+        counters::incr(redis_ctx, Counter::MessagesWithoutReadings);
+        logging::warn("no readings in message")
+            .with("device_id", ext_device_id)
+            .with(
+                "fields",
+                sensor_message
+                    .readings
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+            .log();
+    }
+
+    for measure in &measurements {
+        let recomputed = match predis::recompute_ph(redis_ctx, measure, ext_device_id, ph_config) {
+            Ok(recomputed) => recomputed,
+            Err(e) => {
+                logging::warn("couldn't recompute pH")
+                    .with("device_id", ext_device_id)
+                    .with("error", format!("{:?}", e))
+                    .log();
+                None
+            }
+        };
+        let measure = recomputed.as_ref().unwrap_or(measure);
+
+        let (updated, counter) = match screen.check(ext_device_id, measure) {
+            Ok(()) => (
+                predis::update(
+                    redis_ctx,
+                    measure,
+                    ext_device_id,
+                    history_config,
+                    combine_config,
+                ),
+                Counter::ReadingsApplied,
+            ),
+            Err(reason) => (
+                predis::quarantine(redis_ctx, measure, ext_device_id, &reason),
+                Counter::ReadingsQuarantined,
+            ),
+        };
+
+        match updated {
+            Ok(delta_events) => {
+                counters::incr(redis_ctx, counter);
+                // emit all changed keys & hash field names to redis
+                // on the appropriate redis pub/sub topic.
+                // these will be processed later by the gcloud_push utility
+                predis::publish_updates(redis_ctx, delta_event_topic, delta_events)
+            }
+            Err(e) => {
+                counters::incr(redis_ctx, Counter::ReadingsFailed);
+                logging::error("couldn't apply reading")
+                    .with("device_id", ext_device_id)
+                    .with("sensor_type", measure.name())
+                    .with("error", format!("{:?}", e))
+                    .log()
+            }
+        }
+    }
+}
+
+fn deser_message(payload: &[u8]) -> Result<SensorMessage, TrackerErr> {
+    let s = std::str::from_utf8(payload)?;
+    Ok(serde_json::from_str(s)?)
 }
@@ -10,11 +10,19 @@ extern crate rumqtt;
 extern crate serde_derive;
 extern crate uuid;
 
+mod combine;
 mod config;
+mod counters;
+mod error;
+mod history;
+mod logging;
 mod logic;
 mod model;
+mod ph;
 mod prawnqtt;
 mod predis;
+mod probes;
+mod screening;
 
 fn main() {
     dotenv::dotenv().expect("Unable to load .env file");
@@ -22,6 +30,8 @@ fn main() {
     let config = config::TrackerConfig::new();
     let config_clone = config.clone();
 
+    logging::init(config.log_format.unwrap_or(logging::DEFAULT_FORMAT));
+
     let rx = prawnqtt::start_mqtt(&config);
 
     let redis_ctx = &config_clone.to_redis_context();
@@ -29,5 +39,25 @@ fn main() {
         .redis_delta_event_topic
         .unwrap_or(format!("{}/system/delta_events", &redis_ctx.namespace));
 
-    logic::receive_updates(rx, redis_ctx, &delta_event_topic)
+    let history_config = config_clone.to_history_config();
+    let ph_config = config_clone.to_ph_config();
+    let screening_config = config_clone.to_screening_config();
+    let combine_config = config_clone.to_combine_config();
+
+    for probe in probes::PROBES {
+        logging::info("probe")
+            .with("sensor_type", probe.name())
+            .with("fields", probes::describe(*probe))
+            .log();
+    }
+
+    logic::receive_updates(
+        rx,
+        redis_ctx,
+        &delta_event_topic,
+        &history_config,
+        &ph_config,
+        &combine_config,
+        screening_config,
+    )
 }
@@ -1,122 +1,199 @@
+use crate::probes::{self, ValueKind, PROBES};
+use std::collections::HashMap;
+
 /// This message is emitted to an MQTT channel by
-/// some device with access to a temp sensor (DS18B20, etc)
+/// some device with access to one or more probes
+/// (DS18B20, DHT11, pH, etc).  Which fields it carries
+/// depends on the probes; see `probes`.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SensorMessage {
     pub device_id: String,
-    pub temp_f: Option<f64>,
-    pub temp_c: Option<f64>,
-    pub ph: Option<f64>,
-    pub ph_mv: Option<f64>,
-    pub status: Option<String>,
-    pub humidity: Option<f64>,
-    pub heat_index_c: Option<f64>,
-    pub heat_index_f: Option<f64>,
+    #[serde(flatten)]
+    pub readings: HashMap<String, serde_json::Value>,
 }
 
 /// `external_device_id` is usually reported as a
 /// e.g. "28654597090000e4"
 impl SensorMessage {
     pub fn measurements(&self) -> Vec<Measurement> {
-        let mut v: Vec<Measurement> = vec![];
-        if let (
-            Some(humidity),
-            Some(status),
-            Some(temp_f),
-            Some(temp_c),
-            Some(heat_index_f),
-            Some(heat_index_c),
-        ) = (
-            self.humidity,
-            &self.status,
-            self.temp_f,
-            self.temp_c,
-            self.heat_index_f,
-            self.heat_index_c,
-        ) {
-            v.push(Measurement::DHT {
-                status: status.to_owned(),
-                humidity,
-                temp_f,
-                temp_c,
-                heat_index_f,
-                heat_index_c,
-            })
-        } else if let (Some(temp_f), Some(temp_c)) = (self.temp_f, self.temp_c) {
-            v.push(Measurement::Temp { temp_f, temp_c })
+        let found: Vec<Measurement> = PROBES
+            .iter()
+            .filter_map(|p| p.read(&self.readings))
+            .collect();
+
+        let superseded: Vec<&str> = found
+            .iter()
+            .filter_map(|m| probes::find(m.sensor_type))
+            .flat_map(|p| p.supersedes().iter().cloned())
+            .collect();
+
+        found
+            .into_iter()
+            .filter(|m| !superseded.contains(&m.sensor_type))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+}
+
+impl Value {
+    pub fn from_json(json: &serde_json::Value, kind: ValueKind) -> Option<Value> {
+        match kind {
+            ValueKind::Number => json.as_f64().map(Value::Number),
+            ValueKind::Text => json.as_str().map(|s| Value::Text(s.to_string())),
+            ValueKind::Bool => json.as_bool().map(Value::Bool),
         }
+    }
 
-        if let (Some(ph), Some(ph_mv)) = (self.ph, self.ph_mv) {
-            v.push(Measurement::PH { ph, ph_mv })
+    /// Parses a value as it's stored in a Redis hash.
+    pub fn parse(s: &str, kind: ValueKind) -> Option<Value> {
+        match kind {
+            ValueKind::Number => s.parse::<f64>().ok().map(Value::Number),
+            ValueKind::Text => Some(Value::Text(s.to_string())),
+            ValueKind::Bool => s.parse::<bool>().ok().map(Value::Bool),
         }
+    }
 
-        v
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Number(n) => serde_json::Number::from_f64(*n)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Value::Text(s) => serde_json::Value::String(s.to_string()),
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum Measurement {
-    Temp {
-        temp_f: f64,
-        temp_c: f64,
-    },
-    PH {
-        ph: f64,
-        ph_mv: f64,
-    },
-    /// Digital humidity and temp, e.g. DHT11 sensor
-    DHT {
-        status: String,
-        humidity: f64,
-        temp_f: f64,
-        temp_c: f64,
-        heat_index_f: f64,
-        heat_index_c: f64,
-    },
+/// The readings of a single probe, named by the fields
+/// which the probe declares.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub sensor_type: &'static str,
+    pub values: Vec<(&'static str, Value)>,
 }
 
 impl Measurement {
     pub fn name(&self) -> String {
-        match self {
-            Measurement::Temp {
-                temp_f: _,
-                temp_c: _,
-            } => "temp".to_string(),
-            Measurement::PH { ph: _, ph_mv: _ } => "ph".to_string(),
-            Measurement::DHT {
-                status: _,
-                humidity: _,
-                temp_f: _,
-                temp_c: _,
-                heat_index_f: _,
-                heat_index_c: _,
-            } => "dht".to_string(),
+        self.sensor_type.to_string()
+    }
+
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.values
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map(|(_, v)| v)
+    }
+
+    pub fn number(&self, field: &str) -> Option<f64> {
+        match self.get(field) {
+            Some(Value::Number(n)) => Some(*n),
+            _ => None,
         }
     }
 
-    pub fn to_redis(&self) -> Vec<(&str, String)> {
-        match self {
-            Measurement::Temp { temp_f, temp_c } => vec![
-                ("temp_f", temp_f.to_string()),
-                ("temp_c", temp_c.to_string()),
-            ],
-            Measurement::PH { ph, ph_mv } => {
-                vec![("ph", ph.to_string()), ("ph_mv", ph_mv.to_string())]
-            }
-            Measurement::DHT {
-                status,
-                humidity,
-                temp_f,
-                temp_c,
-                heat_index_f,
-                heat_index_c,
-            } => vec![
-                ("status", status.to_string()),
-                ("humidity", humidity.to_string()),
-                ("temp_f", temp_f.to_string()),
-                ("temp_c", temp_c.to_string()),
-                ("heat_index_f", heat_index_f.to_string()),
-                ("heat_index_c", heat_index_c.to_string()),
-            ],
+    /// Replaces the value of a field, or adds it.
+    pub fn with(mut self, field: &'static str, value: Value) -> Measurement {
+        match self.values.iter_mut().find(|(f, _)| *f == field) {
+            Some(existing) => existing.1 = value,
+            None => self.values.push((field, value)),
+        }
+        self
+    }
+
+    /// The fields as a JSON object, e.g. `{"temp_f":81.4,"temp_c":27.4}`
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.values
+                .iter()
+                .map(|(f, v)| (f.to_string(), v.to_json()))
+                .collect(),
+        )
+    }
+
+    /// The fields written to the sensor hash, and to the
+    /// hash of its tank or area.
+    pub fn to_redis(&self) -> Vec<(&'static str, String)> {
+        self.values
+            .iter()
+            .map(|(f, v)| (*f, v.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(json: &str) -> SensorMessage {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_flatten() {
+        let m = message(r#"{"device_id":"aa","temp_f":81.7,"temp_c":27.6,"ph":7.8,"ph_mv":453.0}"#);
+        assert_eq!(m.device_id, "aa");
+        assert_eq!(m.readings.len(), 4);
+        assert!(!m.readings.contains_key("device_id"));
+
+        let measurements = m.measurements();
+        let types: Vec<&str> = measurements.iter().map(|m| m.sensor_type).collect();
+        assert_eq!(types, vec!["temp", "ph"]);
+        assert_eq!(measurements[0].number("temp_c"), Some(27.6));
+        assert_eq!(measurements[1].number("ph_mv"), Some(453.0));
+    }
+
+    #[test]
+    fn test_dht_supersedes_temp() {
+        let m = message(
+            r#"{"device_id":"aa","status":"ok","humidity":40.0,"temp_f":70.0,"temp_c":21.1,"heat_index_f":69.0,"heat_index_c":20.6}"#,
+        );
+        let measurements = m.measurements();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].sensor_type, "dht");
+        assert_eq!(measurements[0].number("temp_c"), Some(21.1))
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        assert!(message(r#"{"device_id":"aa","temp_f":81.7}"#)
+            .measurements()
+            .is_empty());
+        assert!(message(r#"{"device_id":"aa","humidity":"damp"}"#)
+            .measurements()
+            .is_empty())
+    }
+
+    #[test]
+    fn test_measurement_json() {
+        let m = Measurement {
+            sensor_type: "temp",
+            values: vec![("temp_f", Value::Number(81.5))],
         }
+        .with("temp_c", Value::Number(27.5))
+        .with("temp_f", Value::Number(81.6));
+        assert_eq!(m.to_json().to_string(), r#"{"temp_c":27.5,"temp_f":81.6}"#);
+        assert_eq!(
+            m.to_redis(),
+            vec![
+                ("temp_f", "81.6".to_string()),
+                ("temp_c", "27.5".to_string())
+            ]
+        )
     }
 }
@@ -0,0 +1,70 @@
+use redis::Commands;
+use redis_context::RedisContext;
+
+use crate::logging;
+use crate::model::{Measurement, Value};
+use crate::probes;
+
+/// Controls whether we trust the pH which a device reports,
+/// or work it out ourselves from the raw millivolts.
+///
+/// - `recompute`: use the calibration stored on the sensor hash
+///   (see ph_ref_calibration) to compute pH from `ph_mv`
+/// - `disagreement_threshold`: when the device and the server
+///   differ by more than this, the reading is flagged
+#[derive(Debug, Clone)]
+pub struct PhConfig {
+    pub recompute: bool,
+    pub disagreement_threshold: f64,
+}
+
+pub const DEFAULT_DISAGREEMENT_THRESHOLD: f64 = 0.2;
+
+/// If we have a calibration for this sensor, replaces the device's
+/// pH with one computed from its millivolt reading, keeping the
+/// device's value alongside for comparison.  Anything which isn't a
+/// pH measurement, or which has no calibration, is left as it is.
+pub fn recompute(
+    redis_ctx: &RedisContext,
+    sensor_hash_key: &str,
+    measure: &Measurement,
+    config: &PhConfig,
+) -> Option<Measurement> {
+    if !config.recompute {
+        return None;
+    }
+
+    if measure.sensor_type != probes::ph::NAME {
+        return None;
+    }
+
+    if let (Some(ph_device), Some(ph_mv)) = (measure.number("ph"), measure.number("ph_mv")) {
+        let fit: Result<Vec<Option<f64>>, _> = redis_ctx.conn.hget(
+            sensor_hash_key,
+            vec!["calibration_slope", "calibration_offset"],
+        );
+        if let Ok(fit) = fit {
+            if let (Some(Some(slope)), Some(Some(offset))) = (fit.get(0), fit.get(1)) {
+                let ph = slope * ph_mv + offset;
+                let disagrees = (ph - ph_device).abs() > config.disagreement_threshold;
+                if disagrees {
+                    logging::warn("pH disagreement")
+                        .with("key", sensor_hash_key)
+                        .with("ph_device", ph_device)
+                        .with("ph", ph)
+                        .log()
+                }
+
+                return Some(
+                    measure
+                        .clone()
+                        .with("ph", Value::Number(ph))
+                        .with("ph_device", Value::Number(ph_device))
+                        .with("ph_disagreement", Value::Bool(disagrees)),
+                );
+            }
+        }
+    }
+
+    None
+}
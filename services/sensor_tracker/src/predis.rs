@@ -1,108 +1,240 @@
-use redis::Commands;
+use redis::{Commands, PipelineCommands};
 
 use super::model;
+use crate::combine::{self, CombineConfig};
+use crate::counters::{self, Counter};
+use crate::error::TrackerErr;
+use crate::history::{self, HistoryConfig};
+use crate::logging;
+use crate::ph::{self, PhConfig};
 use redis_context::RedisContext;
-use redis_delta::REvent;
+use redis_delta::{Envelope, Key, Namespace, REvent, SensorType};
 use serde_json;
+use std::convert::TryFrom;
 use std::time::SystemTime;
 use uuid::Uuid;
 
 /// Updates redis so that the individual measurement is applied to the correct tank.
 /// Also records the measurement to a record associated with the sensor itself.
 /// Keeps track of how many updates have been applied to each tank and sensor record.
+/// Appends the measurement to the time series kept for the tank or area.
+/// Combines the reading with those of other sensors linked to the same tank or area.
 /// Will create a new sensor record for this device if one does not already exist.
 pub fn update<'a, 'b>(
     redis_ctx: &RedisContext,
     measure: &model::Measurement,
     ext_device_id: &str,
-) -> Result<Vec<REvent>, redis::RedisError> {
+    history_config: &HistoryConfig,
+    combine_config: &CombineConfig,
+) -> Result<Vec<REvent>, TrackerErr> {
     let mut delta_events: Vec<REvent> = vec![];
 
-    println!("Received redis {} update: {:?}", measure.name(), measure);
-
-    let ext_device_namespace = &redis_ctx.get_external_device_namespace(measure.name())?;
-    let device_id = internal_device_id(ext_device_id, ext_device_namespace).unwrap();
-
-    println!("\tDevice ID (internal): {}", device_id);
+    let device_id = sensor_id(redis_ctx, measure, ext_device_id)?;
     let rn = &redis_ctx.namespace;
 
-    let sensor_set_event = update_sensor_set(redis_ctx, rn, measure, device_id);
+    let sensor_set_event = update_sensor_set(redis_ctx, rn, measure, device_id)?;
     if let Some(e) = sensor_set_event {
         delta_events.push(e)
     }
 
     // lookup associated tank
-    let sensor_hash_key = &format!("{}/sensors/{}/{}", rn, measure.name(), device_id).to_string();
+    let sensor_hash_key = &sensor_key(rn, measure, device_id);
 
-    let tank_and_area_and_update_count: Result<Vec<Option<u64>>, _> = redis_ctx.conn.hget(
+    let v: Vec<Option<u64>> = redis_ctx.conn.hget(
         sensor_hash_key,
         vec!["tank", "area", &format!("{}_update_count", measure.name())],
-    );
-
-    if let Ok(v) = tank_and_area_and_update_count {
-        // Tank associated with this sensor?
-        let revent = match (v.get(0).unwrap_or(&None), v.get(1).unwrap_or(&None)) {
-            (Some(tank_num), _) => {
-                update_container_hash(redis_ctx, Container::Tanks, tank_num, &measure)
-            }
-            (_, Some(area_num)) => {
-                update_container_hash(redis_ctx, Container::Areas, area_num, &measure)
-            }
-            (None, None) => ensure_sensor_hash_exists(redis_ctx, sensor_hash_key, ext_device_id),
-        };
-
-        if let Some(ev) = revent {
-            delta_events.push(ev)
+    )?;
+
+    // Tank associated with this sensor?
+    let entry = logging::info("applying reading")
+        .with("device_id", ext_device_id)
+        .with("sensor_type", measure.name());
+    let revents: Vec<REvent> = match (v.get(0).unwrap_or(&None), v.get(1).unwrap_or(&None)) {
+        (Some(tank_num), _) => {
+            entry.with("tank", *tank_num).log();
+            update_container_hash(
+                redis_ctx,
+                Container::Tanks,
+                tank_num,
+                &measure,
+                device_id,
+                history_config,
+                combine_config,
+            )?
         }
-
-        // record a hit on the updates that the sensor has seen
-        // and also record the most recent measurement on the record
-        // for this individual sensor
-        let sensor_updated = update_sensor_hash(
-            redis_ctx,
-            sensor_hash_key,
-            measure,
-            v.get(2).unwrap_or(&None),
-        );
-
-        if let Some(ev) = sensor_updated {
-            delta_events.push(ev)
+        (_, Some(area_num)) => {
+            entry.with("area", *area_num).log();
+            update_container_hash(
+                redis_ctx,
+                Container::Areas,
+                area_num,
+                &measure,
+                device_id,
+                history_config,
+                combine_config,
+            )?
+        }
+        (None, None) => {
+            // not much use until someone links it
+            entry.with("sensor", device_id.to_string()).log();
+            ensure_sensor_hash_exists(redis_ctx, sensor_hash_key, ext_device_id)?
+                .into_iter()
+                .collect()
         }
     };
 
+    delta_events.extend(revents);
+
+    // record a hit on the updates that the sensor has seen
+    // and also record the most recent measurement on the record
+    // for this individual sensor
+    delta_events.push(update_sensor_hash(
+        redis_ctx,
+        sensor_hash_key,
+        measure,
+        v.get(2).unwrap_or(&None),
+    )?);
+
+    Ok(delta_events)
+}
+
+/// Keeps a reading which failed screening on the sensor's hash,
+/// along with the reason it was rejected.  The tank or area which
+/// the sensor serves is left alone.
+pub fn quarantine(
+    redis_ctx: &RedisContext,
+    measure: &model::Measurement,
+    ext_device_id: &str,
+    reason: &str,
+) -> Result<Vec<REvent>, TrackerErr> {
+    let mut delta_events: Vec<REvent> = vec![];
+
+    logging::warn("reading quarantined")
+        .with("device_id", ext_device_id)
+        .with("sensor_type", measure.name())
+        .with("reason", reason)
+        .with("reading", measure.to_json())
+        .log();
+
+    let device_id = sensor_id(redis_ctx, measure, ext_device_id)?;
+    let rn = &redis_ctx.namespace;
+
+    if let Some(e) = update_sensor_set(redis_ctx, rn, measure, device_id)? {
+        delta_events.push(e)
+    }
+
+    let sensor_hash_key = &sensor_key(rn, measure, device_id);
+
+    if let Some(e) = ensure_sensor_hash_exists(redis_ctx, sensor_hash_key, ext_device_id)? {
+        delta_events.push(e)
+    }
+
+    let data = [
+        ("quarantine_reason", reason.to_string()),
+        ("quarantine_reading", measure.to_json().to_string()),
+        ("quarantine_time", epoch_secs().to_string()),
+    ];
+
+    redis::pipe()
+        .atomic()
+        .hset_multiple(sensor_hash_key, &data[..])
+        .ignore()
+        .hincr(sensor_hash_key, "quarantine_count", 1)
+        .ignore()
+        .query::<()>(&redis_ctx.conn)?;
+
+    let mut fields: Vec<String> = data.iter().map(|(f, _)| f.to_string()).collect();
+    fields.push("quarantine_count".to_string());
+    delta_events.push(REvent::HashUpdated {
+        key: sensor_hash_key.to_string(),
+        fields,
+    });
+
     Ok(delta_events)
 }
 
+/// If we have a calibration for this pH sensor, replaces the pH which
+/// the device reported with one computed on the server.  This is done
+/// before screening, so that the value screened is the one we store.
+pub fn recompute_ph(
+    redis_ctx: &RedisContext,
+    measure: &model::Measurement,
+    ext_device_id: &str,
+    ph_config: &PhConfig,
+) -> Result<Option<model::Measurement>, TrackerErr> {
+    let device_id = sensor_id(redis_ctx, measure, ext_device_id)?;
+    let sensor_hash_key = sensor_key(&redis_ctx.namespace, measure, device_id);
+    Ok(ph::recompute(
+        redis_ctx,
+        &sensor_hash_key,
+        measure,
+        ph_config,
+    ))
+}
+
+fn sensor_id(
+    redis_ctx: &RedisContext,
+    measure: &model::Measurement,
+    ext_device_id: &str,
+) -> Result<Uuid, TrackerErr> {
+    let ext_device_namespace = &redis_ctx.get_external_device_namespace(measure.name())?;
+    Ok(internal_device_id(ext_device_id, ext_device_namespace))
+}
+
+fn sensor_key(rn: &str, measure: &model::Measurement, device_id: Uuid) -> String {
+    Key::Sensor {
+        ns: Namespace(rn.to_string()),
+        st: SensorType(measure.name()),
+        id: device_id,
+    }
+    .to_string()
+}
+
 fn update_sensor_set(
     redis_ctx: &RedisContext,
     rn: &str,
     measure: &model::Measurement,
     device_id: Uuid,
-) -> Option<REvent> {
-    let set_sensor_type_key = format!("{}/sensors/{}", rn, measure.name());
+) -> Result<Option<REvent>, TrackerErr> {
+    let set_sensor_type_key = Key::AllSensors {
+        ns: Namespace(rn.to_string()),
+        st: SensorType(measure.name()),
+    }
+    .to_string();
     // add to the member set if it doesn't already exist
-    let sensors_added: Result<u64, _> = redis_ctx
+    let sensors_added: u64 = redis_ctx
         .conn
-        .sadd(&set_sensor_type_key, &format!("{}", device_id));
+        .sadd(&set_sensor_type_key, &format!("{}", device_id))?;
 
-    match sensors_added {
-        Ok(n) if n > 0 => Some(REvent::SetUpdated {
+    if sensors_added > 0 {
+        Ok(Some(REvent::SetUpdated {
             key: set_sensor_type_key,
-        }),
-        _ => None,
+        }))
+    } else {
+        Ok(None)
     }
 }
 
+#[derive(Clone, Copy)]
 enum Container {
     Tanks,
     Areas,
 }
 
 impl Container {
-    pub fn to_string(self) -> String {
+    /// The field of the sensor hash which links it to the container.
+    pub fn link_field(self) -> &'static str {
         match self {
-            Container::Tanks => "tanks".to_string(),
-            Container::Areas => "areas".to_string(),
+            Container::Tanks => "tank",
+            Container::Areas => "area",
+        }
+    }
+
+    pub fn key(self, namespace: &str, id: u16) -> Key {
+        let ns = Namespace(namespace.to_string());
+        match self {
+            Container::Tanks => Key::Tank { ns, id },
+            Container::Areas => Key::Area { ns, id },
         }
     }
 }
@@ -112,95 +244,133 @@ fn update_container_hash(
     container: Container,
     container_num: &u64,
     measure: &model::Measurement,
-) -> Option<REvent> {
+    device_id: Uuid,
+    history_config: &HistoryConfig,
+    combine_config: &CombineConfig,
+) -> Result<Vec<REvent>, TrackerErr> {
+    // tanks and areas are numbered with a u16, so anything
+    // bigger must be a mistake, rather than another tank
+    let container_id =
+        u16::try_from(*container_num).map_err(|_| TrackerErr::ContainerNum(*container_num))?;
+    let now = epoch_secs();
+
+    // Other sensors of the same type may serve this
+    // tank or area, too.  Their readings are combined
+    // with this one according to the policy.
+    let (measure, sensors) = match combine::gather(
+        redis_ctx,
+        container.link_field(),
+        *container_num,
+        measure,
+        device_id,
+        now,
+        combine_config,
+    )
+    .map(|contributions| combine::combine(combine_config.policy, &contributions))
+    {
+        Ok(Some(combined)) => combined,
+        Ok(None) => (measure.clone(), vec![device_id]),
+        Err(e) => {
+            logging::warn("couldn't combine readings")
+                .with("sensor", device_id.to_string())
+                .with("sensor_type", measure.name())
+                .with("error", format!("{:?}", e))
+                .log();
+            (measure.clone(), vec![device_id])
+        }
+    };
+    let measure = &measure;
+
     // We found the area associated with this
     // sensor ID, so we should update that area's
     // current reading.
-    let container_key = format!(
-        "{}/{}/{}",
-        redis_ctx.namespace,
-        container.to_string(),
-        container_num
-    );
-
-    let container_measure_count: Result<Option<u32>, _> = redis_ctx
+    let container = container.key(&redis_ctx.namespace, container_id);
+    let container_key = container.to_string();
+
+    let container_measure_count: Option<u32> = redis_ctx
         .conn
-        .hget(&container_key, &format!("{}_update_count", measure.name()));
+        .hget(&container_key, &format!("{}_update_count", measure.name()))?;
 
     let uc_name = format!("{}_update_count", measure.name());
     let ut_name = format!("{}_update_time", measure.name());
-    let update: (Result<String, _>, Vec<&str>) = {
+    let us_name = format!("{}_sensors", measure.name());
+    let fields: Vec<String> = {
         let mut data: Vec<(&str, String)> = measure.to_redis();
 
         data.push((
             &uc_name,
             container_measure_count
-                .unwrap_or(None)
                 .map(|u| u + 1)
                 .unwrap_or(1)
                 .to_string(),
         ));
 
-        data.push((&ut_name, epoch_secs().to_string()));
-        (
-            redis_ctx.conn.hset_multiple(&container_key, &data[..]),
-            data.iter().map(|(a, _)| *a).collect(),
-        )
+        data.push((&ut_name, now.to_string()));
+        data.push((
+            &us_name,
+            sensors
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        ));
+        let _: () = redis_ctx.conn.hset_multiple(&container_key, &data[..])?;
+        data.iter().map(|(a, _)| a.to_string()).collect()
     };
 
-    match update {
-        (Err(e), _) => {
-            println!("update fails for {}: {:?}", container_key, e);
-            None
-        }
-        (Ok(_), fields) if fields.len() > 0 => {
-            let fs = fields.iter().map(|s| s.to_string()).collect();
-            Some(REvent::HashUpdated {
-                key: container_key.to_string(),
-                fields: fs,
-            })
-        }
-        _ => None,
+    let mut revents = vec![REvent::HashUpdated {
+        key: container_key.to_string(),
+        fields,
+    }];
+
+    // the current reading is in place, so a gap
+    // in the history isn't worth failing over
+    match history::record(redis_ctx, &container, measure, now, history_config) {
+        Ok(history_events) => revents.extend(history_events),
+        Err(e) => logging::warn("couldn't record history")
+            .with("key", container_key)
+            .with("sensor_type", measure.name())
+            .with("error", format!("{:?}", e))
+            .log(),
     }
+
+    Ok(revents)
 }
 
 fn ensure_sensor_hash_exists(
     redis_ctx: &RedisContext,
     sensor_hash_key: &str,
     ext_device_id_str: &str,
-) -> Option<REvent> {
+) -> Result<Option<REvent>, TrackerErr> {
     // We know that there's no associated "tank"
     // field for this key.  Let's make sure the record
     // for this sensor exists -- we'll need a human
     // to come in and link this device to a specific tank
-    // using redis-cli!
-    let mut result: Option<REvent> = None;
-
-    redis_ctx
-        .conn
-        .exists(sensor_hash_key)
-        .iter()
-        .for_each(|e: &bool| {
-            if !e {
-                let cf = "create_time".to_string();
-                let ed = "ext_device_id".to_string();
-                let field_vals = &vec![
-                    (&cf, format!("{}", epoch_secs())),
-                    (&ed, ext_device_id_str.to_string()),
-                ][..];
-                // new sensor, make note of when it is created
-                let _: Result<Vec<bool>, _> =
-                    redis_ctx.conn.hset_multiple(sensor_hash_key, field_vals);
-
-                let fields = vec![cf, ed];
-                result = Some(REvent::HashUpdated {
-                    key: sensor_hash_key.to_string(),
-                    fields,
-                })
-            }
-        });
+    // using pond's admin routes.
+    let exists: bool = redis_ctx.conn.exists(sensor_hash_key)?;
+    if exists {
+        return Ok(None);
+    }
 
-    result
+    let cf = "create_time".to_string();
+    let ed = "ext_device_id".to_string();
+    let field_vals = &vec![
+        (&cf, format!("{}", epoch_secs())),
+        (&ed, ext_device_id_str.to_string()),
+    ][..];
+    // new sensor, make note of when it is created
+    let _: () = redis_ctx.conn.hset_multiple(sensor_hash_key, field_vals)?;
+
+    logging::info("new sensor")
+        .with("device_id", ext_device_id_str)
+        .with("key", sensor_hash_key)
+        .log();
+
+    let fields = vec![cf, ed];
+    Ok(Some(REvent::HashUpdated {
+        key: sensor_hash_key.to_string(),
+        fields,
+    }))
 }
 
 fn update_sensor_hash(
@@ -208,7 +378,7 @@ fn update_sensor_hash(
     sensor_hash_key: &str,
     measure: &model::Measurement,
     maybe_sensor_upd_count: &Option<u64>,
-) -> Option<REvent> {
+) -> Result<REvent, TrackerErr> {
     let upd_c = &format!("{}_update_count", measure.name());
     let mut data: Vec<(&str, String)> = vec![(
         upd_c,
@@ -221,29 +391,19 @@ fn update_sensor_hash(
     let ut = &format!("{}_update_time", measure.name());
     data.push((ut, epoch_secs().to_string()));
 
-    let redis_result: Result<(), _> = redis_ctx.conn.hset_multiple(sensor_hash_key, &data[..]);
-    if let Err(e) = redis_result {
-        println!("couldn't update sensor record {}: {:?}", sensor_hash_key, e);
-        None
-    } else {
-        let mut fields: Vec<String> = vec![];
-        data.iter().for_each(|(f, _)| fields.push(f.to_string()));
+    let _: () = redis_ctx.conn.hset_multiple(sensor_hash_key, &data[..])?;
 
-        Some(REvent::HashUpdated {
-            key: sensor_hash_key.to_string(),
-            fields,
-        })
-    }
+    let mut fields: Vec<String> = vec![];
+    data.iter().for_each(|(f, _)| fields.push(f.to_string()));
+
+    Ok(REvent::HashUpdated {
+        key: sensor_hash_key.to_string(),
+        fields,
+    })
 }
 
-fn internal_device_id(
-    external_device_id: &str,
-    external_device_namespace: &Uuid,
-) -> Result<Uuid, uuid::parser::ParseError> {
-    Ok(Uuid::new_v5(
-        &external_device_namespace,
-        external_device_id.as_bytes(),
-    ))
+fn internal_device_id(external_device_id: &str, external_device_namespace: &Uuid) -> Uuid {
+    Uuid::new_v5(&external_device_namespace, external_device_id.as_bytes())
 }
 
 fn epoch_secs() -> u64 {
@@ -255,10 +415,14 @@ fn epoch_secs() -> u64 {
 
 pub fn publish_updates(redis_ctx: &RedisContext, topic: &str, updates: Vec<REvent>) {
     updates.iter().for_each(|delta_event| {
-        if let Ok(s) = serde_json::to_string(delta_event) {
+        if let Ok(s) = serde_json::to_string(&Envelope::wrap(delta_event)) {
             let published: Result<u64, _> = redis_ctx.conn.publish(topic, s);
             if let Err(e) = published {
-                println!("Error publishing to {}: {}", topic, e)
+                counters::incr(redis_ctx, Counter::PublishFailed);
+                logging::error("couldn't publish delta event")
+                    .with("topic", topic)
+                    .with("error", format!("{:?}", e))
+                    .log()
             }
         }
     })
@@ -0,0 +1,253 @@
+//! Each kind of probe describes the fields it reports, in its own
+//! module.  Adding a probe means writing a module which implements
+//! `Probe`, and listing it in `PROBES`.  The rest of sensor_tracker
+//! only sees `Measurement`s, and routes them to tanks and areas
+//! without knowing what they measure.
+use crate::model::{Measurement, Value};
+use std::collections::HashMap;
+
+pub mod conductivity;
+pub mod dht;
+pub mod oxygen;
+pub mod ph;
+pub mod temp;
+
+/// Every probe we understand.  A message may carry readings
+/// from several of them at once.
+pub static PROBES: &[&dyn Probe] = &[
+    &temp::Temp,
+    &ph::Ph,
+    &dht::Dht,
+    &oxygen::DissolvedOxygen,
+    &conductivity::Conductivity,
+];
+
+pub fn find(sensor_type: &str) -> Option<&'static dyn Probe> {
+    PROBES.iter().find(|p| p.name() == sensor_type).cloned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    Number,
+    Text,
+    Bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Presence {
+    /// The probe's readings are ignored unless this field is present.
+    Required,
+    Optional,
+    /// Computed by sensor_tracker, never read from the device.
+    Derived,
+}
+
+/// A field which a probe reports.  Its name is used both in
+/// the device's message and in the Redis hashes.
+#[derive(Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: ValueKind,
+    pub unit: Option<&'static str>,
+    pub presence: Presence,
+    /// The lowest and highest plausible values.  Anything
+    /// outside of them is quarantined.
+    pub range: Option<(f64, f64)>,
+    /// How far a value may stray from the median of the
+    /// sensor's recent readings before it's treated as
+    /// a spike.  See `screening`.
+    pub max_spike: Option<f64>,
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(unit) = self.unit {
+            write!(f, " ({})", unit)?;
+        }
+        if self.presence == Presence::Derived {
+            write!(f, " [derived]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists a probe's fields, e.g. `temp_f (°F), temp_c (°C)`
+pub fn describe(probe: &dyn Probe) -> String {
+    probe
+        .fields()
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub trait Probe: Sync {
+    /// The sensor type, as used in `<namespace>/sensors/<type>`.
+    fn name(&self) -> &'static str;
+
+    fn fields(&self) -> &'static [Field];
+
+    /// Sensor types whose fields this probe's readings already
+    /// include.  When both are found in one message, only this
+    /// probe's measurement is kept.
+    fn supersedes(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Numbers must be finite, and within the range of their
+    /// field.  Probes may check more than that.
+    fn validate(&self, measure: &Measurement) -> Result<(), String> {
+        for field in self.fields() {
+            if let Some(n) = measure.number(field.name) {
+                if !n.is_finite() {
+                    return Err(format!("{} is not a number", field.name));
+                }
+                if let Some((low, high)) = field.range {
+                    if n < low || n > high {
+                        return Err(format!(
+                            "{} {} is outside of {} to {}",
+                            field.name, n, low, high
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks this probe's fields out of a message.  Returns
+    /// `None` if any required field is missing.
+    fn read(&self, readings: &HashMap<String, serde_json::Value>) -> Option<Measurement> {
+        let mut values = vec![];
+        for field in self.fields() {
+            if field.presence == Presence::Derived {
+                continue;
+            }
+            match readings
+                .get(field.name)
+                .and_then(|r| Value::from_json(r, field.kind))
+            {
+                Some(v) => values.push((field.name, v)),
+                None if field.presence == Presence::Required => return None,
+                None => (),
+            }
+        }
+
+        Some(Measurement {
+            sensor_type: self.name(),
+            values,
+        })
+    }
+
+    /// Reads this probe's fields back from a sensor hash,
+    /// including those which were computed on the server.
+    fn read_hash(&self, hash: &HashMap<String, String>) -> Option<Measurement> {
+        let mut values = vec![];
+        for field in self.fields() {
+            match hash
+                .get(field.name)
+                .and_then(|v| Value::parse(v, field.kind))
+            {
+                Some(v) => values.push((field.name, v)),
+                None if field.presence == Presence::Required => return None,
+                None => (),
+            }
+        }
+
+        Some(Measurement {
+            sensor_type: self.name(),
+            values,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn readings(json: serde_json::Value) -> HashMap<String, serde_json::Value> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_read() {
+        let m = ph::Ph
+            .read(&readings(json!({"ph": 7.8, "ph_mv": 453.0})))
+            .unwrap();
+        assert_eq!(m.sensor_type, "ph");
+        assert_eq!(
+            m.values,
+            vec![("ph", Value::Number(7.8)), ("ph_mv", Value::Number(453.0))]
+        );
+
+        assert!(ph::Ph.read(&readings(json!({"ph": 7.8}))).is_none());
+        assert!(ph::Ph
+            .read(&readings(json!({"ph": "7.8", "ph_mv": 453.0})))
+            .is_none())
+    }
+
+    #[test]
+    fn test_read_optional() {
+        let m = oxygen::DissolvedOxygen
+            .read(&readings(json!({"do_mg_l": 6.8})))
+            .unwrap();
+        assert_eq!(m.sensor_type, "do");
+        assert_eq!(m.values, vec![("do_mg_l", Value::Number(6.8))]);
+
+        let m = oxygen::DissolvedOxygen
+            .read(&readings(json!({"do_mg_l": 6.8, "do_saturation": 87.2})))
+            .unwrap();
+        assert_eq!(m.number("do_saturation"), Some(87.2));
+
+        assert!(oxygen::DissolvedOxygen
+            .read(&readings(json!({"do_saturation": 87.2})))
+            .is_none())
+    }
+
+    #[test]
+    fn test_read_ignores_derived() {
+        let m = ph::Ph
+            .read(&readings(
+                json!({"ph": 7.8, "ph_mv": 453.0, "ph_device": 9.0, "ph_disagreement": true}),
+            ))
+            .unwrap();
+        assert_eq!(m.get("ph_device"), None);
+        assert_eq!(m.get("ph_disagreement"), None);
+
+        let mut hash = HashMap::new();
+        hash.insert("ph".to_string(), "7.8".to_string());
+        hash.insert("ph_mv".to_string(), "453".to_string());
+        hash.insert("ph_disagreement".to_string(), "true".to_string());
+        let m = ph::Ph.read_hash(&hash).unwrap();
+        assert_eq!(m.get("ph_disagreement"), Some(&Value::Bool(true)))
+    }
+
+    #[test]
+    fn test_validate() {
+        let temp = |c: f64| Measurement {
+            sensor_type: "temp",
+            values: vec![
+                ("temp_f", Value::Number(80.0)),
+                ("temp_c", Value::Number(c)),
+            ],
+        };
+        assert_eq!(temp::Temp.validate(&temp(26.7)), Ok(()));
+        assert_eq!(
+            temp::Temp.validate(&temp(-127.0)),
+            Err("temp_c -127 is outside of -10 to 50".to_string())
+        );
+        assert_eq!(
+            temp::Temp.validate(&temp(std::f64::NAN)),
+            Err("temp_c is not a number".to_string())
+        )
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("dht").map(|p| p.name()), Some("dht"));
+        assert_eq!(find("ec").map(|p| p.name()), Some("ec"));
+        assert!(find("radon").is_none())
+    }
+}
@@ -0,0 +1,34 @@
+use super::{Field, Presence, Probe, ValueKind};
+
+/// An electrical conductivity probe.  Some also report
+/// total dissolved solids, estimated from the conductivity.
+pub struct Conductivity;
+
+static FIELDS: &[Field] = &[
+    Field {
+        name: "ec_us_cm",
+        kind: ValueKind::Number,
+        unit: Some("µS/cm"),
+        presence: Presence::Required,
+        range: Some((0.0, 100_000.0)),
+        max_spike: Some(500.0),
+    },
+    Field {
+        name: "tds_ppm",
+        kind: ValueKind::Number,
+        unit: Some("ppm"),
+        presence: Presence::Optional,
+        range: Some((0.0, 50_000.0)),
+        max_spike: None,
+    },
+];
+
+impl Probe for Conductivity {
+    fn name(&self) -> &'static str {
+        "ec"
+    }
+
+    fn fields(&self) -> &'static [Field] {
+        FIELDS
+    }
+}
@@ -0,0 +1,71 @@
+use super::{Field, Presence, Probe, ValueKind};
+
+/// Digital humidity and temp, e.g. DHT11 sensor
+pub struct Dht;
+
+static FIELDS: &[Field] = &[
+    Field {
+        name: "status",
+        kind: ValueKind::Text,
+        unit: None,
+        presence: Presence::Required,
+        range: None,
+        max_spike: None,
+    },
+    Field {
+        name: "humidity",
+        kind: ValueKind::Number,
+        unit: Some("%"),
+        presence: Presence::Required,
+        range: Some((0.0, 100.0)),
+        max_spike: Some(20.0),
+    },
+    Field {
+        name: "temp_f",
+        kind: ValueKind::Number,
+        unit: Some("°F"),
+        presence: Presence::Required,
+        range: Some((-40.0, 176.0)),
+        max_spike: Some(9.0),
+    },
+    Field {
+        name: "temp_c",
+        kind: ValueKind::Number,
+        unit: Some("°C"),
+        presence: Presence::Required,
+        range: Some((-40.0, 80.0)),
+        max_spike: Some(5.0),
+    },
+    Field {
+        name: "heat_index_f",
+        kind: ValueKind::Number,
+        unit: Some("°F"),
+        presence: Presence::Required,
+        range: Some((-40.0, 176.0)),
+        max_spike: None,
+    },
+    Field {
+        name: "heat_index_c",
+        kind: ValueKind::Number,
+        unit: Some("°C"),
+        presence: Presence::Required,
+        range: Some((-40.0, 80.0)),
+        max_spike: None,
+    },
+];
+
+impl Probe for Dht {
+    fn name(&self) -> &'static str {
+        "dht"
+    }
+
+    fn fields(&self) -> &'static [Field] {
+        FIELDS
+    }
+
+    /// A DHT reading includes the temperature, so it
+    /// isn't also recorded as a plain temp sensor.
+    fn supersedes(&self) -> &'static [&'static str] {
+        &["temp"]
+    }
+}
@@ -0,0 +1,34 @@
+use super::{Field, Presence, Probe, ValueKind};
+
+/// A dissolved oxygen probe.  Some report the percentage
+/// of saturation as well as the concentration.
+pub struct DissolvedOxygen;
+
+static FIELDS: &[Field] = &[
+    Field {
+        name: "do_mg_l",
+        kind: ValueKind::Number,
+        unit: Some("mg/L"),
+        presence: Presence::Required,
+        range: Some((0.0, 20.0)),
+        max_spike: Some(3.0),
+    },
+    Field {
+        name: "do_saturation",
+        kind: ValueKind::Number,
+        unit: Some("%"),
+        presence: Presence::Optional,
+        range: Some((0.0, 250.0)),
+        max_spike: Some(30.0),
+    },
+];
+
+impl Probe for DissolvedOxygen {
+    fn name(&self) -> &'static str {
+        "do"
+    }
+
+    fn fields(&self) -> &'static [Field] {
+        FIELDS
+    }
+}
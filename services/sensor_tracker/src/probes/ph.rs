@@ -0,0 +1,55 @@
+use super::{Field, Presence, Probe, ValueKind};
+
+/// A pH probe, which reports its raw millivolts as well as pH.
+///
+/// When pH is computed on the server (see `crate::ph`),
+/// `ph_device` holds the value which the device reported, and
+/// `ph_disagreement` says whether the two were too far apart.
+pub struct Ph;
+
+pub const NAME: &str = "ph";
+
+static FIELDS: &[Field] = &[
+    Field {
+        name: "ph",
+        kind: ValueKind::Number,
+        unit: None,
+        presence: Presence::Required,
+        range: Some((1.0, 13.0)),
+        max_spike: Some(1.0),
+    },
+    Field {
+        name: "ph_mv",
+        kind: ValueKind::Number,
+        unit: Some("mV"),
+        presence: Presence::Required,
+        range: None,
+        max_spike: None,
+    },
+    Field {
+        name: "ph_device",
+        kind: ValueKind::Number,
+        unit: None,
+        presence: Presence::Derived,
+        range: None,
+        max_spike: None,
+    },
+    Field {
+        name: "ph_disagreement",
+        kind: ValueKind::Bool,
+        unit: None,
+        presence: Presence::Derived,
+        range: None,
+        max_spike: None,
+    },
+];
+
+impl Probe for Ph {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn fields(&self) -> &'static [Field] {
+        FIELDS
+    }
+}
@@ -0,0 +1,35 @@
+use super::{Field, Presence, Probe, ValueKind};
+
+/// A plain thermometer, e.g. DS18B20
+pub struct Temp;
+
+/// A disconnected DS18B20 reports -127°C, and one which has
+/// just been powered up reports 85°C.  Neither is in range.
+static FIELDS: &[Field] = &[
+    Field {
+        name: "temp_f",
+        kind: ValueKind::Number,
+        unit: Some("°F"),
+        presence: Presence::Required,
+        range: Some((14.0, 122.0)),
+        max_spike: Some(5.4),
+    },
+    Field {
+        name: "temp_c",
+        kind: ValueKind::Number,
+        unit: Some("°C"),
+        presence: Presence::Required,
+        range: Some((-10.0, 50.0)),
+        max_spike: Some(3.0),
+    },
+];
+
+impl Probe for Temp {
+    fn name(&self) -> &'static str {
+        "temp"
+    }
+
+    fn fields(&self) -> &'static [Field] {
+        FIELDS
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::model::Measurement;
+use crate::probes;
+
+/// Controls how readings are screened before they're
+/// applied to a tank or area.
+///
+/// - `spike_window`: how many recent readings of each field
+///   are kept for each sensor.  A reading which strays too far
+///   from their median is rejected.  0 disables spike filtering.
+#[derive(Debug, Clone)]
+pub struct ScreeningConfig {
+    pub spike_window: usize,
+}
+
+pub const DEFAULT_SPIKE_WINDOW: usize = 5;
+
+/// Spikes aren't detected until a sensor has sent at least
+/// this many readings, or `spike_window` if that's smaller.
+const MIN_RECENT: usize = 3;
+
+/// Checks each reading against the plausible range of its probe's
+/// fields, and against the recent readings from the same sensor.
+///
+/// Rejected spikes are still remembered, so that a sensor whose
+/// readings really have changed, e.g. after being moved to another
+/// tank, is trusted again once most of its recent readings agree.
+/// Readings which are out of range are forgotten.
+///
+/// Recent readings are only kept in memory, so they
+/// start over whenever sensor_tracker is restarted.
+pub struct Screen {
+    config: ScreeningConfig,
+    recent: HashMap<(String, &'static str, &'static str), VecDeque<f64>>,
+}
+
+impl Screen {
+    pub fn new(config: ScreeningConfig) -> Screen {
+        Screen {
+            config,
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Returns the reason for rejecting the reading, if any.
+    pub fn check(&mut self, ext_device_id: &str, measure: &Measurement) -> Result<(), String> {
+        let probe = match probes::find(measure.sensor_type) {
+            Some(probe) => probe,
+            None => return Ok(()),
+        };
+
+        probe.validate(measure)?;
+
+        let window = self.config.spike_window;
+        let mut spikes: Vec<String> = vec![];
+        for field in probe.fields() {
+            if let Some(value) = measure.number(field.name) {
+                let recent = self
+                    .recent
+                    .entry((ext_device_id.to_string(), measure.sensor_type, field.name))
+                    .or_default();
+
+                if let Some(max_spike) = field.max_spike {
+                    if recent.len() >= MIN_RECENT.min(window) {
+                        let values: Vec<f64> = recent.iter().cloned().collect();
+                        if let Some(m) = median(&values) {
+                            if (value - m).abs() > max_spike {
+                                spikes.push(format!(
+                                    "{} {} is more than {} from the recent median of {}",
+                                    field.name, value, max_spike, m
+                                ))
+                            }
+                        }
+                    }
+                }
+
+                recent.push_back(value);
+                while recent.len() > window {
+                    recent.pop_front();
+                }
+            }
+        }
+
+        spikes.into_iter().next().map_or(Ok(()), Err)
+    }
+}
+
+pub fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted: Vec<f64> = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let n = sorted.len();
+    if n == 0 {
+        None
+    } else if n % 2 == 1 {
+        Some(sorted[n / 2])
+    } else {
+        Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Value;
+
+    fn temp(c: f64) -> Measurement {
+        Measurement {
+            sensor_type: "temp",
+            values: vec![
+                ("temp_f", Value::Number(70.0)),
+                ("temp_c", Value::Number(c)),
+            ],
+        }
+    }
+
+    fn screen(spike_window: usize) -> Screen {
+        Screen::new(ScreeningConfig { spike_window })
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[3.0]), Some(3.0));
+        assert_eq!(median(&[5.0, 1.0, 3.0]), Some(3.0));
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), Some(2.5))
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut screen = screen(5);
+        assert_eq!(
+            screen.check("aa", &temp(-127.0)),
+            Err("temp_c -127 is outside of -10 to 50".to_string())
+        );
+        assert_eq!(screen.check("aa", &temp(26.0)), Ok(()))
+    }
+
+    #[test]
+    fn test_spike() {
+        let mut screen = screen(5);
+        for _ in 0..3 {
+            assert_eq!(screen.check("aa", &temp(26.0)), Ok(()));
+        }
+        assert_eq!(
+            screen.check("aa", &temp(36.0)),
+            Err("temp_c 36 is more than 3 from the recent median of 26".to_string())
+        );
+        assert_eq!(screen.check("aa", &temp(27.0)), Ok(()));
+
+        // other sensors have their own history
+        assert_eq!(screen.check("bb", &temp(36.0)), Ok(()))
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut screen = screen(5);
+        assert_eq!(screen.check("aa", &temp(10.0)), Ok(()));
+        assert_eq!(screen.check("aa", &temp(30.0)), Ok(()));
+        assert_eq!(screen.check("aa", &temp(45.0)), Ok(()));
+        assert!(screen.check("aa", &temp(10.0)).is_err())
+    }
+
+    #[test]
+    fn test_trusted_again() {
+        let mut screen = screen(3);
+        for _ in 0..3 {
+            assert_eq!(screen.check("aa", &temp(20.0)), Ok(()));
+        }
+        assert!(screen.check("aa", &temp(30.0)).is_err());
+        assert!(screen.check("aa", &temp(30.0)).is_err());
+        assert_eq!(screen.check("aa", &temp(30.0)), Ok(()))
+    }
+
+    #[test]
+    fn test_spike_window_disabled() {
+        let mut screen = screen(0);
+        assert_eq!(screen.check("aa", &temp(20.0)), Ok(()));
+        assert_eq!(screen.check("aa", &temp(40.0)), Ok(()));
+        assert!(screen.check("aa", &temp(-127.0)).is_err())
+    }
+}
@@ -2,3 +2,4 @@
 killall ph_ref_calibration
 killall sensor_tracker
 killall led_status_helper
+killall alert_tracker